@@ -39,7 +39,7 @@ pub fn array_cmp(a : &[u32], b : &[u32]) -> bool {
 
 fn main() {
     clks::init();
-    sdram::init();
+    sdram::init().unwrap();
 
     let mut stdout = hio::hstdout().unwrap();
 
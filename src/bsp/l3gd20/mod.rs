@@ -1,13 +1,17 @@
-use stm32f429;
-use stm32f429::interrupt;
-use cortex_m::peripheral::NVIC;
-use bare_metal::Nr;
-use spl_rs::{gpio, rcc};
+use spl_rs::exti;
+use spl_rs::gpio::{Pin, PortA, PortC, N1};
+use bsp::spi_bus;
+use spl_rs::spi::{SpiError, DataFrameFormat, ClkPolCfg, ClkPhaCfg};
+
+// maximum spi clock frequency accepted by the sensor
+const L3GD20_SPI_MAX_FREQ : u32 = 10_000_000;
 
 pub enum L3GD20Error {
     SpiTimeout,
     SpiBusError,
     IdCannotBeRead,
+    // INT1 could not be routed
+    InterruptLine(exti::ExtiError),
 }
 
 
@@ -52,6 +56,9 @@ pub enum Scale {
 }
 
 static mut INSTANCE : L3GD20 = L3GD20{};
+static mut DEVICE : Option<spi_bus::DeviceId> = None;
+// set from the INT1 edge, consumed by the main loop
+static mut INT1_RAISED : bool = false;
 
 pub struct L3GD20;
 
@@ -62,124 +69,101 @@ impl L3GD20 {
         }
     }
 
-    pub fn init(&self) -> Result<(), ()> {
-        // enable peripherals clocks
-        rcc::set_ahb1_periph_clk(
-            rcc::Ahb1Enable::GPIOF |
-            rcc::Ahb1Enable::GPIOA |
-            rcc::Ahb1Enable::GPIOC,
-            true
-        );
-        rcc::set_apb2_periph_clk(rcc::Apb2Enable::SPI5, true);
-
-        // configure cs pin, output pull-up, default high state
-        let pc = unsafe{&*stm32f429::GPIOC::ptr()};
-        gpio::port_others::configure(
-            pc,
-            1,
-            gpio::Mode::Output,
-            gpio::OutType::PushPull,
-            gpio::OutSpeed::Low,
-            gpio::PullType::NoPull
-        ).unwrap();
-
-        // configure spi pins
-        // set as alternative function pin
-        let pf = unsafe{ &*stm32f429::GPIOF::ptr() };
-        let pfpins = [7, 8, 9];
-        for i in pfpins.iter() {
-            gpio::port_others::configure(
-                pf,
-                *i,
-                gpio::Mode::AltFn,
-                gpio::OutType::PushPull,
-                gpio::OutSpeed::High,
-                gpio::PullType::NoPull
-            ).unwrap();
-            gpio::port_others::set_alt_fn(
-                pf,
-                *i,
-                gpio::AltFn::Spi123456
-            ).unwrap();
+    // cs is wired to PC1 and the sensor INT1 to PA1, SPI5 is handled by the
+    // bus
+    pub fn init<CS, INT1>(&self, cs : Pin<PortC, N1, CS>, int1 : Pin<PortA, N1, INT1>)
+        -> Result<(), L3GD20Error> {
+        let int1 = int1.into_floating_input();
+
+        let id = spi_bus::register(spi_bus::DeviceConfig {
+            cpol        : ClkPolCfg::CpolHigh,
+            cpha        : ClkPhaCfg::CphaSecond,
+            bit_rate    : L3GD20_SPI_MAX_FREQ,
+            frame       : DataFrameFormat::Frame8Bits,
+            cs          : cs.into_push_pull_output().erase(),
+        }).map_err(bus_error)?;
+
+        if let Err(e) = exti::listen_pin(&int1, exti::Edge::Rising, L3GD20::sensor_interrupt) {
+            let _ = spi_bus::unregister(id);
+            return Err(L3GD20Error::InterruptLine(e));
+        }
+        unsafe {
+            DEVICE = Some(id);
         }
-
-        // configure spi5 for sensor interface
-        let spi = unsafe{&*stm32f429::SPI5::ptr()};
-        spi.cr1.modify(|_, w| unsafe {
-            w.bidimode().bit(false)
-             .crcen().bit(false)
-             .dff().bit(false)
-             .br().bits(0b010)
-             .mstr().bit(true)
-             .cpol().bit(true)
-             .cpha().bit(true)
-        });
-
-        spi.cr2.modify(|_, w| w.ssoe().bit(true));
-
-        // configure interrupts when pins changing
-        // exti conf
-        let sc = unsafe{&*stm32f429::SYSCFG::ptr()};
-        sc.exticr1.modify(|_, w| unsafe{
-            w.exti1().bits(0b0000)
-        });
-
-        let nvic = unsafe{&*NVIC::ptr()};
-        let nr = interrupt::Interrupt::EXTI1.nr();
-        unsafe { nvic.iser[usize::from(nr / 32)].write(1 << (nr % 32)) };
-
-        spi.cr1.modify(|_, w| w.spe().bit(true));
-
         Ok(())
     }
 
-    pub fn write_reg(&self, reg : Register, dat : u8) {
-        let reg = 0x3F & reg as u8;
-
-        let pc = unsafe{&*stm32f429::GPIOC::ptr()};
-        gpio::port_others::write(pc, 1, true).unwrap();
-
-        let spi = unsafe{&*stm32f429::SPI5::ptr()};
-
-        spi.dr.write(|w| unsafe{w.bits(reg as u32)});
-        while spi.sr.read().txe().bit() == false {}
+    fn device() -> Result<spi_bus::DeviceId, L3GD20Error> {
+        match unsafe { DEVICE } {
+            Some(id) => Ok(id),
+            None => Err(L3GD20Error::SpiBusError),
+        }
+    }
 
-        spi.dr.write(|w| unsafe{w.bits(dat as u32)});
-        while spi.sr.read().txe().bit() == false {}
+    pub fn write_reg(&self, reg : Register, dat : u8) -> Result<(), L3GD20Error> {
+        let reg = 0x3F & reg as u8;
 
-        gpio::port_others::write(pc, 1, false).unwrap();
+        spi_bus::write(L3GD20::device()?, &[reg, dat]).map_err(bus_error)
     }
 
-    pub fn read_reg(&self, reg : Register) -> u8 {
+    pub fn read_reg(&self, reg : Register) -> Result<u8, L3GD20Error> {
+        // read, no address increment
         let reg = (0x80 | reg as u8) & 0b1011_1111;
 
-        let pc = unsafe{&*stm32f429::GPIOC::ptr()};
-        let spi = unsafe{&*stm32f429::SPI5::ptr()};
-
-        gpio::port_others::write(pc, 1, true).unwrap();
-
-        while spi.sr.read().txe().bit() == false { }
-        spi.dr.write(|w| unsafe{w.bits(reg as u32)});
-        while spi.sr.read().rxne().bit() == false { }
-        let _ = spi.dr.read().bits() as u8;
-
-        while spi.sr.read().txe().bit() == false {}
-        spi.dr.write(|w| unsafe{w.bits(0x00)});
-        while spi.sr.read().rxne().bit() == false { }
+        let mut buf = [reg, 0x00];
+        spi_bus::transfer(L3GD20::device()?, &mut buf).map_err(bus_error)?;
+        Ok(buf[1])
+    }
 
-        let data = spi.dr.read().bits() as u8;
-        gpio::port_others::write(pc, 1, false).unwrap();
-        data
+    // Background burst read of OUT_X_L to OUT_Z_H. buf[0] is overwritten with
+    // the address, the rates follow once the transfer is waited for, see
+    // rates().
+    pub fn read_rates_dma(&self, buf : &'static mut [u8; 7], done : Option<fn()>)
+        -> Result<spi_bus::BusTransfer<&'static mut [u8]>, (&'static mut [u8], L3GD20Error)> {
+        // read, address increment
+        buf[0] = 0xC0 | Register::OutXL as u8;
+        let buf : &'static mut [u8] = buf;
+
+        let id = match L3GD20::device() {
+            Ok(id) => id,
+            Err(e) => return Err((buf, e)),
+        };
+        spi_bus::transfer_dma(id, buf, done).map_err(|(buf, e)| (buf, bus_error(e)))
     }
 
     pub fn check_connection(&self) -> Result<(),L3GD20Error> {
-        match self.read_reg(Register::WhoAmI) {
+        match self.read_reg(Register::WhoAmI)? {
             0b1101_0100 => Ok(()),
             _ => Err(L3GD20Error::IdCannotBeRead),
         }
     }
 
+    // runs in EXTI1 context
     pub fn sensor_interrupt() {
+        unsafe {
+            INT1_RAISED = true;
+        }
+    }
+
+    // true if INT1 rose since the last call
+    pub fn take_interrupt(&self) -> bool {
+        ::cortex_m::interrupt::free(|_| unsafe {
+            let raised = INT1_RAISED;
+            INT1_RAISED = false;
+            raised
+        })
+    }
+}
+
+// x, y and z rates out of a buffer filled by read_rates_dma
+pub fn rates(buf : &[u8]) -> (i16, i16, i16) {
+    let axis = |i : usize| (buf[i] as u16 | (buf[i + 1] as u16) << 8) as i16;
+    (axis(1), axis(3), axis(5))
+}
 
+fn bus_error(e : spi_bus::BusError) -> L3GD20Error {
+    match e {
+        spi_bus::BusError::Spi(SpiError::Timeout) => L3GD20Error::SpiTimeout,
+        _ => L3GD20Error::SpiBusError,
     }
 }
@@ -1,17 +1,31 @@
 use super::fonts;
 use misc;
-use stm32f429::{
-    SPI5,
-    RCC,
-    GPIOA, GPIOB, GPIOC, GPIOD, GPIOF, GPIOG,
-    LTDC,
-};
+use stm32f429::LTDC;
 use sdram;
 use super::ltdc;
-
+use spl_rs::{rcc, gpio, clk_gate};
+use spl_rs::gpio::{Pin, PortC, PortD, N2, N13, ErasedPin, Output, PushPull};
+use bsp::{pinmap, spi_bus};
+use spl_rs::spi::{DataFrameFormat, ClkPolCfg, ClkPhaCfg};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+use spl_rs::rcc::RccError;
+use system::profiles;
+use system::profiles::ProfileError;
+use system::clk_config::{Clocks, LcdClock, ConfigError, solve_lcd_clock};
+
+#[derive(Debug)]
 pub enum LcdError {
     OutOfFrame,
     OutOfScreen,
+    PixelClock(ConfigError),
+    PllSai(RccError),
+    Spi(spi_bus::BusError),
+    Profile(ProfileError),
+    Clock(ClkGateError),
+    PinConflict(gpio::PinConflict),
+    PinConfig,
+    Lock(gpio::LockError),
+    Sdram(sdram::SdramError),
 }
 
 pub struct Point {
@@ -25,6 +39,27 @@ pub const LCD_HEIGHT            : u16 = 320;
 const LCD_FRAME_BUFFER_START    : u32 = 0xD0000000;
 const LCD_BUFFER_OFFSET         : u32 = 0x50000;
 
+const OWNER                     : &'static str = "lcd";
+
+// PLL input PLLSAI was last programmed from, 0 if never
+static mut PIXEL_VCO_IN         : u32 = 0;
+
+// read cycles of the ili9341 are 150ns long
+const LCD_SPI_MAX_FREQ          : u32 = 6_000_000;
+
+// rgb interface timings of the ili9341
+pub const LCD_TIMING            : ltdc::PanelTiming = ltdc::PanelTiming {
+    hsync   : 10,
+    hbp     : 20,
+    width   : LCD_WIDTH,
+    hfp     : 10,
+    vsync   : 2,
+    vbp     : 2,
+    height  : LCD_HEIGHT,
+    vfp     : 4,
+};
+pub const LCD_REFRESH_RATE      : u32 = 65; // Hz
+
 pub enum Register {
     LcdSleepOut      = 0x11, /* Sleep out register */
     LcdGamma         = 0x26, /* Gamma register */
@@ -86,17 +121,15 @@ pub fn assemble_rgb(r : u16, g : u16, b : u16) -> u16 {
     (((r & 0xF8) << 8) | ((g << & 0xFC) << 3) | ((b & 0xF8) >> 3))
 }
 
-enum CtrlLine {
-    Ncs,
-    Nwr,
-}
-
 pub struct Lcd {
     current_font         : &'static fonts::Font,
     current_text_color   : Color,
     current_back_color   : Color,
     current_frame_buffer : u32,
     current_layer        : Layer,
+    spi_dev              : Option<spi_bus::DeviceId>,
+    // low for a command, high for data
+    wrx                  : Option<ErasedPin<Output<PushPull>>>,
 }
 
 impl Lcd {
@@ -107,203 +140,78 @@ impl Lcd {
             current_back_color      : Color::White,
             current_frame_buffer    : LCD_FRAME_BUFFER_START,
             current_layer           : Layer::Background,
+            spi_dev                 : None,
+            wrx                     : None,
         }
     }
 
     pub fn deinit(&mut self) {
-        let spi = unsafe {&*SPI5::ptr()};
-        let rcc = unsafe {&*RCC::ptr()};
-
-        let pa = unsafe {&*GPIOA::ptr()};
-        let pb = unsafe {&*GPIOB::ptr()};
-        let pc = unsafe {&*GPIOC::ptr()};
-        let pd = unsafe {&*GPIOD::ptr()};
-        let pf = unsafe {&*GPIOF::ptr()};
-        let pg = unsafe {&*GPIOG::ptr()};
-
-        self.display_off();
-
-        spi.cr1.modify(|_, w| w.spe().bit(false));
-        spi.cr1.reset();
-        spi.cr2.reset();
-
-        rcc.apb2enr.modify(|_, w| w.spi5enr().bit(false));
-
-        // GPIOA ###################################################
-        pa.moder.modify(|_, w| unsafe {
-            w.moder3().bits(0)
-             .moder4().bits(0)
-             .moder6().bits(0)
-             .moder11().bits(0)
-             .moder12().bits(0)
-        });
-        pa.pupdr.modify(|_, w| unsafe {
-            w.pupdr3().bits(0)
-             .pupdr4().bits(0)
-             .pupdr6().bits(0)
-             .pupdr11().bits(0)
-             .pupdr12().bits(0)
-        });
-
-        pa.afrl.modify(|_, w| unsafe {
-            w.afrl3().bits(0)
-             .afrl4().bits(0)
-             .afrl6().bits(0)
-        });
-        pa.afrh.modify(|_, w| unsafe {
-            w.afrh11().bits(0)
-             .afrh12().bits(0)
-        });
-
-        // GPIOB ###################################################
-        pb.moder.modify(|_, w| unsafe {
-            w.moder0().bits(0)
-             .moder1().bits(0)
-             .moder8().bits(0)
-             .moder9().bits(0)
-             .moder10().bits(0)
-             .moder11().bits(0)
-        });
-        pb.pupdr.modify(|_, w| unsafe {
-            w.pupdr0().bits(0)
-             .pupdr1().bits(0)
-             .pupdr8().bits(0)
-             .pupdr9().bits(0)
-             .pupdr10().bits(0)
-             .pupdr11().bits(0)
-        });
-
-        pb.afrl.modify(|_, w| unsafe {
-            w.afrl0().bits(0)
-             .afrl1().bits(0)
-        });
-        pb.afrh.modify(|_, w| unsafe {
-            w.afrh8().bits(0)
-             .afrh9().bits(0)
-             .afrh10().bits(0)
-             .afrh11().bits(0)
-        });
-
-        // GPIOC ###################################################
-        pc.moder.modify(|_, w| unsafe {
-            w.moder2().bits(0)
-             .moder6().bits(0)
-             .moder7().bits(0)
-             .moder10().bits(0)
-        });
-        pc.pupdr.modify(|_, w| unsafe {
-            w.pupdr2().bits(0)
-             .pupdr6().bits(0)
-             .pupdr7().bits(0)
-             .pupdr10().bits(0)
-        });
-
-        pc.afrl.modify(|_, w| unsafe {
-            w.afrl2().bits(0)
-             .afrl6().bits(0)
-             .afrl7().bits(0)
-        });
-        pc.afrh.modify(|_, w| unsafe {
-            w.afrh10().bits(0)
-        });
-
-        // GPIOD ###################################################
-        pd.moder.modify(|_, w| unsafe {
-            w.moder3().bits(0)
-             .moder6().bits(0)
-        });
-        pd.pupdr.modify(|_, w| unsafe {
-            w.pupdr3().bits(0)
-             .pupdr6().bits(0)
-        });
-
-        pd.afrl.modify(|_, w| unsafe {
-            w.afrl3().bits(0)
-             .afrl6().bits(0)
-        });
-
-        // GPIOF ###################################################
-        pf.moder.modify(|_, w| unsafe {
-            w.moder7().bits(0)
-             .moder8().bits(0)
-             .moder9().bits(0)
-             .moder10().bits(0)
-        });
-        pf.pupdr.modify(|_, w| unsafe {
-            w.pupdr7().bits(0)
-             .pupdr8().bits(0)
-             .pupdr9().bits(0)
-             .pupdr10().bits(0)
-        });
-
-        pf.afrl.modify(|_, w| unsafe {
-            w.afrl7().bits(0)
-        });
-        pf.afrh.modify(|_, w| unsafe {
-            w.afrh8().bits(0)
-             .afrh9().bits(0)
-             .afrh10().bits(0)
-        });
-
-        // GPIOG ###################################################
-        pg.moder.modify(|_, w| unsafe {
-            w.moder6().bits(0)
-             .moder7().bits(0)
-             .moder10().bits(0)
-             .moder11().bits(0)
-             .moder12().bits(0)
-        });
-        pg.pupdr.modify(|_, w| unsafe {
-            w.pupdr6().bits(0)
-             .pupdr7().bits(0)
-             .pupdr10().bits(0)
-             .pupdr11().bits(0)
-             .pupdr12().bits(0)
-        });
+        let _ = self.display_off();
+        self.release();
+    }
 
-        pg.afrl.modify(|_, w| unsafe {
-            w.afrl6().bits(0)
-             .afrl7().bits(0)
-        });
-        pg.afrh.modify(|_, w| unsafe {
-            w.afrh10().bits(0)
-             .afrh11().bits(0)
-             .afrh12().bits(0)
-        });
+    // Give back what init took. Used by deinit and by an init failing
+    // midway, whatever was not taken yet is skipped.
+    fn release(&mut self) {
+        // SPI5 stays up as long as the gyroscope uses it, NCS and WRX are
+        // dropped as outputs
+        if let Some(id) = self.spi_dev.take() {
+            let _ = spi_bus::unregister(id);
+        }
+        self.wrx = None;
+
+        // the LTDC pins locked by init keep their function until reset, the
+        // ones a failed init configured go back to inputs
+        let owned = pinmap::LTDC.iter().all(|p| gpio::owner(p.port, p.pin) == Some(OWNER));
+        let locked = pinmap::LTDC.iter().any(|p| gpio::is_locked(p.port, p.pin));
+        if owned && !locked {
+            let _ = gpio::release(&pinmap::LTDC);
+        }
+        gpio::unclaim_group(&pinmap::LTDC, OWNER);
+
+        // a release without init holds nothing, which is fine
+        let _ = clk_gate::release(Gate::Apb2(rcc::Apb2Enable::LTDC), OWNER);
+        let _ = clk_gate::release(Gate::Ahb1(rcc::Ahb1Enable::DMA_2D), OWNER);
+        let _ = clk_gate::release(Gate::Ahb1(
+            rcc::Ahb1Enable::GPIOA |
+            rcc::Ahb1Enable::GPIOB |
+            rcc::Ahb1Enable::GPIOC |
+            rcc::Ahb1Enable::GPIOD |
+            rcc::Ahb1Enable::GPIOF |
+            rcc::Ahb1Enable::GPIOG
+        ), OWNER);
+    }
+
+    // NCS is wired to PC2 and WRX to PD13. Everything taken is given back
+    // if a step fails.
+    pub fn init<NCS, WRX>(&mut self, ncs : Pin<PortC, N2, NCS>, wrx : Pin<PortD, N13, WRX>)
+        -> Result<(), LcdError> {
+        let res = self.try_init(ncs, wrx);
+        if res.is_err() {
+            self.release();
+        }
+        res
     }
 
-    pub fn init(&mut self) {
-        let rcc = unsafe {&*RCC::ptr()};
+    fn try_init<NCS, WRX>(&mut self, ncs : Pin<PortC, N2, NCS>, wrx : Pin<PortD, N13, WRX>)
+        -> Result<(), LcdError> {
         let ltdc = unsafe {&*LTDC::ptr()};
 
-        Lcd::configure_ctrl_lines();
-
-        self.chip_select(true);
-        self.chip_select(false);
+        self.wrx = Some(wrx.into_push_pull_output().erase());
 
-        Lcd::configure_spi();
+        self.spi_dev = Some(Lcd::configure_spi(ncs.into_push_pull_output().erase())?);
 
         self.power_on();
 
-        rcc.apb2enr.modify(|_, w| w.ltdcen().bit(true));
-        rcc.ahb1enr.modify(|_, w| w.dma2den().bit(true));
+        clk_gate::acquire(Gate::Apb2(rcc::Apb2Enable::LTDC), OWNER).map_err(LcdError::Clock)?;
+        clk_gate::acquire(Gate::Ahb1(rcc::Ahb1Enable::DMA_2D), OWNER).map_err(LcdError::Clock)?;
 
-        Lcd::configure_alt_fn_gpios();
+        Lcd::configure_alt_fn_gpios()?;
 
-        sdram::init();
-
-        rcc.pllsaicfgr.modify(|_, w| unsafe {
-            w.pllsain().bits(192)
-             .pllsaiq().bits(7)
-             .pllsair().bits(4)
-        });
-
-        rcc.dckcfgr.modify(|_, w| unsafe {
-            w.pllsaidivr().bits(2)  // divide by 8
-        });
+        sdram::init().map_err(LcdError::Sdram)?;
 
-        rcc.cr.modify(|_, w| w.pllsaion().bit(true));
-        while rcc.cr.read().pllsairdy().bit() == false {}
+        configure_pixel_clock(&LCD_TIMING, LCD_REFRESH_RATE)?;
+        profiles::register(on_clock_change).map_err(LcdError::Profile)?;
 
         ltdc.gcr.modify(|_, w| unsafe {
             w.hspol().bit(false)    // active low
@@ -316,25 +224,15 @@ impl Lcd {
             w.bits(0)
         });
 
-        ltdc.twcr.modify(|_, w| unsafe{
-            w.totalw().bits(279)    // hsync width + hbp + active width + hfp - 1
-             .totalh().bits(327)    // vsync height + vbp + active height + vfp - 1
-        });
-
-        ltdc.awcr.modify(|_, w| unsafe{
-            w.aav().bits(323)       // vsync height + vhp + active heigh - 1
-             .aah().bits(269)       // hsync width + hbp + active width - 1
-        });
-
-        ltdc.bpcr.modify(|_, w| unsafe {
-            w.ahbp().bits(29)       // hsync width + hbp - 1
-             .avbp().bits(3)        // vsync height + vbp - 1
-        });
+        ltdc::configure_timing(&LCD_TIMING);
 
-        ltdc.sscr.modify(|_, w| unsafe {
-            w.hsw().bits(9)
-             .vsh().bits(1)
-        });
+        // A stray write to the SDRAM or LTDC pins would take the display
+        // down. The lock holds until reset, an init after deinit finds the
+        // ports already locked.
+        match gpio::lock_groups(&pinmap::LOCKED) {
+            Ok(()) | Err(gpio::LockError::AlreadyLocked(_)) => Ok(()),
+            Err(e) => Err(LcdError::Lock(e)),
+        }
     }
 
     pub fn init_layers(&mut self) {
@@ -454,15 +352,6 @@ impl Lcd {
         ltdc.gcr.modify(|_, w| w.den().bit(true));
     }
 
-    fn chip_select(&self, en : bool) {
-        let pc = unsafe{&*GPIOC::ptr()};
-        if en {
-            pc.bsrr.write(|w| w.bs2().bit(true));
-        } else {
-            pc.bsrr.write(|w| w.br2().bit(true));
-        }
-    }
-
     pub fn set_layer(&mut self, l : Layer) {
         match l {
             Layer::Background => {
@@ -717,245 +606,121 @@ impl Lcd {
         Ok(())
     }
 
-    pub fn send_command(&self, cmd : Register) {
-
+    fn spi_write(&self, data : &[u8]) -> Result<(), LcdError> {
+        match self.spi_dev {
+            Some(id) => spi_bus::write(id, data).map_err(LcdError::Spi),
+            None => Err(LcdError::Spi(spi_bus::BusError::UnknownDevice)),
+        }
     }
 
-    pub fn send_data(&self, val : u8) {
+    // WRX low selects the command register
+    pub fn send_command(&mut self, cmd : Register) -> Result<(), LcdError> {
+        self.set_wrx(false);
+        self.spi_write(&[cmd as u8])
+    }
 
+    pub fn send_data(&mut self, val : u8) -> Result<(), LcdError> {
+        self.set_wrx(true);
+        self.spi_write(&[val])
     }
 
     pub fn power_on(&self) {
 
     }
 
-    pub fn display_on(&self) {
-
+    pub fn display_on(&mut self) -> Result<(), LcdError> {
+        self.send_command(Register::LcdDisplayOn)
     }
 
-    pub fn display_off(&self) {
-
+    pub fn display_off(&mut self) -> Result<(), LcdError> {
+        self.send_command(Register::LcdDisplayOff)
     }
 
-    fn configure_ctrl_lines() {
-        let rcc = unsafe{&*RCC::ptr()};
-        let pc  = unsafe{&*GPIOC::ptr()};
-        let pd  = unsafe{&*GPIOD::ptr()};
-
-        rcc.ahb1enr.modify(|_, w|
-            w.gpiocen().bit(true)
-             .gpioden().bit(true)
-        );
-
-        pc.moder.modify(|_, w| unsafe{
-            w.moder2().bits(1)
-        });
-        pd.moder.modify(|_, w| unsafe{
-            w.moder13().bits(1)
-        });
-
-        Lcd::write_ctrl_line(CtrlLine::Ncs, true);
+    fn set_wrx(&mut self, state : bool) {
+        if let Some(ref mut wrx) = self.wrx {
+            if state {
+                wrx.set_high();
+            } else {
+                wrx.set_low();
+            }
+        }
     }
 
-    fn write_ctrl_line(l : CtrlLine, state : bool) {
-        let pc = unsafe{&*GPIOC::ptr()};
-        let pd = unsafe{&*GPIOD::ptr()};
-        match l {
-            CtrlLine::Ncs => pc.odr.modify(|_, w| w.odr2().bit(state)),
-            CtrlLine::Nwr => pd.odr.modify(|_, w| w.odr13().bit(state)),
-        };
+    // SPI5 is shared with the gyroscope, the bus switches settings and
+    // drives NCS (PC2) around every transfer
+    fn configure_spi(ncs : ErasedPin<Output<PushPull>>) -> Result<spi_bus::DeviceId, LcdError> {
+        spi_bus::register(spi_bus::DeviceConfig {
+            cpol        : ClkPolCfg::CpolLow,
+            cpha        : ClkPhaCfg::CphaFirst,
+            bit_rate    : LCD_SPI_MAX_FREQ,
+            frame       : DataFrameFormat::Frame8Bits,
+            cs          : ncs,
+        }).map_err(LcdError::Spi)
     }
 
-    fn configure_spi() {
-        let rcc = unsafe{&*RCC::ptr()};
-        let pf = unsafe{&*GPIOF::ptr()};
-        let spi = unsafe{&*SPI5::ptr()};
-
-        rcc.ahb1enr.modify(|_, w| w.gpiofen().bit(true));
-        rcc.apb2enr.modify(|_, w| w.spi5enr().bit(true));
-
-        // configure as alt fn
-        pf.moder.modify(|_, w| unsafe {
-            w.moder7().bits(2)
-             .moder8().bits(2)
-             .moder9().bits(2)
-        });
-
-        // medium high speed
-        pf.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr7().bits(2)
-             .ospeedr8().bits(2)
-             .ospeedr9().bits(2)
-        });
-
-        pf.afrl.modify(|_, w| unsafe {
-            w.afrl7().bits(5)
-        });
-        pf.afrh.modify(|_, w| unsafe {
-            w.afrh8().bits(5)
-             .afrh9().bits(5)
-        });
+    fn configure_alt_fn_gpios() -> Result<(), LcdError> {
+        clk_gate::acquire(Gate::Ahb1(gpio::port_clks(&pinmap::LTDC)), OWNER)
+            .map_err(LcdError::Clock)?;
+        gpio::claim_group(&pinmap::LTDC, OWNER, false).map_err(LcdError::PinConflict)?;
+        gpio::apply(&pinmap::LTDC).map_err(|_| LcdError::PinConfig)
+    }
 
-        // do not reconfigure if already enabled
-        if spi.cr1.read().spe().bit() == false {
-            spi.cr1.modify(|_, w| unsafe {
-                w.bidimode().bit(false)     // full duplex
-                 .crcen().bit(false)        // disable crc
-                 .dff().bit(false)          // 8 bits frame len
-                 .rxonly().bit(false)       // rxonly disabled
-                 .lsbfirst().bit(false)     // msb first
-                 .br().bits(0b011)          // baudrate / 16
-                 .mstr().bit(true)          // configured as master
-                 .spe().bit(true)           // enable spi
-            });
+    fn put_pixel(&self, x : u16, y : u16) -> Result<(), LcdError> {
+        if x > 239 || y > 319 {
+            return Err(LcdError::OutOfScreen);
         }
+        self.draw_line(x, y, 1, Direction::Horizontal);
+        Ok(())
     }
+}
 
-    fn configure_alt_fn_gpios() {
-        let pa = unsafe {&*GPIOA::ptr()};
-        let pb = unsafe {&*GPIOB::ptr()};
-        let pc = unsafe {&*GPIOC::ptr()};
-        let pd = unsafe {&*GPIOD::ptr()};
-        let pf = unsafe {&*GPIOF::ptr()};
-        let pg = unsafe {&*GPIOG::ptr()};
-
-        let rcc = unsafe{&*RCC::ptr()};
-
-        // GPIOA ###################################################
-        pa.moder.modify(|_, w| unsafe {
-            w.moder3().bits(0b10)
-             .moder4().bits(0b10)
-             .moder6().bits(0b10)
-             .moder11().bits(0b10)
-             .moder12().bits(0b10)
-        });
-        pa.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr3().bits(0b10)
-             .ospeedr4().bits(0b10)
-             .ospeedr6().bits(0b10)
-             .ospeedr11().bits(0b10)
-             .ospeedr12().bits(0b10)
-        });
-
-        pa.afrl.modify(|_, w| unsafe {
-            w.afrl3().bits(0x0E)
-             .afrl4().bits(0x0E)
-             .afrl6().bits(0x0E)
-        });
-        pa.afrh.modify(|_, w| unsafe {
-            w.afrh11().bits(0x0E)
-             .afrh12().bits(0x0E)
-        });
-
-        // GPIOB ###################################################
-        pb.moder.modify(|_, w| unsafe {
-            w.moder0().bits(0b10)
-             .moder1().bits(0b10)
-             .moder8().bits(0b10)
-             .moder9().bits(0b10)
-             .moder10().bits(0b10)
-             .moder11().bits(0b10)
-        });
-        pb.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr0().bits(0b10)
-             .ospeedr1().bits(0b10)
-             .ospeedr8().bits(0b10)
-             .ospeedr9().bits(0b10)
-             .ospeedr10().bits(0b10)
-             .ospeedr11().bits(0b10)
-        });
-
-        pb.afrl.modify(|_, w| unsafe {
-            w.afrl0().bits(0x09)
-             .afrl1().bits(0x09)
-        });
-        pb.afrh.modify(|_, w| unsafe {
-            w.afrh8().bits(0x0E)
-             .afrh9().bits(0x0E)
-             .afrh10().bits(0x0E)
-             .afrh11().bits(0x0E)
-        });
-
-        // GPIOC ###################################################
-        pc.moder.modify(|_, w| unsafe {
-            w.moder6().bits(0b10)
-             .moder7().bits(0b10)
-             .moder10().bits(0b10)
-        });
-        pc.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr6().bits(0b10)
-             .ospeedr7().bits(0b10)
-             .ospeedr10().bits(0b10)
-        });
-
-        pc.afrl.modify(|_, w| unsafe {
-            w.afrl6().bits(0x0E)
-             .afrl7().bits(0x0E)
-        });
-        pc.afrh.modify(|_, w| unsafe {
-            w.afrh10().bits(0x0E)
-        });
-
-        // GPIOD ###################################################
-        pd.moder.modify(|_, w| unsafe {
-            w.moder3().bits(0b10)
-             .moder6().bits(0b10)
-        });
-        pd.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr3().bits(0b10)
-             .ospeedr6().bits(0b10)
-        });
+// Program PLLSAI for the pixel clock giving `refresh_rate` on a panel. The
+// solver works from the PLL input (after M) which is shared with the main
+// PLL, so this has to be redone whenever the main PLL source changes.
+pub fn configure_pixel_clock(timing : &ltdc::PanelTiming, refresh_rate : u32)
+        -> Result<LcdClock, LcdError> {
+    let vco_in = rcc::get_clocks_freq().vco_in;
 
-        pd.afrl.modify(|_, w| unsafe {
-            w.afrl3().bits(0x0E)
-             .afrl6().bits(0x0E)
-        });
+    let clk = match solve_lcd_clock(vco_in, timing.pixel_clock(refresh_rate)) {
+        Ok(c) => c,
+        Err(e) => return Err(LcdError::PixelClock(e)),
+    };
 
-        // GPIOF ###################################################
-        pf.moder.modify(|_, w| unsafe {
-            w.moder10().bits(0b10)
-        });
-        pf.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr10().bits(0b10)
-        });
+    rcc::clock_ctrl(rcc::Clock::PLL_SAI_ON, false);
 
-        pf.afrh.modify(|_, w| unsafe {
-            w.afrh10().bits(0x0E)
-        });
+    if let Err(e) = rcc::conf_lcd_pll(clk.r, clk.n) {
+        return Err(LcdError::PllSai(e));
+    }
 
-        // GPIOG ###################################################
-        pg.moder.modify(|_, w| unsafe {
-            w.moder6().bits(0b10)
-             .moder7().bits(0b10)
-             .moder10().bits(0b10)
-             .moder11().bits(0b10)
-             .moder12().bits(0b10)
-        });
-        pg.ospeedr.modify(|_, w| unsafe {
-            w.ospeedr6().bits(0b10)
-             .ospeedr7().bits(0b10)
-             .ospeedr10().bits(0b10)
-             .ospeedr11().bits(0b10)
-             .ospeedr12().bits(0b10)
-        });
+    rcc::conf_lcd_pllsai_divr(match clk.divr {
+        2 => rcc::LcdPllSaiDiv::Div2,
+        4 => rcc::LcdPllSaiDiv::Div4,
+        8 => rcc::LcdPllSaiDiv::Div8,
+        _ => rcc::LcdPllSaiDiv::Div16,
+    });
 
-        pg.afrl.modify(|_, w| unsafe {
-            w.afrl6().bits(0x0E)
-             .afrl7().bits(0x0E)
-        });
-        pg.afrh.modify(|_, w| unsafe {
-            w.afrh10().bits(0x09)
-             .afrh11().bits(0x0E)
-             .afrh12().bits(0x09)
-        });
+    rcc::clock_ctrl(rcc::Clock::PLL_SAI_ON, true);
+    if !rcc::wait_flag(rcc::ClkFlag::PLL_SAI_RDY, rcc::PLL_LOCK_TIMEOUT) {
+        return Err(LcdError::PllSai(RccError::PllSaiLockTimeout));
+    }
 
+    unsafe {
+        PIXEL_VCO_IN = vco_in;
     }
+    Ok(clk)
+}
 
-    fn put_pixel(&self, x : u16, y : u16) -> Result<(), LcdError> {
-        if x > 239 || y > 319 {
-            return Err(LcdError::OutOfScreen);
-        }
-        self.draw_line(x, y, 1, Direction::Horizontal);
-        Ok(())
+// refresh rate in mHz the panel is currently driven at
+pub fn refresh_rate_millihz() -> u32 {
+    LCD_TIMING.refresh_rate_millihz(rcc::get_clocks_freq().lcd_clk)
+}
+
+pub fn on_clock_change(_clocks : &Clocks) {
+    // PLLSAI does not depend on sysclk but on the PLL input, which is not
+    // part of a Clocks when the main PLL is off. Stopping PLLSAI blanks the
+    // panel, it is left alone when its input did not change.
+    if rcc::get_clocks_freq().vco_in == unsafe { PIXEL_VCO_IN } {
+        return;
     }
+    let _ = configure_pixel_clock(&LCD_TIMING, LCD_REFRESH_RATE);
 }
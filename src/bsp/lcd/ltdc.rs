@@ -7,6 +7,63 @@ pub enum Layer {
     Second,
 }
 
+// Panel synchronisation timings, in pixel clock periods for the horizontal
+// ones and in lines for the vertical ones.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PanelTiming {
+    pub hsync   : u16,
+    pub hbp     : u16,
+    pub width   : u16,
+    pub hfp     : u16,
+    pub vsync   : u16,
+    pub vbp     : u16,
+    pub height  : u16,
+    pub vfp     : u16,
+}
+
+impl PanelTiming {
+    pub fn total_width(&self) -> u32 {
+        (self.hsync + self.hbp + self.width + self.hfp) as u32
+    }
+
+    pub fn total_height(&self) -> u32 {
+        (self.vsync + self.vbp + self.height + self.vfp) as u32
+    }
+
+    pub fn pixel_clock(&self, refresh_rate : u32) -> u32 {
+        self.total_width() * self.total_height() * refresh_rate
+    }
+
+    // refresh rate in mHz obtained with the given pixel clock
+    pub fn refresh_rate_millihz(&self, pixel_clk : u32) -> u32 {
+        (pixel_clk as u64 * 1000 / (self.total_width() * self.total_height()) as u64) as u32
+    }
+}
+
+pub fn configure_timing(t : &PanelTiming) {
+    let ltdc = unsafe{&*LTDC::ptr()};
+
+    ltdc.sscr.modify(|_, w| unsafe {
+        w.hsw().bits(t.hsync - 1)
+         .vsh().bits(t.vsync - 1)
+    });
+
+    ltdc.bpcr.modify(|_, w| unsafe {
+        w.ahbp().bits(t.hsync + t.hbp - 1)
+         .avbp().bits(t.vsync + t.vbp - 1)
+    });
+
+    ltdc.awcr.modify(|_, w| unsafe{
+        w.aah().bits(t.hsync + t.hbp + t.width - 1)
+         .aav().bits(t.vsync + t.vbp + t.height - 1)
+    });
+
+    ltdc.twcr.modify(|_, w| unsafe{
+        w.totalw().bits(t.hsync + t.hbp + t.width + t.hfp - 1)
+         .totalh().bits(t.vsync + t.vbp + t.height + t.vfp - 1)
+    });
+}
+
 pub fn set_layer_position(l : Layer, xpos : u16, ypos : u16) {
     let ltdc = unsafe{&*LTDC::ptr()};
 
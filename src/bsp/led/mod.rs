@@ -1,77 +1,87 @@
-use stm32f429::{RCC, GPIOG};
+use spl_rs::gpio::{Pin, PinId, PortG, N13, N14, Output, PushPull};
+use hal::digital;
 
-pub enum LedName {
-    Led3,
-    Led4,
+// LD3 (green) is wired to PG13 and LD4 (red) to PG14, they light up when
+// their pin is driven high
+pub struct Led<N> {
+    pin : Pin<PortG, N, Output<PushPull>>,
 }
 
-pub struct Led {
-    n : LedName,
+pub type Led3 = Led<N13>;
+pub type Led4 = Led<N14>;
+
+impl Led<N13> {
+    pub fn led3<MODE>(pin : Pin<PortG, N13, MODE>) -> Led3 {
+        Led {
+            pin : pin.into_push_pull_output(),
+        }
+    }
 }
 
-impl Led {
-    pub fn new(n : LedName) -> Led {
+impl Led<N14> {
+    pub fn led4<MODE>(pin : Pin<PortG, N14, MODE>) -> Led4 {
         Led {
-            n,
+            pin : pin.into_push_pull_output(),
         }
     }
+}
+
+impl<N : PinId> Led<N> {
+    pub fn on(&mut self) {
+        self.pin.set_high();
+    }
+
+    pub fn off(&mut self) {
+        self.pin.set_low();
+    }
 
-    pub fn init(&self) {
-        let rcc = unsafe {&*RCC::ptr()};
-        let pg  = unsafe {&*GPIOG::ptr()};
-
-        rcc.ahb1enr.modify(|_, w| w.gpiogen().bit(true));
-
-        match self.n {
-            LedName::Led3 => {
-                pg.moder.modify(|_, w| unsafe {
-                    w.moder13().bits(0b01)
-                });
-            },
-            LedName::Led4 => {
-                pg.moder.modify(|_, w| unsafe {
-                    w.moder14().bits(0b01)
-                });
-            },
-        };
+    pub fn toggle(&mut self) {
+        self.pin.toggle();
     }
 
-    pub fn on(&self) {
-        let pg = unsafe {&*GPIOG::ptr()};
+    pub fn is_on(&self) -> bool {
+        self.pin.is_set_high()
+    }
 
-        match self.n {
-            LedName::Led3 => pg.bsrr.write(|w| w.bs13().bit(true)),
-            LedName::Led4 => pg.bsrr.write(|w| w.bs14().bit(true)),
-        };
+    // the pin is left driving the last level
+    pub fn free(self) -> Pin<PortG, N, Output<PushPull>> {
+        self.pin
     }
+}
 
-    pub fn off(&self) {
-        let pg = unsafe {&*GPIOG::ptr()};
+impl<N : PinId> digital::OutputPin for Led<N> {
+    fn set_high(&mut self) {
+        self.on();
+    }
+
+    fn set_low(&mut self) {
+        self.off();
+    }
+}
+
+impl<N : PinId> digital::StatefulOutputPin for Led<N> {
+    fn is_set_high(&self) -> bool {
+        self.is_on()
+    }
+
+    fn is_set_low(&self) -> bool {
+        !self.is_on()
+    }
+}
+
+impl<N : PinId> digital::ToggleableOutputPin for Led<N> {
+    fn toggle(&mut self) {
+        Led::toggle(self);
+    }
+}
 
-        match self.n {
-            LedName::Led3 => pg.bsrr.write(|w| w.br13().bit(true)),
-            LedName::Led4 => pg.bsrr.write(|w| w.br14().bit(true)),
-        };
+// the pin is an output, reading it gives the level actually on the line
+impl<N : PinId> digital::InputPin for Led<N> {
+    fn is_high(&self) -> bool {
+        self.pin.is_high()
     }
 
-    pub fn toggle(&self) {
-        let pg = unsafe {&*GPIOG::ptr()};
-
-        match self.n {
-            LedName::Led3 => {
-                if pg.idr.read().idr13().bit() {
-                    pg.bsrr.write(|w| w.br13().bit(true))
-                } else {
-                    pg.bsrr.write(|w| w.bs13().bit(true))
-                }
-            },
-            LedName::Led4 => {
-                if pg.idr.read().idr14().bit() {
-                    pg.bsrr.write(|w| w.br14().bit(true))
-                } else {
-                    pg.bsrr.write(|w| w.bs14().bit(true))
-                }
-            },
-        };
+    fn is_low(&self) -> bool {
+        !self.pin.is_high()
     }
 }
@@ -2,3 +2,7 @@ pub mod led;
 pub mod sdram;
 pub mod l3gd20;
 pub mod lcd;
+pub mod spi_bus;
+
+// hardware independent, see system/mod.rs
+pub use stm32f429i_disco::bsp::pinmap;
@@ -0,0 +1,201 @@
+use spl_rs::pins::{PinConfig, Port, Mode, AltFn, OutType, OutSpeed, PullType, find_overlap};
+
+// Every signal of the stm32f429i-disco used by the board drivers, grouped by
+// function. A pin must appear in a single group, see check().
+
+macro_rules! af {
+    ($port:ident, $pin:expr, $af:ident) => {
+        af!($port, $pin, $af, High, NoPull)
+    };
+    ($port:ident, $pin:expr, $af:ident, $speed:ident, $pull:ident) => {
+        PinConfig {
+            port    : Port::$port,
+            pin     : $pin,
+            mode    : Mode::AltFn,
+            af      : AltFn::$af,
+            otype   : OutType::PushPull,
+            speed   : OutSpeed::$speed,
+            pull    : PullType::$pull,
+        }
+    };
+}
+
+macro_rules! af_od {
+    ($port:ident, $pin:expr, $af:ident) => {
+        PinConfig {
+            port    : Port::$port,
+            pin     : $pin,
+            mode    : Mode::AltFn,
+            af      : AltFn::$af,
+            otype   : OutType::OpenDrain,
+            speed   : OutSpeed::Medium,
+            pull    : PullType::NoPull,
+        }
+    };
+}
+
+macro_rules! output {
+    ($port:ident, $pin:expr) => {
+        PinConfig {
+            port    : Port::$port,
+            pin     : $pin,
+            mode    : Mode::Output,
+            af      : AltFn::Sys,
+            otype   : OutType::PushPull,
+            speed   : OutSpeed::Low,
+            pull    : PullType::NoPull,
+        }
+    };
+}
+
+macro_rules! input {
+    ($port:ident, $pin:expr, $pull:ident) => {
+        PinConfig {
+            port    : Port::$port,
+            pin     : $pin,
+            mode    : Mode::Input,
+            af      : AltFn::Sys,
+            otype   : OutType::PushPull,
+            speed   : OutSpeed::Low,
+            pull    : PullType::$pull,
+        }
+    };
+}
+
+// IS42S16400J on bank 2 of the FMC
+pub const SDRAM : [PinConfig; 38] = [
+    af!(B, 5, FmcSdioOtg2Fs),   // SDCKE1
+    af!(B, 6, FmcSdioOtg2Fs),   // SDNE1
+    af!(C, 0, FmcSdioOtg2Fs),   // SDNWE
+    af!(D, 0, FmcSdioOtg2Fs),   // D2
+    af!(D, 1, FmcSdioOtg2Fs),   // D3
+    af!(D, 8, FmcSdioOtg2Fs),   // D13
+    af!(D, 9, FmcSdioOtg2Fs),   // D14
+    af!(D, 10, FmcSdioOtg2Fs),  // D15
+    af!(D, 14, FmcSdioOtg2Fs),  // D0
+    af!(D, 15, FmcSdioOtg2Fs),  // D1
+    af!(E, 0, FmcSdioOtg2Fs),   // NBL0
+    af!(E, 1, FmcSdioOtg2Fs),   // NBL1
+    af!(E, 7, FmcSdioOtg2Fs),   // D4
+    af!(E, 8, FmcSdioOtg2Fs),   // D5
+    af!(E, 9, FmcSdioOtg2Fs),   // D6
+    af!(E, 10, FmcSdioOtg2Fs),  // D7
+    af!(E, 11, FmcSdioOtg2Fs),  // D8
+    af!(E, 12, FmcSdioOtg2Fs),  // D9
+    af!(E, 13, FmcSdioOtg2Fs),  // D10
+    af!(E, 14, FmcSdioOtg2Fs),  // D11
+    af!(E, 15, FmcSdioOtg2Fs),  // D12
+    af!(F, 0, FmcSdioOtg2Fs),   // A0
+    af!(F, 1, FmcSdioOtg2Fs),   // A1
+    af!(F, 2, FmcSdioOtg2Fs),   // A2
+    af!(F, 3, FmcSdioOtg2Fs),   // A3
+    af!(F, 4, FmcSdioOtg2Fs),   // A4
+    af!(F, 5, FmcSdioOtg2Fs),   // A5
+    af!(F, 11, FmcSdioOtg2Fs),  // SDNRAS
+    af!(F, 12, FmcSdioOtg2Fs),  // A6
+    af!(F, 13, FmcSdioOtg2Fs),  // A7
+    af!(F, 14, FmcSdioOtg2Fs),  // A8
+    af!(F, 15, FmcSdioOtg2Fs),  // A9
+    af!(G, 0, FmcSdioOtg2Fs),   // A10
+    af!(G, 1, FmcSdioOtg2Fs),   // A11
+    af!(G, 4, FmcSdioOtg2Fs),   // BA0
+    af!(G, 5, FmcSdioOtg2Fs),   // BA1
+    af!(G, 8, FmcSdioOtg2Fs),   // SDCLK
+    af!(G, 15, FmcSdioOtg2Fs),  // SDNCAS
+];
+
+// RGB666 interface of the ILI9341, R3/R6/G3/B4 are on AF9
+pub const LTDC : [PinConfig; 22] = [
+    af!(A, 3, Lcd),                 // B5
+    af!(A, 4, Lcd),                 // VSYNC
+    af!(A, 6, Lcd),                 // G2
+    af!(A, 11, Lcd),                // R4
+    af!(A, 12, Lcd),                // R5
+    af!(B, 0, Can12Tim121314Lcd),   // R3
+    af!(B, 1, Can12Tim121314Lcd),   // R6
+    af!(B, 8, Lcd),                 // B6
+    af!(B, 9, Lcd),                 // B7
+    af!(B, 10, Lcd),                // G4
+    af!(B, 11, Lcd),                // G5
+    af!(C, 6, Lcd),                 // HSYNC
+    af!(C, 7, Lcd),                 // G6
+    af!(C, 10, Lcd),                // R2
+    af!(D, 3, Lcd),                 // G7
+    af!(D, 6, Lcd),                 // B2
+    af!(F, 10, Lcd),                // DE
+    af!(G, 6, Lcd),                 // R7
+    af!(G, 7, Lcd),                 // CLK
+    af!(G, 10, Can12Tim121314Lcd),  // G3
+    af!(G, 11, Lcd),                // B3
+    af!(G, 12, Can12Tim121314Lcd),  // B4
+];
+
+// SPI5 is shared by the ILI9341 control interface and the L3GD20
+pub const SPI5 : [PinConfig; 3] = [
+    af!(F, 7, Spi123456),   // SCK
+    af!(F, 8, Spi123456),   // MISO
+    af!(F, 9, Spi123456),   // MOSI
+];
+
+pub const LCD_CTRL : [PinConfig; 2] = [
+    output!(C, 2),          // NCS
+    output!(D, 13),         // WRX / DCX
+];
+
+pub const GYRO : [PinConfig; 3] = [
+    output!(C, 1),          // CS
+    input!(A, 1, NoPull),   // INT1
+    input!(A, 2, NoPull),   // INT2
+];
+
+pub const LEDS : [PinConfig; 2] = [
+    output!(G, 13),         // LD3, green
+    output!(G, 14),         // LD4, red
+];
+
+// B1 has its own pull-down on the board
+pub const USER_BUTTON : [PinConfig; 1] = [
+    input!(A, 0, NoPull),
+];
+
+// STMPE811 touch screen controller
+pub const TOUCH : [PinConfig; 3] = [
+    af_od!(A, 8, I2c123),   // I2C3 SCL
+    af_od!(C, 9, I2c123),   // I2C3 SDA
+    input!(A, 15, PullUp),  // INT, open-drain on the controller side
+];
+
+pub const ALL : [&'static [PinConfig]; 8] = [
+    &SDRAM, &LTDC, &SPI5, &LCD_CTRL, &GYRO, &LEDS, &USER_BUTTON, &TOUCH,
+];
+
+// Pins worth freezing once the display runs from the SDRAM, see
+// gpio::lock_groups. Their ports can't be locked again afterwards.
+pub const LOCKED : [&'static [PinConfig]; 2] = [
+    &SDRAM, &LTDC,
+];
+
+// a pin listed in two groups, None if the table is consistent
+pub fn check() -> Option<(Port, u8)> {
+    find_overlap(&ALL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_pin_assigned_twice() {
+        assert_eq!(check(), None);
+    }
+
+    #[test]
+    fn pin_assigned_twice() {
+        // the gyro chip select given to a second function
+        const TWICE : [PinConfig; 1] = [output!(C, 1)];
+        assert_eq!(find_overlap(&[&SPI5, &GYRO, &TWICE]), Some((Port::C, 1)));
+
+        const SAME_GROUP : [PinConfig; 2] = [output!(G, 13), input!(G, 13, PullUp)];
+        assert_eq!(find_overlap(&[&SAME_GROUP]), Some((Port::G, 13)));
+    }
+}
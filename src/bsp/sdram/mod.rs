@@ -1,19 +1,27 @@
 use stm32f429::*;
 use misc;
 use cortex_m;
+use spl_rs::{rcc, gpio, clk_gate};
+use bsp::pinmap;
+use spl_rs::clk_gate::{Gate, ClkGateError};
+use system::profiles;
+use system::profiles::ProfileError;
+use system::clk_config::Clocks;
 
 use core::fmt;
 
 pub const SDRAM_SIZE                            : u32 = 0x800000; // bytes, 0x200000 words
 pub const SDRAM_BANK_ADDR                       : u32 = 0xD0000000;
 
-const GPIO_ALT_FN                               : u8  = 0b10;
-const ALT_FN_FMC                                : u8  = 0b1100;
-const GPIO_SPEED_FMC                            : u8  = 0b10;
-const GPIO_NPUPD_FMC                            : u8  = 0b00;
-
 const SDRAM_STORAGE_ELEMENTS_SIZE               : u32 = 4; // bytes
 
+const OWNER                                     : &'static str = "sdram";
+
+// 4096 rows to refresh every 64ms, one row every 15.62us
+const SDRAM_ROW_REFRESH_RATE                    : u32 = 64_000; // Hz
+const SDRAM_REFRESH_MARGIN                      : u32 = 20;     // sdclk cycles
+const SDRAM_REFRESH_COUNT_MIN                   : u32 = 41;
+
 pub enum SdRamError {
     OutOfBoundsAccess(u32), // contains size of overhead access
     RefreshError,
@@ -30,302 +38,35 @@ impl fmt::Display for SdRamError {
     }
 }
 
-pub fn init_gpios() {
-    let rcc = unsafe{&*RCC::ptr()};
-    let pb = unsafe{&*GPIOB::ptr()};
-    let pc = unsafe{&*GPIOC::ptr()};
-    let pd = unsafe{&*GPIOD::ptr()};
-    let pe = unsafe{&*GPIOE::ptr()};
-    let pf = unsafe{&*GPIOF::ptr()};
-    let pg = unsafe{&*GPIOG::ptr()};
-
-    rcc.ahb1enr.modify(|_, w|{
-        w.gpioben().bit(true)
-         .gpiocen().bit(true)
-         .gpioden().bit(true)
-         .gpioeen().bit(true)
-         .gpiofen().bit(true)
-         .gpiogen().bit(true)
-    });
-
-    // port B config, pins 5 and 6
-    pb.moder.modify(|_, w| unsafe{
-        w.moder5().bits(GPIO_ALT_FN)
-         .moder6().bits(GPIO_ALT_FN)
-    });
-    pb.otyper.modify(|_, w|
-        w.ot5().bit(false)
-         .ot6().bit(false)
-    );
-    pb.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr5().bits(GPIO_SPEED_FMC)
-         .ospeedr6().bits(GPIO_SPEED_FMC)
-    });
-    pb.pupdr.modify(|_, w| unsafe {
-        w.pupdr5().bits(GPIO_NPUPD_FMC)
-         .pupdr6().bits(GPIO_NPUPD_FMC)
-    });
-    pb.afrl.modify(|_, w| unsafe {
-        w.afrl5().bits(ALT_FN_FMC)
-         .afrl6().bits(ALT_FN_FMC)
-    });
-
-    // port C config
-    pc.moder.modify(|_, w| unsafe{
-        w.moder0().bits(GPIO_ALT_FN)
-    });
-    pc.otyper.modify(|_, w|
-        w.ot0().bit(false)
-    );
-    pc.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr0().bits(GPIO_SPEED_FMC)
-    });
-    pc.pupdr.modify(|_, w| unsafe {
-        w.pupdr0().bits(GPIO_NPUPD_FMC)
-    });
-    pc.afrl.modify(|_, w| unsafe {
-        w.afrl0().bits(ALT_FN_FMC)
-    });
-
-    // port D config
-    pd.moder.modify(|_, w| unsafe{
-        w.moder0().bits(GPIO_ALT_FN)
-         .moder1().bits(GPIO_ALT_FN)
-         .moder8().bits(GPIO_ALT_FN)
-         .moder9().bits(GPIO_ALT_FN)
-         .moder10().bits(GPIO_ALT_FN)
-         .moder14().bits(GPIO_ALT_FN)
-         .moder15().bits(GPIO_ALT_FN)
-    });
-    pd.otyper.modify(|_, w|
-        w.ot0().bit(false)
-         .ot1().bit(false)
-         .ot8().bit(false)
-         .ot9().bit(false)
-         .ot10().bit(false)
-         .ot14().bit(false)
-         .ot15().bit(false)
-    );
-    pd.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr0().bits(GPIO_SPEED_FMC)
-         .ospeedr1().bits(GPIO_SPEED_FMC)
-         .ospeedr8().bits(GPIO_SPEED_FMC)
-         .ospeedr9().bits(GPIO_SPEED_FMC)
-         .ospeedr10().bits(GPIO_SPEED_FMC)
-         .ospeedr14().bits(GPIO_SPEED_FMC)
-         .ospeedr15().bits(GPIO_SPEED_FMC)
-    });
-    pd.pupdr.modify(|_, w| unsafe {
-        w.pupdr0().bits(GPIO_NPUPD_FMC)
-         .pupdr1().bits(GPIO_NPUPD_FMC)
-         .pupdr8().bits(GPIO_NPUPD_FMC)
-         .pupdr9().bits(GPIO_NPUPD_FMC)
-         .pupdr10().bits(GPIO_NPUPD_FMC)
-         .pupdr14().bits(GPIO_NPUPD_FMC)
-         .pupdr15().bits(GPIO_NPUPD_FMC)
-    });
-    pd.afrl.modify(|_, w| unsafe {
-        w.afrl0().bits(ALT_FN_FMC)
-         .afrl1().bits(ALT_FN_FMC)
-    });
-    pd.afrh.modify(|_, w| unsafe {
-        w.afrh8().bits(ALT_FN_FMC)
-         .afrh9().bits(ALT_FN_FMC)
-         .afrh10().bits(ALT_FN_FMC)
-         .afrh14().bits(ALT_FN_FMC)
-         .afrh15().bits(ALT_FN_FMC)
-    });
-
-    // port E config
-    pe.moder.modify(|_, w| unsafe{
-        w.moder0().bits(GPIO_ALT_FN)
-         .moder1().bits(GPIO_ALT_FN)
-         .moder7().bits(GPIO_ALT_FN)
-         .moder8().bits(GPIO_ALT_FN)
-         .moder9().bits(GPIO_ALT_FN)
-         .moder10().bits(GPIO_ALT_FN)
-         .moder11().bits(GPIO_ALT_FN)
-         .moder12().bits(GPIO_ALT_FN)
-         .moder13().bits(GPIO_ALT_FN)
-         .moder14().bits(GPIO_ALT_FN)
-         .moder15().bits(GPIO_ALT_FN)
-    });
-    pe.otyper.modify(|_, w|
-        w.ot0().bit(false)
-         .ot1().bit(false)
-         .ot7().bit(false)
-         .ot8().bit(false)
-         .ot9().bit(false)
-         .ot10().bit(false)
-         .ot11().bit(false)
-         .ot12().bit(false)
-         .ot13().bit(false)
-         .ot14().bit(false)
-         .ot15().bit(false)
-    );
-    pe.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr0().bits(GPIO_SPEED_FMC)
-         .ospeedr1().bits(GPIO_SPEED_FMC)
-         .ospeedr7().bits(GPIO_SPEED_FMC)
-         .ospeedr8().bits(GPIO_SPEED_FMC)
-         .ospeedr9().bits(GPIO_SPEED_FMC)
-         .ospeedr10().bits(GPIO_SPEED_FMC)
-         .ospeedr11().bits(GPIO_SPEED_FMC)
-         .ospeedr12().bits(GPIO_SPEED_FMC)
-         .ospeedr13().bits(GPIO_SPEED_FMC)
-         .ospeedr14().bits(GPIO_SPEED_FMC)
-         .ospeedr15().bits(GPIO_SPEED_FMC)
-    });
-    pe.pupdr.modify(|_, w| unsafe {
-        w.pupdr0().bits(GPIO_NPUPD_FMC)
-         .pupdr1().bits(GPIO_NPUPD_FMC)
-         .pupdr7().bits(GPIO_NPUPD_FMC)
-         .pupdr8().bits(GPIO_NPUPD_FMC)
-         .pupdr9().bits(GPIO_NPUPD_FMC)
-         .pupdr10().bits(GPIO_NPUPD_FMC)
-         .pupdr11().bits(GPIO_NPUPD_FMC)
-         .pupdr12().bits(GPIO_NPUPD_FMC)
-         .pupdr13().bits(GPIO_NPUPD_FMC)
-         .pupdr14().bits(GPIO_NPUPD_FMC)
-         .pupdr15().bits(GPIO_NPUPD_FMC)
-    });
-    pe.afrl.modify(|_, w| unsafe {
-        w.afrl0().bits(ALT_FN_FMC)
-         .afrl1().bits(ALT_FN_FMC)
-         .afrl7().bits(ALT_FN_FMC)
-    });
-    pe.afrh.modify(|_, w| unsafe {
-        w.afrh8().bits(ALT_FN_FMC)
-         .afrh9().bits(ALT_FN_FMC)
-         .afrh10().bits(ALT_FN_FMC)
-         .afrh11().bits(ALT_FN_FMC)
-         .afrh12().bits(ALT_FN_FMC)
-         .afrh13().bits(ALT_FN_FMC)
-         .afrh14().bits(ALT_FN_FMC)
-         .afrh15().bits(ALT_FN_FMC)
-    });
-
-    // port F config
-    pf.moder.modify(|_, w| unsafe{
-        w.moder0().bits(GPIO_ALT_FN)
-         .moder1().bits(GPIO_ALT_FN)
-         .moder2().bits(GPIO_ALT_FN)
-         .moder3().bits(GPIO_ALT_FN)
-         .moder4().bits(GPIO_ALT_FN)
-         .moder5().bits(GPIO_ALT_FN)
-         .moder11().bits(GPIO_ALT_FN)
-         .moder12().bits(GPIO_ALT_FN)
-         .moder13().bits(GPIO_ALT_FN)
-         .moder14().bits(GPIO_ALT_FN)
-         .moder15().bits(GPIO_ALT_FN)
-    });
-    pf.otyper.modify(|_, w|
-        w.ot0().bit(false)
-         .ot1().bit(false)
-         .ot2().bit(false)
-         .ot3().bit(false)
-         .ot4().bit(false)
-         .ot5().bit(false)
-         .ot11().bit(false)
-         .ot12().bit(false)
-         .ot13().bit(false)
-         .ot14().bit(false)
-         .ot15().bit(false)
-    );
-    pf.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr0().bits(GPIO_SPEED_FMC)
-         .ospeedr1().bits(GPIO_SPEED_FMC)
-         .ospeedr2().bits(GPIO_SPEED_FMC)
-         .ospeedr3().bits(GPIO_SPEED_FMC)
-         .ospeedr4().bits(GPIO_SPEED_FMC)
-         .ospeedr5().bits(GPIO_SPEED_FMC)
-         .ospeedr11().bits(GPIO_SPEED_FMC)
-         .ospeedr12().bits(GPIO_SPEED_FMC)
-         .ospeedr13().bits(GPIO_SPEED_FMC)
-         .ospeedr14().bits(GPIO_SPEED_FMC)
-         .ospeedr15().bits(GPIO_SPEED_FMC)
-    });
-    pf.pupdr.modify(|_, w| unsafe {
-        w.pupdr0().bits(GPIO_NPUPD_FMC)
-         .pupdr1().bits(GPIO_NPUPD_FMC)
-         .pupdr2().bits(GPIO_NPUPD_FMC)
-         .pupdr3().bits(GPIO_NPUPD_FMC)
-         .pupdr4().bits(GPIO_NPUPD_FMC)
-         .pupdr5().bits(GPIO_NPUPD_FMC)
-         .pupdr11().bits(GPIO_NPUPD_FMC)
-         .pupdr12().bits(GPIO_NPUPD_FMC)
-         .pupdr13().bits(GPIO_NPUPD_FMC)
-         .pupdr14().bits(GPIO_NPUPD_FMC)
-         .pupdr15().bits(GPIO_NPUPD_FMC)
-    });
-    pf.afrl.modify(|_, w| unsafe {
-        w.afrl0().bits(ALT_FN_FMC)
-         .afrl1().bits(ALT_FN_FMC)
-         .afrl2().bits(ALT_FN_FMC)
-         .afrl3().bits(ALT_FN_FMC)
-         .afrl4().bits(ALT_FN_FMC)
-         .afrl5().bits(ALT_FN_FMC)
-    });
-    pf.afrh.modify(|_, w| unsafe {
-        w.afrh11().bits(ALT_FN_FMC)
-         .afrh12().bits(ALT_FN_FMC)
-         .afrh13().bits(ALT_FN_FMC)
-         .afrh14().bits(ALT_FN_FMC)
-         .afrh15().bits(ALT_FN_FMC)
-    });
+#[derive(Debug)]
+pub enum SdramError {
+    PinConflict(gpio::PinConflict),
+    PinConfig,
+    Gate(ClkGateError),
+    Profile(ProfileError),
+}
 
-    // port G config
-    pg.moder.modify(|_, w| unsafe{
-        w.moder0().bits(GPIO_ALT_FN)
-         .moder1().bits(GPIO_ALT_FN)
-         .moder4().bits(GPIO_ALT_FN)
-         .moder5().bits(GPIO_ALT_FN)
-         .moder8().bits(GPIO_ALT_FN)
-         .moder15().bits(GPIO_ALT_FN)
-    });
-    pg.otyper.modify(|_, w|
-        w.ot0().bit(false)
-         .ot1().bit(false)
-         .ot4().bit(false)
-         .ot5().bit(false)
-         .ot8().bit(false)
-         .ot15().bit(false)
-    );
-    pg.ospeedr.modify(|_, w| unsafe{
-        w.ospeedr0().bits(GPIO_SPEED_FMC)
-         .ospeedr1().bits(GPIO_SPEED_FMC)
-         .ospeedr4().bits(GPIO_SPEED_FMC)
-         .ospeedr5().bits(GPIO_SPEED_FMC)
-         .ospeedr8().bits(GPIO_SPEED_FMC)
-         .ospeedr15().bits(GPIO_SPEED_FMC)
-    });
-    pg.pupdr.modify(|_, w| unsafe {
-        w.pupdr0().bits(GPIO_NPUPD_FMC)
-         .pupdr1().bits(GPIO_NPUPD_FMC)
-         .pupdr4().bits(GPIO_NPUPD_FMC)
-         .pupdr5().bits(GPIO_NPUPD_FMC)
-         .pupdr8().bits(GPIO_NPUPD_FMC)
-         .pupdr15().bits(GPIO_NPUPD_FMC)
-    });
-    pg.afrl.modify(|_, w| unsafe {
-        w.afrl0().bits(ALT_FN_FMC)
-         .afrl1().bits(ALT_FN_FMC)
-         .afrl4().bits(ALT_FN_FMC)
-         .afrl5().bits(ALT_FN_FMC)
-    });
-    pg.afrh.modify(|_, w| unsafe {
-        w.afrh8().bits(ALT_FN_FMC)
-         .afrh15().bits(ALT_FN_FMC)
-    });
+pub fn init_gpios() -> Result<(), SdramError> {
+    let clks = Gate::Ahb1(gpio::port_clks(&pinmap::SDRAM));
+    clk_gate::acquire(clks, OWNER).map_err(SdramError::Gate)?;
+    if let Err(e) = gpio::claim_group(&pinmap::SDRAM, OWNER, false) {
+        let _ = clk_gate::release(clks, OWNER);
+        return Err(SdramError::PinConflict(e));
+    }
+    if gpio::apply(&pinmap::SDRAM).is_err() {
+        gpio::unclaim_group(&pinmap::SDRAM, OWNER);
+        let _ = clk_gate::release(clks, OWNER);
+        return Err(SdramError::PinConfig);
+    }
+    Ok(())
 }
 
-pub fn init() {
-    init_gpios();
+pub fn init() -> Result<(), SdramError> {
+    init_gpios()?;
 
     let fmc = unsafe{&*FMC::ptr()};
-    let rcc = unsafe{&*RCC::ptr()};
 
-    rcc.ahb3enr.write(|w| w.fmcen().bit(true));
+    clk_gate::acquire(Gate::Ahb3(rcc::Ahb3Enable::FSMC), OWNER).map_err(SdramError::Gate)?;
 
     fmc.sdcr1.modify(|_, w| unsafe {
         w.rpipe().bits(0b01)    // one hclk cycle delay
@@ -357,6 +98,37 @@ pub fn init() {
     });
 
     init_sequence();
+
+    profiles::register_pre(before_clock_change).map_err(SdramError::Profile)?;
+    profiles::register(on_clock_change).map_err(SdramError::Profile)
+}
+
+// sdclk runs at hclk / 2, see sdcr1 configuration
+pub fn refresh_count(hclk : u32) -> u16 {
+    let count = (hclk / 2) / SDRAM_ROW_REFRESH_RATE;
+    if count < SDRAM_REFRESH_COUNT_MIN + SDRAM_REFRESH_MARGIN {
+        SDRAM_REFRESH_COUNT_MIN as u16
+    } else {
+        (count - SDRAM_REFRESH_MARGIN) as u16
+    }
+}
+
+fn set_refresh_count(hclk : u32) {
+    let fmc = unsafe{&*FMC::ptr()};
+    fmc.sdrtr.modify(|_, w| unsafe{
+        w.count().bits(refresh_count(hclk))
+    });
+}
+
+// the count has to fit the slower clock while both may run, rows would be
+// refreshed too late if hclk drops with the count of the faster one
+pub fn before_clock_change(clocks : &Clocks) {
+    let hclk = rcc::get_clocks_freq().hclk;
+    set_refresh_count(if clocks.hclk() < hclk { clocks.hclk() } else { hclk });
+}
+
+pub fn on_clock_change(clocks : &Clocks) {
+    set_refresh_count(clocks.hclk());
 }
 
 pub fn init_sequence() {
@@ -421,7 +193,7 @@ pub fn init_sequence() {
     while fmc.sdsr.read().busy().bit() == true {}
 
     fmc.sdrtr.modify(|_, w| unsafe{
-        w.count().bits(1366)
+        w.count().bits(refresh_count(rcc::get_clocks_freq().hclk))
     });
 
     while fmc.sdsr.read().busy().bit() == true {}
@@ -0,0 +1,381 @@
+use cortex_m::interrupt;
+
+use spl_rs::{gpio, clk_gate};
+use spl_rs::gpio::{ErasedPin, Output, PushPull};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+use spl_rs::spi::{Spi, SpiBuilder, SpiError, SpiPeriph, DataFrameFormat, ClkPolCfg, ClkPhaCfg,
+                  DmaTransfer};
+use bsp::pinmap;
+use system::profiles;
+use system::profiles::ProfileError;
+use system::clk_config::Clocks;
+
+// SPI5 is wired to the L3GD20 and to the ILI9341 control interface. Each of
+// them registers its own settings and chip select here, the peripheral is
+// reconfigured whenever the device talked to changes. A transaction holds
+// the bus and keeps the chip select low for its whole length.
+
+const MAX_DEVICES   : usize = 4;
+const OWNER         : &'static str = "spi_bus";
+
+pub struct DeviceConfig {
+    pub cpol        : ClkPolCfg,
+    pub cpha        : ClkPhaCfg,
+    // highest spi clock accepted by the device
+    pub bit_rate    : u32,
+    pub frame       : DataFrameFormat,
+    // active low, e.g. gpioc.pc1.into_push_pull_output().erase()
+    pub cs          : ErasedPin<Output<PushPull>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DeviceId(usize);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BusError {
+    TooManyDevices,
+    // another transaction is running, e.g. from an interrupt
+    Busy,
+    UnknownDevice,
+    Spi(SpiError),
+    Clock(ClkGateError),
+    PinConflict(gpio::PinConflict),
+    PinConfig,
+    Profile(ProfileError),
+}
+
+struct Device {
+    cfg         : DeviceConfig,
+    spi         : Spi,
+    // set when the bus clock is too fast for the device, its transactions
+    // are refused until a clock change fixes it
+    rate_err    : Option<SpiError>,
+}
+
+static mut DEVICES : [Option<Device>; MAX_DEVICES] = [None, None, None, None];
+static mut LOCKED : bool = false;
+// device the peripheral is configured for
+static mut CURRENT : Option<usize> = None;
+// the bus clock changed since the dividers were computed
+static mut RATES_STALE : bool = false;
+// settings of the device a DMA transaction runs for, its cs is released
+// from the stream interrupt
+static mut DMA_CFG : Option<DeviceConfig> = None;
+static mut DMA_DONE : Option<fn()> = None;
+
+fn devices() -> &'static mut [Option<Device>; MAX_DEVICES] {
+    unsafe { &mut DEVICES }
+}
+
+fn lock() -> Result<(), BusError> {
+    interrupt::free(|_| unsafe {
+        if LOCKED {
+            Err(BusError::Busy)
+        } else {
+            LOCKED = true;
+            Ok(())
+        }
+    })
+}
+
+fn unlock() {
+    unsafe {
+        LOCKED = false;
+    }
+}
+
+fn build(cfg : &DeviceConfig) -> Result<Spi, BusError> {
+    SpiBuilder::new()
+        .spi_periph(SpiPeriph::Spi5)
+        .clock_polarity(cfg.cpol)
+        .clock_edge(cfg.cpha)
+        .data_frame_length(cfg.frame)
+        .bit_rate(cfg.bit_rate)
+        .sw_slave_mgmt(true)
+        .master(true)
+        .configure()
+        .map_err(BusError::Spi)
+}
+
+// The first device brings the bus up. The cs pin is kept until the device
+// is unregistered.
+pub fn register(cfg : DeviceConfig) -> Result<DeviceId, BusError> {
+    lock()?;
+    let res = register_locked(cfg);
+    unlock();
+    res
+}
+
+fn register_locked(mut cfg : DeviceConfig) -> Result<DeviceId, BusError> {
+    let slot = match devices().iter().position(|d| d.is_none()) {
+        Some(s) => s,
+        None => return Err(BusError::TooManyDevices),
+    };
+
+    let first = devices().iter().all(|d| d.is_none());
+    if first {
+        bring_up()?;
+    }
+
+    cfg.cs.set_high();
+
+    // configure() loads the new settings, whoever was current is not anymore
+    let spi = match build(&cfg) {
+        Ok(spi) => spi,
+        Err(e) => {
+            if first {
+                shut_down();
+            }
+            return Err(e);
+        },
+    };
+    devices()[slot] = Some(Device { cfg : cfg, spi : spi, rate_err : None });
+    unsafe {
+        CURRENT = Some(slot);
+    }
+    Ok(DeviceId(slot))
+}
+
+// Pins and clocks of the bus, nothing is held if a step fails. The clock
+// listener is left registered, it has nothing to do without devices.
+fn bring_up() -> Result<(), BusError> {
+    let gate = Gate::Ahb1(gpio::port_clks(&pinmap::SPI5));
+    profiles::register(on_clock_change).map_err(BusError::Profile)?;
+    clk_gate::acquire(gate, OWNER).map_err(BusError::Clock)?;
+    if let Err(e) = gpio::claim_group(&pinmap::SPI5, OWNER, true) {
+        let _ = clk_gate::release(gate, OWNER);
+        return Err(BusError::PinConflict(e));
+    }
+    if gpio::apply(&pinmap::SPI5).is_err() {
+        shut_down();
+        return Err(BusError::PinConfig);
+    }
+    Ok(())
+}
+
+fn shut_down() {
+    // can't fail, the pin numbers of the table are valid
+    let _ = gpio::release(&pinmap::SPI5);
+    gpio::unclaim_group(&pinmap::SPI5, OWNER);
+    let _ = clk_gate::release(Gate::Ahb1(gpio::port_clks(&pinmap::SPI5)), OWNER);
+}
+
+// The cs pin is handed back. The last device gone, SPI5 is disabled, its
+// clock gated and its pins released.
+pub fn unregister(id : DeviceId) -> Result<ErasedPin<Output<PushPull>>, BusError> {
+    lock()?;
+    let dev = match devices().get_mut(id.0) {
+        Some(d) => d.take(),
+        None => None,
+    };
+    let res = match dev {
+        Some(d) => {
+            let last = devices().iter().all(|d| d.is_none());
+            let cs = d.cfg.cs;
+            if last {
+                // the clock hold of the builder is shared by the devices
+                let _ = d.spi.release();
+                shut_down();
+            }
+            unsafe {
+                if CURRENT == Some(id.0) {
+                    CURRENT = None;
+                }
+            }
+            Ok(cs)
+        },
+        None => Err(BusError::UnknownDevice),
+    };
+    unlock();
+    res
+}
+
+// Run `f` with the bus configured for `id` and its chip select asserted.
+// Fails with Busy instead of waiting when the bus is taken, a transaction
+// can't be nested or started from an interrupt preempting another one.
+pub fn transaction<F, R>(id : DeviceId, f : F) -> Result<R, BusError>
+    where F : FnOnce(&Spi) -> Result<R, SpiError> {
+    lock()?;
+    let dev = match select(id) {
+        Ok(d) => d,
+        Err(e) => {
+            unlock();
+            return Err(e);
+        },
+    };
+
+    dev.cfg.cs.set_low();
+    let res = f(&dev.spi);
+    dev.cfg.cs.set_high();
+
+    unlock();
+    res.map_err(BusError::Spi)
+}
+
+pub fn write(id : DeviceId, data : &[u8]) -> Result<(), BusError> {
+    transaction(id, |spi| spi.write(data))
+}
+
+pub fn transfer(id : DeviceId, data : &mut [u8]) -> Result<(), BusError> {
+    transaction(id, |spi| spi.transfer(data))
+}
+
+// to be called with the bus locked
+fn select(id : DeviceId) -> Result<&'static mut Device, BusError> {
+    if unsafe { RATES_STALE } {
+        update_rates();
+    }
+    let dev = match devices().get_mut(id.0) {
+        Some(&mut Some(ref mut d)) => d,
+        _ => return Err(BusError::UnknownDevice),
+    };
+    if let Some(e) = dev.rate_err {
+        return Err(BusError::Spi(e));
+    }
+
+    if unsafe { CURRENT } != Some(id.0) {
+        dev.spi.reconfigure();
+        unsafe {
+            CURRENT = Some(id.0);
+        }
+    }
+    Ok(dev)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+// DMA transactions
+/////////////////////////////////////////////////////////////////////////////////
+
+// The bus stays locked from the start of the transfer until wait(), the chip
+// select is released as soon as the last frame is in, before `done` is
+// called in interrupt context. wait() has to be called even when `done` is
+// used, it hands the bus back. Dropping the transfer stops it and hands the
+// bus back as well.
+
+#[must_use]
+pub struct BusTransfer<B> {
+    id          : DeviceId,
+    // only taken by wait, which consumes the handle
+    transfer    : Option<DmaTransfer<B>>,
+}
+
+impl<B> BusTransfer<B> {
+    pub fn is_done(&self) -> bool {
+        self.transfer.as_ref().map_or(true, |t| t.is_done())
+    }
+
+    pub fn wait(mut self) -> (B, Result<(), BusError>) {
+        let (spi, buf, res) = self.transfer.take().unwrap().wait();
+        restore(self.id, spi);
+        (buf, res.map_err(BusError::Spi))
+    }
+}
+
+impl<B> Drop for BusTransfer<B> {
+    fn drop(&mut self) {
+        if let Some(t) = self.transfer.take() {
+            let (spi, _) = t.abort();
+            restore(self.id, spi);
+        }
+    }
+}
+
+// Put the device back in the table and unlock the bus. The streams must be
+// stopped, the interrupt can't look at DMA_CFG anymore.
+fn restore(id : DeviceId, spi : Spi) {
+    if let Some(mut cfg) = unsafe { DMA_CFG.take() } {
+        cfg.cs.set_high();
+        devices()[id.0] = Some(Device { cfg : cfg, spi : spi, rate_err : None });
+    }
+    unsafe {
+        DMA_DONE = None;
+    }
+    unlock();
+}
+
+// rx stream interrupt
+fn dma_done() {
+    unsafe {
+        if let Some(ref mut cfg) = DMA_CFG {
+            cfg.cs.set_high();
+        }
+        if let Some(f) = DMA_DONE {
+            f();
+        }
+    }
+}
+
+fn start_dma<B, F>(id : DeviceId, buf : B, done : Option<fn()>, start : F)
+    -> Result<BusTransfer<B>, (B, BusError)>
+    where F : FnOnce(Spi, B, Option<fn()>) -> Result<DmaTransfer<B>, (Spi, B, SpiError)> {
+    if let Err(e) = lock() {
+        return Err((buf, e));
+    }
+    if let Err(e) = select(id) {
+        unlock();
+        return Err((buf, e));
+    }
+
+    // the device leaves the table for the length of the transfer, the lock
+    // keeps everyone else from looking for it
+    let Device { mut cfg, spi, .. } = match devices()[id.0].take() {
+        Some(d) => d,
+        None => {
+            unlock();
+            return Err((buf, BusError::UnknownDevice));
+        },
+    };
+    cfg.cs.set_low();
+    unsafe {
+        DMA_CFG = Some(cfg);
+        DMA_DONE = done;
+    }
+
+    match start(spi, buf, Some(dma_done)) {
+        Ok(t) => Ok(BusTransfer { id : id, transfer : Some(t) }),
+        Err((spi, buf, e)) => {
+            restore(id, spi);
+            Err((buf, BusError::Spi(e)))
+        },
+    }
+}
+
+// full duplex, the received bytes replace the buffer content
+pub fn transfer_dma(id : DeviceId, buf : &'static mut [u8], done : Option<fn()>)
+    -> Result<BusTransfer<&'static mut [u8]>, (&'static mut [u8], BusError)> {
+    start_dma(id, buf, done, |spi, buf, done| spi.transfer_dma(buf, done))
+}
+
+pub fn write_dma(id : DeviceId, buf : &'static [u8], done : Option<fn()>)
+    -> Result<BusTransfer<&'static [u8]>, (&'static [u8], BusError)> {
+    start_dma(id, buf, done, |spi, buf, done| spi.write_dma(buf, done))
+}
+
+fn update_rates() {
+    // set_bit_rate loads the config of the device when it succeeds
+    let mut current = None;
+    for (i, d) in devices().iter_mut().enumerate() {
+        if let Some(ref mut d) = *d {
+            d.rate_err = d.spi.set_bit_rate(d.cfg.bit_rate).err();
+            if d.rate_err.is_none() {
+                current = Some(i);
+            }
+        }
+    }
+    unsafe {
+        CURRENT = current;
+        RATES_STALE = false;
+    }
+}
+
+// SPI5 is fed from pclk2, the dividers of every device are picked again. If
+// the switch happened during a transaction this is left to the next one.
+pub fn on_clock_change(_clocks : &Clocks) {
+    unsafe {
+        RATES_STALE = true;
+    }
+    if lock().is_ok() {
+        update_rates();
+        unlock();
+    }
+}
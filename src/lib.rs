@@ -0,0 +1,32 @@
+#![no_std]
+
+// Hardware independent part of the firmware: clock tree solving and
+// decoding, pin tables and input filtering. The firmware in main.rs uses it
+// under the same paths. It doesn't touch a register so it also builds for
+// the host, which is where its tests run:
+//
+//     cargo test --lib --target x86_64-unknown-linux-gnu
+//
+// (or whatever `rustc -vV` reports as host), the target is needed to
+// override the thumbv7em default of .cargo/config.
+
+#[macro_use]
+extern crate bitflags;
+
+// the test harness needs it
+#[cfg(test)]
+extern crate std;
+
+pub mod system {
+    pub mod clk_config;
+    pub mod debouncer;
+}
+
+pub mod spl_rs {
+    pub mod pins;
+    pub mod clk_decode;
+}
+
+pub mod bsp {
+    pub mod pinmap;
+}
@@ -13,8 +13,11 @@ extern crate stm32f429;
 #[macro_use]
 extern crate bitflags;
 extern crate bare_metal;
+extern crate embedded_hal as hal;
 extern crate volatile;
 
+extern crate stm32f429i_disco;
+
 extern crate panic_abort;
 
 use core::fmt::Write;
@@ -28,43 +31,56 @@ mod misc;
 mod system;
 mod spl_rs;
 
-use system::clks;
+use system::{clks, css, boot};
 use bsp::l3gd20::*;
 use bsp::led::*;
 use bsp::sdram;
 use misc::*;
 use bsp::lcd::{lcd, fonts};
-use spl_rs::gpio;
-use spl_rs::rcc;
-
-use stm32f429::GPIOG;
+use spl_rs::gpio::GpioExt;
 
 fn main() {
+    let boot_info = boot::capture();
+
     match clks::init() {
+        Ok(_) => (),
+        Err(_) => asm::bkpt(),
+    };
+
+    match css::enable() {
         Ok(()) => (),
-        Err(()) => asm::bkpt(),
+        Err(_) => asm::bkpt(),
     };
 
-    rcc::set_ahb1_periph_clk(rcc::Ahb1Enable::GPIOG, true);
+    // a watchdog reset deserves a look before going on, so does a boot
+    // that could not be recorded
+    match boot_info.map(|info| info.cause) {
+        Ok(boot::ResetCause::IndependentWatchdog) |
+        Ok(boot::ResetCause::WindowWatchdog) |
+        Err(_) => asm::bkpt(),
+        _ => (),
+    };
+    boot::clear_flags();
 
-    let pg = unsafe {&*GPIOG::ptr()};
-    gpio::port_others::configure(
-        pg,
-        13,
-        gpio::Mode::Output,
-        gpio::OutType::PushPull,
-        gpio::OutSpeed::Low,
-        gpio::PullType::NoPull
-    ).unwrap();
+    let p = stm32f429::Peripherals::take().unwrap();
+    let gpioa = p.GPIOA.split();
+    let gpioc = p.GPIOC.split();
+    let gpiog = p.GPIOG.split();
+    let mut led3 = Led::led3(gpiog.pg13);
 
-    L3GD20::get_instance().init().unwrap();
+    match L3GD20::get_instance().init(gpioc.pc1, gpioa.pa1) {
+        Ok(()) => (),
+        Err(_) => asm::bkpt(),
+    };
 
     loop {
+        css::poll();
+
         if L3GD20::get_instance().check_connection().is_ok() {
-            gpio::port_others::write(pg, 13, true).unwrap();
+            led3.on();
             delay(0xFFFF);
         }
-        gpio::port_others::write(pg, 13, false).unwrap();
+        led3.off();
         delay(0xFFFF);
     }
 }
@@ -6,3 +6,15 @@ pub fn delay(t : u32) {
         t.update(|t| *t -= 1);
     }
 }
+
+// evaluate `cond` until it holds or `timeout` iterations elapsed
+pub fn wait_until<F : Fn() -> bool>(cond : F, timeout : u32) -> bool {
+    let mut t = timeout;
+    while !cond() {
+        if t == 0 {
+            return false;
+        }
+        t -= 1;
+    }
+    true
+}
@@ -0,0 +1,105 @@
+// Bit-band aliases of the Cortex-M4. Every bit of the first megabyte of SRAM
+// and of the peripheral space has its own word in an alias region, a write
+// to that word is turned into a read-modify-write done by the bus in one
+// go. An interrupt can't slip in between the read and the write as it can
+// with modify().
+//
+// The bus still writes the whole register back: don't use it on registers
+// holding write-1-to-clear flags, they would be cleared as a side effect.
+
+use core::ptr;
+use cortex_m::interrupt;
+
+const SRAM_BASE     : usize = 0x2000_0000;
+const SRAM_ALIAS    : usize = 0x2200_0000;
+const PERIPH_BASE   : usize = 0x4000_0000;
+const PERIPH_ALIAS  : usize = 0x4200_0000;
+const REGION_SIZE   : usize = 0x0010_0000;
+
+// alias word of `bit` of the word at `addr`, None outside the bit-band regions
+pub fn alias(addr : usize, bit : u8) -> Option<usize> {
+    if bit > 31 {
+        return None;
+    }
+    // bits of an unaligned address belong to the word around it
+    let byte = addr & 0b11;
+    let addr = addr & !0b11;
+    let bit = bit as usize + byte * 8;
+    if bit > 31 {
+        return None;
+    }
+
+    let (base, alias) = if addr >= SRAM_BASE && addr < SRAM_BASE + REGION_SIZE {
+        (SRAM_BASE, SRAM_ALIAS)
+    } else if addr >= PERIPH_BASE && addr < PERIPH_BASE + REGION_SIZE {
+        (PERIPH_BASE, PERIPH_ALIAS)
+    } else {
+        return None;
+    };
+    Some(alias + (addr - base) * 32 + bit * 4)
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Bit {
+    alias : usize,
+}
+
+impl Bit {
+    pub fn new(addr : usize, bit : u8) -> Option<Bit> {
+        alias(addr, bit).map(|a| Bit { alias : a })
+    }
+
+    // bit of a register or of a static, None outside the bit-band regions
+    pub fn of<T>(reg : &T, bit : u8) -> Option<Bit> {
+        Bit::new(reg as *const T as usize, bit)
+    }
+
+    pub fn read(&self) -> bool {
+        unsafe { ptr::read_volatile(self.alias as *const u32) & 1 != 0 }
+    }
+
+    pub fn write(&self, state : bool) {
+        unsafe { ptr::write_volatile(self.alias as *mut u32, state as u32) }
+    }
+
+    pub fn set(&self) {
+        self.write(true);
+    }
+
+    pub fn clear(&self) {
+        self.write(false);
+    }
+}
+
+// word holding `bit` counted from `addr`, and the bit number in that word
+fn word(addr : usize, bit : u8) -> (usize, u32) {
+    let bit = (addr & 0b11) * 8 + bit as usize;
+    ((addr & !0b11) + bit / 32 * 4, (bit % 32) as u32)
+}
+
+// Registers outside the bit-band regions (AHB2, FMC) or bits past their
+// word go through a read-modify-write in a critical section instead, which
+// is as safe from interrupts but slower.
+pub fn read<T>(reg : &T, bit : u8) -> bool {
+    match Bit::of(reg, bit) {
+        Some(b) => b.read(),
+        None => {
+            let (w, b) = word(reg as *const T as usize, bit);
+            unsafe { ptr::read_volatile(w as *const u32) & (1 << b) != 0 }
+        },
+    }
+}
+
+pub fn write<T>(reg : &T, bit : u8, state : bool) {
+    match Bit::of(reg, bit) {
+        Some(b) => b.write(state),
+        None => {
+            let (w, b) = word(reg as *const T as usize, bit);
+            interrupt::free(|_| unsafe {
+                let v = ptr::read_volatile(w as *const u32);
+                let v = if state { v | (1 << b) } else { v & !(1 << b) };
+                ptr::write_volatile(w as *mut u32, v);
+            });
+        },
+    }
+}
@@ -0,0 +1,233 @@
+use system::clk_config::HSI_FREQ;
+
+// Clock tree frequencies computed from a copy of the RCC registers, the
+// equivalent of RCC_GetClocksFreq in the SPL. spl_rs::rcc takes the copy.
+
+// external crystal of the stm32f429i-disco board
+pub const HSE_VALUE : u32 = 8_000_000;
+pub const LSE_VALUE : u32 = 32_768;
+
+// raw copy of the registers needed to compute the clock tree frequencies
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct RccSnapshot {
+    pub cr          : u32,
+    pub pllcfgr     : u32,
+    pub cfgr        : u32,
+    pub dckcfgr     : u32,
+    pub pllsaicfgr  : u32,
+    pub plli2scfgr  : u32,
+}
+
+// every frequency is in Hz, a disabled clock reads as 0
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct ClockFreqs {
+    pub sysclk      : u32,
+    pub hclk        : u32,
+    pub pclk1       : u32,
+    pub pclk2       : u32,
+    pub timclk1     : u32,
+    pub timclk2     : u32,
+    pub pll48clk    : u32,
+    pub lcd_clk     : u32,
+    // main PLL P output, whether or not it drives sysclk
+    pub pll_clk     : u32,
+    // PLLI2S R output
+    pub plli2s_clk  : u32,
+    // PLL input after the M divider, shared by PLL, PLLI2S and PLLSAI
+    pub vco_in      : u32,
+}
+
+pub fn decode_clocks(regs : &RccSnapshot, hse_freq : u32) -> ClockFreqs {
+    let pll_m = regs.pllcfgr & 0x3F;
+    let pll_n = (regs.pllcfgr >> 6) & 0x1FF;
+    let pll_p = (((regs.pllcfgr >> 16) & 0b11) + 1) * 2;
+    let pll_q = (regs.pllcfgr >> 24) & 0xF;
+    let pll_in = if (regs.pllcfgr & (1 << 22)) != 0 { hse_freq } else { HSI_FREQ };
+
+    // M = 0 or 1 is invalid and leaves the PLLs unusable
+    let vco_in = if pll_m < 2 { 0 } else { pll_in / pll_m };
+    let vco = if pll_m < 2 {
+        0
+    } else {
+        (pll_in as u64 * pll_n as u64 / pll_m as u64) as u32
+    };
+
+    let sysclk = match (regs.cfgr >> 2) & 0b11 {
+        0b00 => HSI_FREQ,
+        0b01 => hse_freq,
+        0b10 => vco / pll_p,
+        _ => 0,
+    };
+
+    let hpre = (regs.cfgr >> 4) & 0xF;
+    let hclk = match hpre {
+        0b1000 => sysclk / 2,
+        0b1001 => sysclk / 4,
+        0b1010 => sysclk / 8,
+        0b1011 => sysclk / 16,
+        0b1100 => sysclk / 64,
+        0b1101 => sysclk / 128,
+        0b1110 => sysclk / 256,
+        0b1111 => sysclk / 512,
+        _ => sysclk,
+    };
+
+    let ppre1 = apb_div((regs.cfgr >> 10) & 0b111);
+    let ppre2 = apb_div((regs.cfgr >> 13) & 0b111);
+    let pclk1 = hclk / ppre1;
+    let pclk2 = hclk / ppre2;
+
+    let timpre = (regs.dckcfgr & (1 << 24)) != 0;
+    let timclk1 = tim_clk(hclk, pclk1, ppre1, timpre);
+    let timclk2 = tim_clk(hclk, pclk2, ppre2, timpre);
+
+    let pll_on = (regs.cr & (1 << 24)) != 0;
+    let pll48clk = if pll_on && pll_q >= 2 { vco / pll_q } else { 0 };
+    let pll_clk = if pll_on { vco / pll_p } else { 0 };
+
+    let plli2s_on = (regs.cr & (1 << 26)) != 0;
+    let plli2s_n = (regs.plli2scfgr >> 6) & 0x1FF;
+    let plli2s_r = (regs.plli2scfgr >> 28) & 0b111;
+    let plli2s_clk = if plli2s_on && pll_m >= 2 && plli2s_r >= 2 {
+        (pll_in as u64 * plli2s_n as u64 / (pll_m as u64 * plli2s_r as u64)) as u32
+    } else {
+        0
+    };
+
+    // PLLSAI shares the M divider of the main PLL
+    let pllsai_on = (regs.cr & (1 << 28)) != 0;
+    let pllsai_n = (regs.pllsaicfgr >> 6) & 0x1FF;
+    let pllsai_r = (regs.pllsaicfgr >> 28) & 0b111;
+    let pllsai_divr = 2 << ((regs.dckcfgr >> 16) & 0b11);
+    let lcd_clk = if pllsai_on && pll_m >= 2 && pllsai_r >= 2 {
+        (pll_in as u64 * pllsai_n as u64 /
+            (pll_m as u64 * pllsai_r as u64 * pllsai_divr as u64)) as u32
+    } else {
+        0
+    };
+
+    ClockFreqs {
+        sysclk      : sysclk,
+        hclk        : hclk,
+        pclk1       : pclk1,
+        pclk2       : pclk2,
+        timclk1     : timclk1,
+        timclk2     : timclk2,
+        pll48clk    : pll48clk,
+        lcd_clk     : lcd_clk,
+        pll_clk     : pll_clk,
+        plli2s_clk  : plli2s_clk,
+        vco_in      : vco_in,
+    }
+}
+
+fn apb_div(ppre : u32) -> u32 {
+    match ppre {
+        0b100 => 2,
+        0b101 => 4,
+        0b110 => 8,
+        0b111 => 16,
+        _ => 1,
+    }
+}
+
+fn tim_clk(hclk : u32, pclk : u32, div : u32, timpre : bool) -> u32 {
+    if timpre {
+        if div <= 4 { hclk } else { pclk * 4 }
+    } else {
+        if div == 1 { pclk } else { pclk * 2 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HSION | HSIRDY with the default trimming
+    const CR_RESET : u32 = 0x0000_0083;
+    const CR_HSE_PLL : u32 = CR_RESET | 1 << 16 | 1 << 17 | 1 << 24 | 1 << 25;
+
+    // register values after reset, the PLLs are off
+    const RESET : RccSnapshot = RccSnapshot {
+        cr          : CR_RESET,
+        pllcfgr     : 0x2400_3010,
+        cfgr        : 0,
+        dckcfgr     : 0,
+        pllsaicfgr  : 0x2400_3000,
+        plli2scfgr  : 0x2000_3000,
+    };
+
+    // 8MHz HSE, M = 4, N = 180, P = 2, Q = 8, AHB /1, APB1 /4, APB2 /2,
+    // sysclk switched to the PLL
+    const HSE_180MHZ : RccSnapshot = RccSnapshot {
+        cr          : CR_HSE_PLL,
+        pllcfgr     : 8 << 24 | 1 << 22 | 180 << 6 | 4,
+        cfgr        : 0b100 << 13 | 0b101 << 10 | 0b10 << 2 | 0b10,
+        dckcfgr     : 0,
+        pllsaicfgr  : 0x2400_3000,
+        plli2scfgr  : 0x2000_3000,
+    };
+
+    #[test]
+    fn reset_runs_from_hsi() {
+        let f = decode_clocks(&RESET, HSE_VALUE);
+        assert_eq!(f, ClockFreqs {
+            sysclk      : HSI_FREQ,
+            hclk        : HSI_FREQ,
+            pclk1       : HSI_FREQ,
+            pclk2       : HSI_FREQ,
+            timclk1     : HSI_FREQ,
+            timclk2     : HSI_FREQ,
+            pll48clk    : 0,
+            lcd_clk     : 0,
+            pll_clk     : 0,
+            plli2s_clk  : 0,
+            // HSI divided by M = 16
+            vco_in      : 1_000_000,
+        });
+    }
+
+    #[test]
+    fn hse_pll_180mhz() {
+        let f = decode_clocks(&HSE_180MHZ, HSE_VALUE);
+        assert_eq!(f, ClockFreqs {
+            sysclk      : 180_000_000,
+            hclk        : 180_000_000,
+            pclk1       : 45_000_000,
+            pclk2       : 90_000_000,
+            timclk1     : 90_000_000,
+            timclk2     : 180_000_000,
+            pll48clk    : 45_000_000,
+            lcd_clk     : 0,
+            pll_clk     : 180_000_000,
+            plli2s_clk  : 0,
+            vco_in      : 2_000_000,
+        });
+
+        // TIMPRE runs the timers from hclk while APB is divided by up to 4
+        let regs = RccSnapshot { dckcfgr : 1 << 24, .. HSE_180MHZ };
+        let f = decode_clocks(&regs, HSE_VALUE);
+        assert_eq!((f.timclk1, f.timclk2), (180_000_000, 180_000_000));
+    }
+
+    #[test]
+    fn pllsai_lcd_clock() {
+        // PLLSAI N = 192, Q = 7, R = 4 and PLLSAIDIVR /8
+        let regs = RccSnapshot {
+            cr          : CR_HSE_PLL | 1 << 28 | 1 << 29,
+            dckcfgr     : 0b10 << 16,
+            pllsaicfgr  : 4 << 28 | 7 << 24 | 192 << 6,
+            .. HSE_180MHZ
+        };
+        let f = decode_clocks(&regs, HSE_VALUE);
+        assert_eq!(f.lcd_clk, 12_000_000);
+        // the main PLL is left as is
+        assert_eq!(f.sysclk, 180_000_000);
+
+        // stopped, or with R below 2, PLLSAI gives no pixel clock
+        let off = RccSnapshot { cr : CR_HSE_PLL, .. regs };
+        assert_eq!(decode_clocks(&off, HSE_VALUE).lcd_clk, 0);
+        let bad_r = RccSnapshot { pllsaicfgr : 1 << 28 | 7 << 24 | 192 << 6, .. regs };
+        assert_eq!(decode_clocks(&bad_r, HSE_VALUE).lcd_clk, 0);
+    }
+}
@@ -0,0 +1,198 @@
+use core::fmt;
+use cortex_m::interrupt;
+use spl_rs::rcc;
+
+// Reference counted peripheral clock enables. Each driver acquires the
+// clocks it needs under its own name, a clock is only gated once the last
+// holder released it.
+
+const MAX_HOLDS : usize = 48;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Bus {
+    Ahb1,
+    Ahb2,
+    Ahb3,
+    Apb1,
+    Apb2,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Gate {
+    Ahb1(rcc::Ahb1Enable),
+    Ahb2(rcc::Ahb2Enable),
+    Ahb3(rcc::Ahb3Enable),
+    Apb1(rcc::Apb1Enable),
+    Apb2(rcc::Apb2Enable),
+}
+
+impl Gate {
+    fn split(&self) -> (Bus, u32) {
+        match *self {
+            Gate::Ahb1(f) => (Bus::Ahb1, f.bits()),
+            Gate::Ahb2(f) => (Bus::Ahb2, f.bits()),
+            Gate::Ahb3(f) => (Bus::Ahb3, f.bits()),
+            Gate::Apb1(f) => (Bus::Apb1, f.bits()),
+            Gate::Apb2(f) => (Bus::Apb2, f.bits()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClkGateError {
+    TooManyHolds,
+    NotHeld,
+}
+
+#[derive(Copy, Clone)]
+struct Hold {
+    bus     : Bus,
+    bit     : u8,
+    owner   : &'static str,
+}
+
+static mut HOLDS : [Option<Hold>; MAX_HOLDS] = [None; MAX_HOLDS];
+// clocks enabled for good by keep(), one mask per bus
+static mut KEPT : [u32; 5] = [0; 5];
+
+fn holds() -> &'static mut [Option<Hold>; MAX_HOLDS] {
+    unsafe { &mut HOLDS }
+}
+
+fn count(bus : Bus, bit : u8) -> usize {
+    holds().iter()
+        .filter(|h| match **h {
+            Some(h) => h.bus == bus && h.bit == bit,
+            None => false,
+        })
+        .count()
+}
+
+fn is_kept(bus : Bus, bit : u8) -> bool {
+    unsafe { KEPT[bus as usize] & (1 << bit) != 0 }
+}
+
+fn find(bus : Bus, bit : u8, owner : &'static str) -> Option<usize> {
+    holds().iter().position(|h| match *h {
+        Some(h) => h.bus == bus && h.bit == bit && h.owner == owner,
+        None => false,
+    })
+}
+
+fn set_clk(bus : Bus, bit : u8, en : bool) {
+    let b = 1 << bit;
+    match bus {
+        Bus::Ahb1 => rcc::set_ahb1_periph_clk(rcc::Ahb1Enable::from_bits_truncate(b), en),
+        Bus::Ahb2 => rcc::set_ahb2_periph_clk(rcc::Ahb2Enable::from_bits_truncate(b), en),
+        Bus::Ahb3 => rcc::set_ahb3_periph_clk(rcc::Ahb3Enable::from_bits_truncate(b), en),
+        Bus::Apb1 => rcc::set_apb1_periph_clk(rcc::Apb1Enable::from_bits_truncate(b), en),
+        Bus::Apb2 => rcc::set_apb2_periph_clk(rcc::Apb2Enable::from_bits_truncate(b), en),
+    }
+}
+
+// Enable every clock of `gate` on behalf of `owner`. Acquiring a clock
+// already held by the same owner does nothing. Nothing is held if the
+// table runs out of room midway.
+pub fn acquire(gate : Gate, owner : &'static str) -> Result<(), ClkGateError> {
+    let (bus, bits) = gate.split();
+    interrupt::free(|_| {
+        // clocks held by this call, dropped again on failure
+        let mut new = 0u32;
+        for bit in 0..32 {
+            if bits & (1 << bit) == 0 || find(bus, bit, owner).is_some() {
+                continue;
+            }
+            let slot = match holds().iter().position(|h| h.is_none()) {
+                Some(s) => s,
+                None => {
+                    let _ = drop_holds(bus, new, owner);
+                    return Err(ClkGateError::TooManyHolds);
+                },
+            };
+            if count(bus, bit) == 0 {
+                set_clk(bus, bit, true);
+            }
+            holds()[slot] = Some(Hold { bus : bus, bit : bit, owner : owner });
+            new |= 1 << bit;
+        }
+        Ok(())
+    })
+}
+
+// remove the holds of `owner` on `bits`, Err if one of them was not held
+fn drop_holds(bus : Bus, bits : u32, owner : &'static str) -> Result<(), ClkGateError> {
+    let mut ret = Ok(());
+    for bit in 0..32 {
+        if bits & (1 << bit) == 0 {
+            continue;
+        }
+        match find(bus, bit, owner) {
+            Some(slot) => {
+                holds()[slot] = None;
+                if count(bus, bit) == 0 && !is_kept(bus, bit) {
+                    set_clk(bus, bit, false);
+                }
+            },
+            None => ret = Err(ClkGateError::NotHeld),
+        }
+    }
+    ret
+}
+
+// Drop the clocks of `gate` held by `owner`, gating the ones nobody else
+// holds. Every clock is processed even if one of them was not held.
+pub fn release(gate : Gate, owner : &'static str) -> Result<(), ClkGateError> {
+    let (bus, bits) = gate.split();
+    interrupt::free(|_| drop_holds(bus, bits, owner))
+}
+
+// Enable every clock of `gate` for good, release never gates them again.
+// This takes no slot of the table and can't fail, it is meant for the
+// peripherals given out once and never handed back, like a split GPIO
+// port.
+pub fn keep(gate : Gate) {
+    let (bus, bits) = gate.split();
+    interrupt::free(|_| {
+        for bit in 0..32 {
+            if bits & (1 << bit) != 0 && !is_kept(bus, bit) {
+                set_clk(bus, bit, true);
+            }
+        }
+        unsafe {
+            KEPT[bus as usize] |= bits;
+        }
+    })
+}
+
+// number of owners holding the given clock, a kept clock counts as one.
+// For a gate with several clocks the least held one is reported
+pub fn users(gate : Gate) -> usize {
+    let (bus, bits) = gate.split();
+    interrupt::free(|_| {
+        (0..32)
+            .filter(|bit| bits & (1 << bit) != 0)
+            .map(|bit| count(bus, bit) + is_kept(bus, bit) as usize)
+            .min()
+            .unwrap_or(0)
+    })
+}
+
+// debug helper, calls f(bus, bit, owner) for every held clock
+pub fn for_each_hold<F : FnMut(Bus, u8, &'static str)>(mut f : F) {
+    let snapshot = interrupt::free(|_| *holds());
+    for h in snapshot.iter() {
+        if let Some(h) = *h {
+            f(h.bus, h.bit, h.owner);
+        }
+    }
+}
+
+pub fn dump<W : fmt::Write>(w : &mut W) -> fmt::Result {
+    let mut ret = Ok(());
+    for_each_hold(|bus, bit, owner| {
+        if ret.is_ok() {
+            ret = writeln!(w, "{:?} bit {} : {}", bus, bit, owner);
+        }
+    });
+    ret
+}
@@ -0,0 +1,101 @@
+use cortex_m::peripheral::{DCB, DWT};
+use hal::blocking::delay::{DelayMs, DelayUs};
+
+use spl_rs::rcc;
+
+// Busy waits counted on the DWT cycle counter, so they don't depend on the
+// optimisation level or on flash wait states. The counter wraps after 2^32
+// cycles, about 25s at 168MHz, longer waits are split.
+
+const TRCENA : u32 = 1 << 24;
+const CYCCNTENA : u32 = 1 << 0;
+
+// start the cycle counter, harmless if a debugger already did
+pub fn enable_cycle_counter() {
+    unsafe {
+        (*DCB::ptr()).demcr.modify(|r| r | TRCENA);
+        (*DWT::ptr()).cyccnt.write(0);
+        (*DWT::ptr()).ctrl.modify(|r| r | CYCCNTENA);
+    }
+}
+
+pub fn cycle_count() -> u32 {
+    unsafe { (*DWT::ptr()).cyccnt.read() }
+}
+
+pub fn delay_cycles(n : u32) {
+    let start = cycle_count();
+    while cycle_count().wrapping_sub(start) < n {}
+}
+
+#[derive(Copy, Clone)]
+pub struct CycleDelay {
+    hclk : u32,
+}
+
+impl CycleDelay {
+    // hclk is sampled here, take a new one after a clock profile change
+    pub fn new() -> CycleDelay {
+        enable_cycle_counter();
+        CycleDelay {
+            hclk : rcc::get_clocks_freq().hclk,
+        }
+    }
+
+    pub fn hclk(&self) -> u32 {
+        self.hclk
+    }
+
+    // cycles in `ns` nanoseconds, rounded up so a wait is never short
+    pub fn ns_to_cycles(&self, ns : u32) -> u32 {
+        ((self.hclk as u64 * ns as u64 + 999_999_999) / 1_000_000_000) as u32
+    }
+
+    pub fn delay_ns(&self, ns : u32) {
+        delay_cycles(self.ns_to_cycles(ns));
+    }
+}
+
+impl DelayUs<u32> for CycleDelay {
+    fn delay_us(&mut self, us : u32) {
+        let per_us = self.hclk / 1_000_000;
+        let mut left = us as u64 * per_us as u64;
+        while left > 0 {
+            let n = if left > 0x8000_0000 { 0x8000_0000 } else { left as u32 };
+            delay_cycles(n);
+            left -= n as u64;
+        }
+    }
+}
+
+impl DelayUs<u16> for CycleDelay {
+    fn delay_us(&mut self, us : u16) {
+        self.delay_us(us as u32);
+    }
+}
+
+impl DelayUs<u8> for CycleDelay {
+    fn delay_us(&mut self, us : u8) {
+        self.delay_us(us as u32);
+    }
+}
+
+impl DelayMs<u32> for CycleDelay {
+    fn delay_ms(&mut self, ms : u32) {
+        for _ in 0..ms {
+            self.delay_us(1000u32);
+        }
+    }
+}
+
+impl DelayMs<u16> for CycleDelay {
+    fn delay_ms(&mut self, ms : u16) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl DelayMs<u8> for CycleDelay {
+    fn delay_ms(&mut self, ms : u8) {
+        self.delay_ms(ms as u32);
+    }
+}
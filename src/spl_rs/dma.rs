@@ -0,0 +1,245 @@
+use core::ptr;
+use stm32f429::DMA2;
+use stm32f429::interrupt::Interrupt;
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use bare_metal::Nr;
+
+use spl_rs::{rcc, clk_gate};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+
+// Streams of DMA2, used one transfer at a time. The stream registers are
+// reached by offset, they are identical for the 8 streams. Only the
+// interrupts of the streams used by the drivers are declared below.
+
+const CLK_OWNER : &'static str = "dma";
+const NB_STREAMS : usize = 8;
+// a stream ends its current beat before EN reads 0, this is plenty
+const STOP_TIMEOUT : u32 = 0x1000;
+
+// stream flags, as found for stream 0 in LISR
+pub const FEIF  : u8 = 1 << 0;
+pub const DMEIF : u8 = 1 << 2;
+pub const TEIF  : u8 = 1 << 3;
+pub const HTIF  : u8 = 1 << 4;
+pub const TCIF  : u8 = 1 << 5;
+const ALL_FLAGS : u8 = FEIF | DMEIF | TEIF | HTIF | TCIF;
+
+// SxCR bits
+const EN    : u32 = 1 << 0;
+const TEIE  : u32 = 1 << 2;
+const TCIE  : u32 = 1 << 4;
+const MINC  : u32 = 1 << 10;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Direction {
+    PeriphToMem = 0b00,
+    MemToPeriph = 0b01,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Width {
+    Byte        = 0b00,
+    HalfWord    = 0b01,
+    Word        = 0b10,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DmaError {
+    StreamOutOfRange(u8),
+    ChannelOutOfRange(u8),
+    Busy(u8),
+    // a handler was given for a stream without interrupt vector
+    NoInterrupt(u8),
+    // EN still reads 1 after the stream was disabled
+    Timeout(u8),
+    Clock(ClkGateError),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StreamConfig {
+    pub channel     : u8,
+    pub dir         : Direction,
+    pub periph_addr : u32,
+    pub mem_addr    : u32,
+    pub len         : u16,
+    // step through memory, otherwise the same location is used each time
+    pub mem_inc     : bool,
+    // same width on both sides, no packing
+    pub width       : Width,
+    // called from the stream interrupt once the transfer ends
+    pub handler     : Option<fn()>,
+}
+
+static mut HANDLERS : [Option<fn()>; NB_STREAMS] = [None; NB_STREAMS];
+// flags seen by the interrupt, kept until taken
+static mut STATUS : [u8; NB_STREAMS] = [0; NB_STREAMS];
+
+fn reg(stream : u8, offset : usize) -> *mut u32 {
+    (DMA2::ptr() as usize + 0x10 + 0x18 * stream as usize + offset) as *mut u32
+}
+
+fn cr(stream : u8) -> *mut u32 { reg(stream, 0x00) }
+fn ndtr(stream : u8) -> *mut u32 { reg(stream, 0x04) }
+fn par(stream : u8) -> *mut u32 { reg(stream, 0x08) }
+fn m0ar(stream : u8) -> *mut u32 { reg(stream, 0x0C) }
+fn fcr(stream : u8) -> *mut u32 { reg(stream, 0x14) }
+
+// status register, its clear register and the flag shift of a stream
+fn isr(stream : u8) -> (*const u32, *mut u32, u8) {
+    let base = DMA2::ptr() as usize;
+    let shift = [0, 6, 16, 22][(stream % 4) as usize];
+    if stream < 4 {
+        (base as *const u32, (base + 0x08) as *mut u32, shift)
+    } else {
+        ((base + 0x04) as *const u32, (base + 0x0C) as *mut u32, shift)
+    }
+}
+
+// streams whose interrupt is declared at the end of the file
+fn served(stream : u8) -> bool {
+    stream == 3 || stream == 4
+}
+
+fn vector(stream : u8) -> Interrupt {
+    match stream {
+        0 => Interrupt::DMA2_STREAM0,
+        1 => Interrupt::DMA2_STREAM1,
+        2 => Interrupt::DMA2_STREAM2,
+        3 => Interrupt::DMA2_STREAM3,
+        4 => Interrupt::DMA2_STREAM4,
+        5 => Interrupt::DMA2_STREAM5,
+        6 => Interrupt::DMA2_STREAM6,
+        _ => Interrupt::DMA2_STREAM7,
+    }
+}
+
+fn set_nvic(stream : u8, en : bool) {
+    let nvic = unsafe{&*NVIC::ptr()};
+    let nr = vector(stream).nr();
+    unsafe {
+        if en {
+            nvic.iser[usize::from(nr / 32)].write(1 << (nr % 32));
+        } else {
+            nvic.icer[usize::from(nr / 32)].write(1 << (nr % 32));
+        }
+    }
+}
+
+pub fn flags(stream : u8) -> u8 {
+    let (isr, _, shift) = isr(stream);
+    ((unsafe { ptr::read_volatile(isr) } >> shift) as u8) & ALL_FLAGS
+}
+
+pub fn clear_flags(stream : u8) {
+    let (_, ifcr, shift) = isr(stream);
+    unsafe { ptr::write_volatile(ifcr, (ALL_FLAGS as u32) << shift) };
+}
+
+// flags raised since the transfer started, whether or not the interrupt
+// already cleared them
+pub fn status(stream : u8) -> u8 {
+    interrupt::free(|_| unsafe { STATUS[stream as usize] } | flags(stream))
+}
+
+pub fn is_enabled(stream : u8) -> bool {
+    unsafe { ptr::read_volatile(cr(stream)) & EN != 0 }
+}
+
+// items left to transfer
+pub fn remaining(stream : u8) -> u16 {
+    unsafe { ptr::read_volatile(ndtr(stream)) as u16 }
+}
+
+pub fn start(stream : u8, cfg : &StreamConfig) -> Result<(), DmaError> {
+    if stream as usize >= NB_STREAMS {
+        return Err(DmaError::StreamOutOfRange(stream));
+    }
+    if cfg.channel > 7 {
+        return Err(DmaError::ChannelOutOfRange(cfg.channel));
+    }
+    if is_enabled(stream) {
+        return Err(DmaError::Busy(stream));
+    }
+    if cfg.handler.is_some() && !served(stream) {
+        return Err(DmaError::NoInterrupt(stream));
+    }
+    clk_gate::acquire(Gate::Ahb1(rcc::Ahb1Enable::DMA2), CLK_OWNER).map_err(DmaError::Clock)?;
+
+    let w = cfg.width as u32;
+    let ccr = (cfg.channel as u32) << 25
+        | w << 13                       // MSIZE
+        | w << 11                       // PSIZE
+        | if cfg.mem_inc { MINC } else { 0 }
+        | (cfg.dir as u32) << 6
+        | if served(stream) { TEIE } else { 0 }
+        | if cfg.handler.is_some() { TCIE } else { 0 };
+
+    interrupt::free(|_| unsafe {
+        HANDLERS[stream as usize] = cfg.handler;
+        STATUS[stream as usize] = 0;
+    });
+    clear_flags(stream);
+    unsafe {
+        ptr::write_volatile(cr(stream), ccr);
+        ptr::write_volatile(par(stream), cfg.periph_addr);
+        ptr::write_volatile(m0ar(stream), cfg.mem_addr);
+        ptr::write_volatile(ndtr(stream), cfg.len as u32);
+        // direct mode
+        ptr::write_volatile(fcr(stream), 0);
+        if served(stream) {
+            set_nvic(stream, true);
+        }
+        ptr::write_volatile(cr(stream), ccr | EN);
+    }
+    Ok(())
+}
+
+// abort the transfer if still running, the stream is free once EN reads 0
+pub fn stop(stream : u8) -> Result<(), DmaError> {
+    unsafe {
+        let c = ptr::read_volatile(cr(stream));
+        ptr::write_volatile(cr(stream), c & !(EN | TCIE | TEIE));
+    }
+    let mut t = STOP_TIMEOUT;
+    let mut ret = Ok(());
+    while is_enabled(stream) {
+        if t == 0 {
+            ret = Err(DmaError::Timeout(stream));
+            break;
+        }
+        t -= 1;
+    }
+    if served(stream) {
+        set_nvic(stream, false);
+    }
+    clear_flags(stream);
+    ret
+}
+
+// the DMA2 clock is dropped when no stream is left running
+pub fn release() {
+    if (0..NB_STREAMS as u8).all(|s| !is_enabled(s)) {
+        let _ = clk_gate::release(Gate::Ahb1(rcc::Ahb1Enable::DMA2), CLK_OWNER);
+    }
+}
+
+fn serve(stream : u8) {
+    let f = flags(stream);
+    clear_flags(stream);
+    unsafe {
+        STATUS[stream as usize] |= f;
+    }
+    if f & (TCIF | TEIF) != 0 {
+        if let Some(h) = unsafe { HANDLERS[stream as usize] } {
+            h();
+        }
+    }
+}
+
+// SPI5 RX and TX
+fn dma2_stream3() { serve(3); }
+fn dma2_stream4() { serve(4); }
+
+interrupt!(DMA2_STREAM3, dma2_stream3);
+interrupt!(DMA2_STREAM4, dma2_stream4);
@@ -0,0 +1,210 @@
+use stm32f429::{EXTI, SYSCFG};
+use stm32f429::interrupt::Interrupt;
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use bare_metal::Nr;
+
+use spl_rs::{gpio, rcc, clk_gate};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+
+// External interrupts on GPIO edges. Line n can be routed from pin n of a
+// single port at a time, the SYSCFG clock is held as long as any line is in
+// use. Handlers run in interrupt context, the pending bit is already
+// cleared when they are called.
+
+const NB_LINES  : usize = 16;
+const CLK_OWNER : &'static str = "exti";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ExtiError {
+    PinOutOfRange(u8),
+    // the line is already routed from this port
+    LineInUse(gpio::Port),
+    Gate(ClkGateError),
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    port    : gpio::Port,
+    handler : fn(),
+}
+
+static mut LINES : [Option<Line>; NB_LINES] = [None; NB_LINES];
+
+fn lines() -> &'static mut [Option<Line>; NB_LINES] {
+    unsafe { &mut LINES }
+}
+
+// vector serving a line, lines 5 to 9 and 10 to 15 share theirs
+fn vector(pin : u8) -> Interrupt {
+    match pin {
+        0 => Interrupt::EXTI0,
+        1 => Interrupt::EXTI1,
+        2 => Interrupt::EXTI2,
+        3 => Interrupt::EXTI3,
+        4 => Interrupt::EXTI4,
+        5...9 => Interrupt::EXTI9_5,
+        _ => Interrupt::EXTI15_10,
+    }
+}
+
+fn set_nvic(pin : u8, en : bool) {
+    let nvic = unsafe{&*NVIC::ptr()};
+    let nr = vector(pin).nr();
+    unsafe {
+        if en {
+            nvic.iser[usize::from(nr / 32)].write(1 << (nr % 32));
+        } else {
+            nvic.icer[usize::from(nr / 32)].write(1 << (nr % 32));
+        }
+    }
+}
+
+fn route(port : gpio::Port, pin : u8) {
+    let sc = unsafe{&*SYSCFG::ptr()};
+    let shift = (pin % 4) * 4;
+    let f = |r : u32| (r & !(0b1111 << shift)) | ((port as u32) << shift);
+    match pin / 4 {
+        0 => sc.exticr1.modify(|r, w| unsafe{w.bits(f(r.bits()))}),
+        1 => sc.exticr2.modify(|r, w| unsafe{w.bits(f(r.bits()))}),
+        2 => sc.exticr3.modify(|r, w| unsafe{w.bits(f(r.bits()))}),
+        _ => sc.exticr4.modify(|r, w| unsafe{w.bits(f(r.bits()))}),
+    };
+}
+
+// Call `handler` on every `edge` of `pin` of `port`. Listening again on the
+// same pin replaces the edge and the handler.
+fn listen(port : gpio::Port, pin : u8, edge : Edge, handler : fn()) -> Result<(), ExtiError> {
+    if pin > 15 {
+        return Err(ExtiError::PinOutOfRange(pin));
+    }
+    let exti = unsafe{&*EXTI::ptr()};
+    let bit = 1 << pin;
+
+    interrupt::free(|_| {
+        match lines()[pin as usize] {
+            Some(l) if l.port != port => return Err(ExtiError::LineInUse(l.port)),
+            Some(_) => (),
+            None => {
+                clk_gate::acquire(Gate::Apb2(rcc::Apb2Enable::SYS_CFG), CLK_OWNER)
+                    .map_err(ExtiError::Gate)?;
+            },
+        };
+        lines()[pin as usize] = Some(Line { port : port, handler : handler });
+
+        // mask the line while it is reconfigured
+        exti.imr.modify(|r, w| unsafe{w.bits(r.bits() & !bit)});
+        route(port, pin);
+
+        let (rising, falling) = match edge {
+            Edge::Rising => (true, false),
+            Edge::Falling => (false, true),
+            Edge::Both => (true, true),
+        };
+        exti.rtsr.modify(|r, w| unsafe {
+            w.bits(if rising { r.bits() | bit } else { r.bits() & !bit })
+        });
+        exti.ftsr.modify(|r, w| unsafe {
+            w.bits(if falling { r.bits() | bit } else { r.bits() & !bit })
+        });
+
+        // drop an edge seen before the handler was in place
+        exti.pr.write(|w| unsafe{w.bits(bit)});
+        exti.imr.modify(|r, w| unsafe{w.bits(r.bits() | bit)});
+        set_nvic(pin, true);
+        Ok(())
+    })
+}
+
+// the pin has to be an input, which its type proves
+pub fn listen_pin<P, N, PULL>(_pin : &gpio::Pin<P, N, gpio::Input<PULL>>, edge : Edge, handler : fn())
+    -> Result<(), ExtiError>
+    where P : gpio::PortId, N : gpio::PinId {
+    listen(P::PORT, N::PIN, edge, handler)
+}
+
+// stop listening on `pin`, whatever port it is routed from
+pub fn unlisten(pin : u8) -> Result<(), ExtiError> {
+    if pin > 15 {
+        return Err(ExtiError::PinOutOfRange(pin));
+    }
+    let exti = unsafe{&*EXTI::ptr()};
+    let bit = 1 << pin;
+
+    interrupt::free(|_| {
+        if lines()[pin as usize].is_none() {
+            return;
+        }
+        exti.imr.modify(|r, w| unsafe{w.bits(r.bits() & !bit)});
+        exti.rtsr.modify(|r, w| unsafe{w.bits(r.bits() & !bit)});
+        exti.ftsr.modify(|r, w| unsafe{w.bits(r.bits() & !bit)});
+        exti.pr.write(|w| unsafe{w.bits(bit)});
+        lines()[pin as usize] = None;
+
+        // shared vectors stay enabled while another of their lines is used
+        let (first, last) = match pin {
+            0...4 => (pin, pin),
+            5...9 => (5, 9),
+            _ => (10, 15),
+        };
+        if (first..(last + 1)).all(|l| lines()[l as usize].is_none()) {
+            set_nvic(pin, false);
+        }
+
+        // a single hold covers every line
+        if lines().iter().all(|l| l.is_none()) {
+            let _ = clk_gate::release(Gate::Apb2(rcc::Apb2Enable::SYS_CFG), CLK_OWNER);
+        }
+    });
+    Ok(())
+}
+
+// raise the line by software, as if the edge had happened
+pub fn trigger(pin : u8) -> Result<(), ExtiError> {
+    if pin > 15 {
+        return Err(ExtiError::PinOutOfRange(pin));
+    }
+    let exti = unsafe{&*EXTI::ptr()};
+    exti.swier.write(|w| unsafe{w.bits(1 << pin)});
+    Ok(())
+}
+
+// clear then serve every pending line between first and last
+fn dispatch(first : u8, last : u8) {
+    let exti = unsafe{&*EXTI::ptr()};
+    let mask = ((1u32 << (last + 1)) - 1) & !((1u32 << first) - 1);
+    let pending = exti.pr.read().bits() & mask;
+    exti.pr.write(|w| unsafe{w.bits(pending)});
+
+    for l in first..(last + 1) {
+        if pending & (1 << l) == 0 {
+            continue;
+        }
+        if let Some(line) = lines()[l as usize] {
+            (line.handler)();
+        }
+    }
+}
+
+fn exti0() { dispatch(0, 0); }
+fn exti1() { dispatch(1, 1); }
+fn exti2() { dispatch(2, 2); }
+fn exti3() { dispatch(3, 3); }
+fn exti4() { dispatch(4, 4); }
+fn exti9_5() { dispatch(5, 9); }
+fn exti15_10() { dispatch(10, 15); }
+
+interrupt!(EXTI0, exti0);
+interrupt!(EXTI1, exti1);
+interrupt!(EXTI2, exti2);
+interrupt!(EXTI3, exti3);
+interrupt!(EXTI4, exti4);
+interrupt!(EXTI9_5, exti9_5);
+interrupt!(EXTI15_10, exti15_10);
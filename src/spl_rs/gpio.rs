@@ -2,94 +2,65 @@ use stm32f429::{
     GPIOA, GPIOB, GPIOC, GPIOD, GPIOE, GPIOF, GPIOG, GPIOH, GPIOI, GPIOJ, GPIOK,
 };
 
-use stm32f429::{gpioa, gpiob, gpiok};
-use core::ops::Deref;
+use core::marker::PhantomData;
+use cortex_m::interrupt;
+use hal::digital;
+use spl_rs::{rcc, clk_gate};
+use spl_rs::clk_gate::Gate;
+pub use spl_rs::pins::{Mode, OutType, OutSpeed, PullType, AltFn, Port, PinConfig, find_overlap};
 
-pub enum Mode {
-    Input   = 0b00,
-    Output  = 0b01,
-    AltFn   = 0b10,
-    Analog  = 0b11,
-}
-
-pub enum OutType {
-    PushPull = 0,
-    OpenDrain = 1,
-}
-
-pub enum OutSpeed {
-    Low = 0b00,
-    Medium = 0b01,
-    High = 0b10,
-    VeryHigh = 0b11,
-}
-
-pub enum PullType {
-    NoPull = 0b00,
-    PullUp = 0b01,
-    PullDown = 0b10,
-}
-
-pub enum AltFn {
-    Sys               = 0,
-    Tim12             = 1,
-    Tim345            = 2,
-    Tim8910           = 3,
-    I2c123            = 4,
-    Spi123456         = 5,
-    Spi23Sai1         = 6,
-    Spi3Usart123      = 7,
-    Usart6Uart4578    = 8,
-    Can12Tim121314Lcd = 9,
-    Otg2HsOtg1Fs      = 10,
-    Eth               = 11,
-    FmcSdioOtg2Fs     = 12,
-    Dcmi              = 13,
-    Lcd               = 14,
-    SysEvent          = 15,
-}
-
-pub trait IsPortA : Deref<Target = gpioa::RegisterBlock> {
-
-}
-
-pub trait IsPortB : Deref<Target = gpiob::RegisterBlock> {
+/////////////////////////////////////////////////////////////////////////////////
+// Runtime port access
+/////////////////////////////////////////////////////////////////////////////////
 
+// All the GPIO ports share the same register layout, only their reset values
+// differ. They are all accessed through the GPIOK register block. Single pins
+// are only reached through the typed pins below or the pin tables, drivers
+// don't get to configure a pin they don't own.
+trait PortRegs {
+    fn regs(&self) -> &'static ::stm32f429::gpiok::RegisterBlock;
+    fn clk(&self) -> rcc::Ahb1Enable;
 }
 
-pub trait IsPortK : Deref<Target = gpiok::RegisterBlock> {
+impl PortRegs for Port {
+    fn regs(&self) -> &'static ::stm32f429::gpiok::RegisterBlock {
+        let ptr = match *self {
+            Port::A => GPIOA::ptr() as *const ::stm32f429::gpiok::RegisterBlock,
+            Port::B => GPIOB::ptr() as *const ::stm32f429::gpiok::RegisterBlock,
+            Port::C => GPIOC::ptr(),
+            Port::D => GPIOD::ptr(),
+            Port::E => GPIOE::ptr(),
+            Port::F => GPIOF::ptr(),
+            Port::G => GPIOG::ptr(),
+            Port::H => GPIOH::ptr(),
+            Port::I => GPIOI::ptr(),
+            Port::J => GPIOJ::ptr(),
+            Port::K => GPIOK::ptr(),
+        };
+        unsafe { &*ptr }
+    }
 
+    fn clk(&self) -> rcc::Ahb1Enable {
+        match *self {
+            Port::A => rcc::Ahb1Enable::GPIOA,
+            Port::B => rcc::Ahb1Enable::GPIOB,
+            Port::C => rcc::Ahb1Enable::GPIOC,
+            Port::D => rcc::Ahb1Enable::GPIOD,
+            Port::E => rcc::Ahb1Enable::GPIOE,
+            Port::F => rcc::Ahb1Enable::GPIOF,
+            Port::G => rcc::Ahb1Enable::GPIOG,
+            Port::H => rcc::Ahb1Enable::GPIOH,
+            Port::I => rcc::Ahb1Enable::GPIOI,
+            Port::J => rcc::Ahb1Enable::GPIOJ,
+            Port::K => rcc::Ahb1Enable::GPIOK,
+        }
+    }
 }
 
-impl IsPortA for GPIOA {}
-impl IsPortB for GPIOB {}
-impl IsPortK for GPIOC {}
-impl IsPortK for GPIOD {}
-impl IsPortK for GPIOE {}
-impl IsPortK for GPIOF {}
-impl IsPortK for GPIOG {}
-impl IsPortK for GPIOH {}
-impl IsPortK for GPIOI {}
-impl IsPortK for GPIOJ {}
-impl IsPortK for GPIOK {}
-
-
-/////////////////////////////////////////////////////////////////////////////////
-// GPIOA Implementation
-/////////////////////////////////////////////////////////////////////////////////
-pub mod gpio_a {
-    use spl_rs::gpio::*;
-    pub fn configure<T : IsPortA>(gpio_port : &T,
-        pin : u8,
-        m : Mode,
-        ot : OutType,
-        os : OutSpeed,
-        pt : PullType
-    ) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port  = gpio_port;
-
-        // configure mode
+fn set_mode(port : Port, pin : u8, m : Mode) -> Result<(), ()> {
+    if pin > 15 {return Err(());}
+    let port = port.regs();
+    interrupt::free(|_| {
         port.moder.modify(|r, w| unsafe {
             // clear targeted bits
             let o = r.bits() & !(0b11 << (pin * 2));
@@ -97,265 +68,899 @@ pub mod gpio_a {
             let n = o | ((m as u32) << (pin * 2));
             w.bits(n)
         });
+    });
+    Ok(())
+}
 
-        // configure output type. if input or analog, don't care
+// if input or analog, don't care
+fn set_out_type(port : Port, pin : u8, ot : OutType) -> Result<(), ()> {
+    if pin > 15 {return Err(());}
+    let port = port.regs();
+    interrupt::free(|_| {
         port.otyper.modify(|r, w| unsafe {
             let o = r.bits() & !(1 << pin);
             let n = o | ((ot as u32) << pin);
             w.bits(n)
         });
+    });
+    Ok(())
+}
 
-        // configure output speed
+fn set_speed(port : Port, pin : u8, os : OutSpeed) -> Result<(), ()> {
+    if pin > 15 {return Err(());}
+    let port = port.regs();
+    interrupt::free(|_| {
         port.ospeedr.modify(|r, w| unsafe {
-            // clear targeted bits
             let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
             let n = o | ((os as u32) << (pin * 2));
             w.bits(n)
         });
+    });
+    Ok(())
+}
 
-        // configure pull type
+fn set_pull(port : Port, pin : u8, pt : PullType) -> Result<(), ()> {
+    if pin > 15 {return Err(());}
+    let port = port.regs();
+    interrupt::free(|_| {
         port.pupdr.modify(|r, w| unsafe {
-            // clear targeted bits
             let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
             let n = o | ((pt as u32) << (pin * 2));
             w.bits(n)
         });
+    });
+    Ok(())
+}
 
-        Ok(())
-    }
+fn configure(port : Port,
+    pin : u8,
+    m : Mode,
+    ot : OutType,
+    os : OutSpeed,
+    pt : PullType
+) -> Result<(), ()> {
+    set_mode(port, pin, m)?;
+    set_out_type(port, pin, ot)?;
+    set_speed(port, pin, os)?;
+    set_pull(port, pin, pt)
+}
+
+fn set_alt_fn(port : Port, pin : u8, af : AltFn) -> Result<(), ()> {
+    set_alt_fn_bits(port, pin, af as u8)
+}
 
-    pub fn set_alt_fn<T : IsPortA>(gpio_port : &T, pin : u8, af : AltFn) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
+fn set_alt_fn_bits(port : Port, pin : u8, af : u8) -> Result<(), ()> {
+    if pin > 15 || af > 15 {return Err(());}
+    let port = port.regs();
+    interrupt::free(|_| {
         if pin > 7 {
             port.afrh.modify(|r, w| unsafe {
-                // clear targeted bits
                 let o = r.bits() & !(0b1111 << ((pin - 8) * 4));
-                // print pattern on new bitfield
                 let n = o | ((af as u32) << ((pin - 8) * 4));
                 w.bits(n)
             });
         } else {
             port.afrl.modify(|r, w| unsafe {
-                // clear targeted bits
                 let o = r.bits() & !(0b1111 << (pin * 4));
-                // print pattern on new bitfield
                 let n = o | ((af as u32) << (pin * 4));
                 w.bits(n)
             });
         }
-        Ok(())
+    });
+    Ok(())
+}
+
+// level on the pin, whatever its mode
+pub(crate) fn read(port : Port, pin : u8) -> Result<bool, ()> {
+    if pin > 15 {return Err(());}
+    Ok((port.regs().idr.read().bits() & (1 << pin)) != 0)
+}
+
+// level the pin is driven to when in output mode
+fn read_output(port : Port, pin : u8) -> Result<bool, ()> {
+    if pin > 15 {return Err(());}
+    Ok((port.regs().odr.read().bits() & (1 << pin)) != 0)
+}
+
+fn write(port : Port, pin : u8, state : bool) -> Result<(), ()> {
+    if pin > 15 {return Err(());}
+    // bsrr is write only, no read-modify-write race
+    port.regs().bsrr.write(|w| unsafe {
+        if state {
+            w.bits(1 << pin)
+        } else {
+            w.bits(1 << (pin + 16))
+        }
+    });
+    Ok(())
+}
+
+// Drive several pins of a port in a single bsrr write, so they all change
+// at the same time. A pin in both masks ends up set.
+fn write_mask(port : Port, set : u16, reset : u16) {
+    port.regs().bsrr.write(|w| unsafe {
+        w.bits(((reset as u32) << 16) | set as u32)
+    });
+}
+
+// levels of all the pins of a port
+fn read_port(port : Port) -> u16 {
+    port.regs().idr.read().bits() as u16
+}
+
+fn read_port_output(port : Port) -> u16 {
+    port.regs().odr.read().bits() as u16
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+// Parallel bus
+/////////////////////////////////////////////////////////////////////////////////
+
+// 8 bit bus on the pins of a single port, bit n of the data is on pins[n].
+// Writes go through write_mask so the whole byte changes at once. The pins
+// are owned by the bus and keep the output type and speed they were
+// configured with.
+pub struct ParallelBus {
+    port        : Port,
+    pins        : [u8; 8],
+    mask        : u16,
+    // data bit 0 position when the pins follow each other
+    shift       : Option<u8>,
+}
+
+impl ParallelBus {
+    // the pins are handed back if they are not all on the same port
+    pub fn new<MODE>(pins : [ErasedPin<MODE>; 8]) -> Result<ParallelBus, [ErasedPin<MODE>; 8]> {
+        let port = pins[0].port;
+        if pins.iter().any(|p| p.port != port) {
+            return Err(pins);
+        }
+        let mut numbers = [0u8; 8];
+        let mut mask = 0u16;
+        for (i, p) in pins.iter().enumerate() {
+            numbers[i] = p.pin;
+            mask |= 1 << p.pin;
+        }
+        let contiguous = numbers.iter().enumerate().all(|(i, p)| *p == numbers[0] + i as u8);
+        Ok(ParallelBus {
+            port    : port,
+            pins    : numbers,
+            mask    : mask,
+            shift   : if contiguous { Some(numbers[0]) } else { None },
+        })
     }
 
-    pub fn read<T : IsPortA>(gpio_port : &T, pin : u8) -> Result<bool, ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        Ok((port.idr.read().bits() | (1 << pin)) != 0)
+    // the pins are given back as floating inputs, in data bit order
+    pub fn free(mut self) -> [ErasedPin<Input<Floating>>; 8] {
+        self.set_input(PullType::NoPull);
+        let pin = |i : usize| ErasedPin {
+            port    : self.port,
+            pin     : self.pins[i],
+            _mode   : PhantomData,
+        };
+        [pin(0), pin(1), pin(2), pin(3), pin(4), pin(5), pin(6), pin(7)]
     }
 
-    pub fn write<T : IsPortA>(gpio_port : &T, pin : u8, state : bool) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        port.bsrr.write(|w| unsafe {
-            if state {
-                w.bits(1 << pin)
-            } else {
-                w.bits(1 << (pin + 16))
-            }
-        });
-        Ok(())
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    pub fn set_output(&mut self) {
+        for p in self.pins.iter() {
+            set_mode(self.port, *p, Mode::Output).unwrap();
+        }
+    }
+
+    pub fn set_input(&mut self, pt : PullType) {
+        for p in self.pins.iter() {
+            set_pull(self.port, *p, pt).unwrap();
+            set_mode(self.port, *p, Mode::Input).unwrap();
+        }
+    }
+
+    fn spread(&self, data : u8) -> u16 {
+        match self.shift {
+            Some(s) => (data as u16) << s,
+            None => {
+                let mut bits = 0u16;
+                for (i, p) in self.pins.iter().enumerate() {
+                    if data & (1 << i) != 0 {
+                        bits |= 1 << *p;
+                    }
+                }
+                bits
+            },
+        }
+    }
+
+    fn gather(&self, bits : u16) -> u8 {
+        match self.shift {
+            Some(s) => (bits >> s) as u8,
+            None => {
+                let mut data = 0u8;
+                for (i, p) in self.pins.iter().enumerate() {
+                    if bits & (1 << *p) != 0 {
+                        data |= 1 << i;
+                    }
+                }
+                data
+            },
+        }
+    }
+
+    pub fn write(&mut self, data : u8) {
+        let set = self.spread(data);
+        write_mask(self.port, set, self.mask & !set);
+    }
+
+    pub fn read(&self) -> u8 {
+        self.gather(read_port(self.port))
+    }
+
+    // last value written, whatever the direction
+    pub fn read_output(&self) -> u8 {
+        self.gather(read_port_output(self.port))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+// Pin tables
+/////////////////////////////////////////////////////////////////////////////////
+
+// Configure every pin of a group. The port clocks have to be running.
+pub fn apply(pins : &[PinConfig]) -> Result<(), ()> {
+    for p in pins.iter() {
+        // select the function before the pin is switched to it
+        if p.mode == Mode::AltFn {
+            set_alt_fn(p.port, p.pin, p.af)?;
+        }
+        configure(p.port, p.pin, p.mode, p.otype, p.speed, p.pull)?;
     }
+    Ok(())
 }
 
+// Put every pin of a group back to a floating input on AF0
+pub fn release(pins : &[PinConfig]) -> Result<(), ()> {
+    for p in pins.iter() {
+        configure(p.port, p.pin, Mode::Input, OutType::PushPull, OutSpeed::Low,
+                  PullType::NoPull)?;
+        set_alt_fn(p.port, p.pin, AltFn::Sys)?;
+    }
+    Ok(())
+}
+
+// AHB1 enable bits of the ports used by a group
+pub fn port_clks(pins : &[PinConfig]) -> rcc::Ahb1Enable {
+    pins.iter().fold(rcc::Ahb1Enable::empty(), |acc, p| acc | p.port.clk())
+}
 
 /////////////////////////////////////////////////////////////////////////////////
-// GPIOB Implementation
+// Configuration lock
 /////////////////////////////////////////////////////////////////////////////////
-pub mod gpio_b {
-    use spl_rs::gpio::*;
-    pub fn configure<T : IsPortB>(gpio_port : &T,
-        pin : u8,
-        m : Mode,
-        ot : OutType,
-        os : OutSpeed,
-        pt : PullType
-    ) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port  = gpio_port;
-
-        // configure mode
-        port.moder.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((m as u32) << (pin * 2));
-            w.bits(n)
-        });
 
-        // configure output type. if input or analog, don't care
-        port.otyper.modify(|r, w| unsafe {
-            let o = r.bits() & !(1 << pin);
-            let n = o | ((ot as u32) << pin);
-            w.bits(n)
-        });
+// LCKR freezes the mode, type, speed, pull and function of the selected pins
+// until the next reset. The key sequence can only be run once per port, all
+// the pins of a port have to be locked at the same time.
 
-        // configure output speed
-        port.ospeedr.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((os as u32) << (pin * 2));
-            w.bits(n)
-        });
+const LCKK : u32 = 1 << 16;
 
-        // configure pull type
-        port.pupdr.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((pt as u32) << (pin * 2));
-            w.bits(n)
-        });
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LockError {
+    AlreadyLocked(Port),
+    // LCKK did not read back as set, the mask changed during the sequence
+    SequenceFailed(Port),
+}
+
+pub fn is_port_locked(port : Port) -> bool {
+    port.regs().lckr.read().bits() & LCKK != 0
+}
+
+pub fn is_locked(port : Port, pin : u8) -> bool {
+    let lckr = port.regs().lckr.read().bits();
+    pin < 16 && lckr & LCKK != 0 && lckr & (1 << pin) != 0
+}
+
+// run the lock key sequence for the pins of `mask` on `port`
+pub fn lock(port : Port, mask : u16) -> Result<(), LockError> {
+    if is_port_locked(port) {
+        return Err(LockError::AlreadyLocked(port));
+    }
 
+    let lckr = &port.regs().lckr;
+    let mask = mask as u32;
+    let locked = interrupt::free(|_| {
+        lckr.write(|w| unsafe{w.bits(LCKK | mask)});
+        lckr.write(|w| unsafe{w.bits(mask)});
+        lckr.write(|w| unsafe{w.bits(LCKK | mask)});
+        // the first read completes the sequence, the second one checks it
+        let _ = lckr.read().bits();
+        lckr.read().bits() & LCKK != 0
+    });
+
+    if locked {
         Ok(())
+    } else {
+        Err(LockError::SequenceFailed(port))
     }
+}
 
-    pub fn set_alt_fn<T : IsPortB>(gpio_port : &T, pin : u8, af : AltFn) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        if pin > 7 {
-            port.afrh.modify(|r, w| unsafe {
-                // clear targeted bits
-                let o = r.bits() & !(0b1111 << ((pin - 8) * 4));
-                // print pattern on new bitfield
-                let n = o | ((af as u32) << ((pin - 8) * 4));
-                w.bits(n)
-            });
-        } else {
-            port.afrl.modify(|r, w| unsafe {
-                // clear targeted bits
-                let o = r.bits() & !(0b1111 << (pin * 4));
-                // print pattern on new bitfield
-                let n = o | ((af as u32) << (pin * 4));
-                w.bits(n)
-            });
+// lock every pin of the groups, one sequence per port involved
+pub fn lock_groups(groups : &[&[PinConfig]]) -> Result<(), LockError> {
+    let mut masks = [0u16; 11];
+    for g in groups.iter() {
+        for p in g.iter() {
+            masks[p.port as usize] |= 1 << p.pin;
         }
-        Ok(())
     }
 
-    pub fn read<T : IsPortB>(gpio_port : &T, pin : u8) -> Result<bool, ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        Ok((port.idr.read().bits() | (1 << pin)) != 0)
+    let ports = [
+        Port::A, Port::B, Port::C, Port::D, Port::E, Port::F,
+        Port::G, Port::H, Port::I, Port::J, Port::K,
+    ];
+    for port in ports.iter() {
+        let mask = masks[*port as usize];
+        if mask != 0 {
+            lock(*port, mask)?;
+        }
+    }
+    Ok(())
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+// Pin ownership
+/////////////////////////////////////////////////////////////////////////////////
+
+// Drivers configuring pin tables, or pins missing from the board tables,
+// claim them first under their own name. An exclusive claim fails if anyone
+// else holds the pin, a shared claim (buses wired to several devices) only
+// fails if someone holds it exclusively.
+
+// one exclusive claim per pin plus room for the sharers
+const MAX_CLAIMS : usize = 11 * 16 + 32;
+// reported as owner when the registry is full
+const REGISTRY_FULL : &'static str = "<registry full>";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PinConflict {
+    pub port    : Port,
+    pub pin     : u8,
+    // current holder of the pin
+    pub owner   : &'static str,
+}
+
+#[derive(Copy, Clone)]
+struct Claim {
+    port    : Port,
+    pin     : u8,
+    owner   : &'static str,
+    shared  : bool,
+}
+
+static mut CLAIMS : [Option<Claim>; MAX_CLAIMS] = [None; MAX_CLAIMS];
+
+fn claims() -> &'static mut [Option<Claim>; MAX_CLAIMS] {
+    unsafe { &mut CLAIMS }
+}
+
+// Ok(false) if the owner already held the pin
+fn claim_pin(port : Port, pin : u8, owner : &'static str, shared : bool) -> Result<bool, PinConflict> {
+    let mut free = None;
+    for (i, c) in claims().iter().enumerate() {
+        match *c {
+            Some(c) if c.port == port && c.pin == pin => {
+                if c.owner == owner && c.shared == shared {
+                    return Ok(false);
+                }
+                if c.owner != owner && (!c.shared || !shared) {
+                    return Err(PinConflict { port : port, pin : pin, owner : c.owner });
+                }
+            },
+            Some(_) => (),
+            None => if free.is_none() { free = Some(i) },
+        }
+    }
+    match free {
+        Some(i) => {
+            claims()[i] = Some(Claim { port : port, pin : pin, owner : owner, shared : shared });
+            Ok(true)
+        },
+        None => Err(PinConflict { port : port, pin : pin, owner : REGISTRY_FULL }),
+    }
+}
+
+fn unclaim_pin(port : Port, pin : u8, owner : &'static str) {
+    for c in claims().iter_mut() {
+        if let Some(cl) = *c {
+            if cl.port == port && cl.pin == pin && cl.owner == owner {
+                *c = None;
+            }
+        }
     }
+}
+
+pub fn claim(port : Port, pin : u8, owner : &'static str) -> Result<(), PinConflict> {
+    interrupt::free(|_| claim_pin(port, pin, owner, false).map(|_| ()))
+}
+
+pub fn claim_shared(port : Port, pin : u8, owner : &'static str) -> Result<(), PinConflict> {
+    interrupt::free(|_| claim_pin(port, pin, owner, true).map(|_| ()))
+}
+
+pub fn unclaim(port : Port, pin : u8, owner : &'static str) {
+    interrupt::free(|_| unclaim_pin(port, pin, owner));
+}
+
+// first holder of a pin
+pub fn owner(port : Port, pin : u8) -> Option<&'static str> {
+    interrupt::free(|_| {
+        claims().iter()
+            .filter_map(|c| *c)
+            .find(|c| c.port == port && c.pin == pin)
+            .map(|c| c.owner)
+    })
+}
 
-    pub fn write<T : IsPortB>(gpio_port : &T, pin : u8, state : bool) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        port.bsrr.write(|w| unsafe {
-            if state {
-                w.bits(1 << pin)
-            } else {
-                w.bits(1 << (pin + 16))
+// Claim every pin of a group, nothing stays claimed if one of them conflicts
+pub fn claim_group(pins : &[PinConfig], owner : &'static str, shared : bool)
+    -> Result<(), PinConflict> {
+    interrupt::free(|_| {
+        // pins newly claimed by this call, to be rolled back on conflict
+        let mut new = [false; 16 * 11];
+        for (i, p) in pins.iter().enumerate() {
+            match claim_pin(p.port, p.pin, owner, shared) {
+                Ok(n) => new[i] = n,
+                Err(e) => {
+                    for (j, p) in pins[..i].iter().enumerate() {
+                        if new[j] {
+                            unclaim_pin(p.port, p.pin, owner);
+                        }
+                    }
+                    return Err(e);
+                },
             }
-        });
+        }
         Ok(())
-    }
+    })
+}
+
+pub fn unclaim_group(pins : &[PinConfig], owner : &'static str) {
+    interrupt::free(|_| {
+        for p in pins.iter() {
+            unclaim_pin(p.port, p.pin, owner);
+        }
+    });
 }
 
 /////////////////////////////////////////////////////////////////////////////////
-// GPIOK Implementation
+// Type-state pins
 /////////////////////////////////////////////////////////////////////////////////
-pub mod port_others {
-    use spl_rs::gpio::*;
-    pub fn configure(gpio_port : &gpiok::RegisterBlock,
-        pin : u8,
-        m : Mode,
-        ot : OutType,
-        os : OutSpeed,
-        pt : PullType
-    ) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port  = gpio_port;
-
-        // configure mode
-        port.moder.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((m as u32) << (pin * 2));
-            w.bits(n)
-        });
 
-        // configure output type. if input or analog, don't care
-        port.otyper.modify(|r, w| unsafe {
-            let o = r.bits() & !(1 << pin);
-            let n = o | ((ot as u32) << pin);
-            w.bits(n)
-        });
+// Each port splits into one owned value per pin, the mode of the pin is part
+// of its type. Mode changes consume the pin and return it in its new mode.
 
-        // configure output speed
-        port.ospeedr.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((os as u32) << (pin * 2));
-            w.bits(n)
-        });
+pub trait PortId {
+    const PORT : Port;
+}
 
-        // configure pull type
-        port.pupdr.modify(|r, w| unsafe {
-            // clear targeted bits
-            let o = r.bits() & !(0b11 << (pin * 2));
-            // print pattern on new bitfield
-            let n = o | ((pt as u32) << (pin * 2));
-            w.bits(n)
-        });
+pub trait PinId {
+    const PIN : u8;
+}
 
-        Ok(())
+pub trait AltFnId {
+    const AF : u8;
+}
+
+pub struct Floating;
+pub struct PullUp;
+pub struct PullDown;
+pub struct PushPull;
+pub struct OpenDrain;
+
+pub struct Input<PULL> {
+    _pull : PhantomData<PULL>,
+}
+
+pub struct Output<OTYPE> {
+    _otype : PhantomData<OTYPE>,
+}
+
+pub struct Alternate<AF> {
+    _af : PhantomData<AF>,
+}
+
+pub struct Analog;
+
+pub struct Pin<P, N, MODE> {
+    _port : PhantomData<P>,
+    _pin : PhantomData<N>,
+    _mode : PhantomData<MODE>,
+}
+
+impl<P : PortId, N : PinId, MODE> Pin<P, N, MODE> {
+    fn new() -> Pin<P, N, MODE> {
+        Pin {
+            _port : PhantomData,
+            _pin : PhantomData,
+            _mode : PhantomData,
+        }
     }
 
-    pub fn set_alt_fn(gpio_port : &gpiok::RegisterBlock, pin : u8, af : AltFn) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        if pin > 7 {
-            port.afrh.modify(|r, w| unsafe {
-                // clear targeted bits
-                let o = r.bits() & !(0b1111 << ((pin - 8) * 4));
-                // print pattern on new bitfield
-                let n = o | ((af as u32) << ((pin - 8) * 4));
-                w.bits(n)
-            });
-        } else {
-            port.afrl.modify(|r, w| unsafe {
-                // clear targeted bits
-                let o = r.bits() & !(0b1111 << (pin * 4));
-                // print pattern on new bitfield
-                let n = o | ((af as u32) << (pin * 4));
-                w.bits(n)
-            });
+    pub fn port(&self) -> Port {
+        P::PORT
+    }
+
+    pub fn pin_number(&self) -> u8 {
+        N::PIN
+    }
+
+    // move the port and number to run time, the mode stays in the type
+    pub fn erase(self) -> ErasedPin<MODE> {
+        ErasedPin {
+            port : P::PORT,
+            pin : N::PIN,
+            _mode : PhantomData,
         }
-        Ok(())
     }
 
-    pub fn read(gpio_port : &gpiok::RegisterBlock, pin : u8) -> Result<bool, ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        Ok((port.idr.read().bits() | (1 << pin)) != 0)
+    // the pin number is always valid here, the runtime checks can't fail
+    fn into_mode<NMODE>(self, m : Mode, ot : OutType, pt : PullType) -> Pin<P, N, NMODE> {
+        set_pull(P::PORT, N::PIN, pt).unwrap();
+        set_out_type(P::PORT, N::PIN, ot).unwrap();
+        set_mode(P::PORT, N::PIN, m).unwrap();
+        Pin::new()
+    }
+
+    pub fn into_floating_input(self) -> Pin<P, N, Input<Floating>> {
+        self.into_mode(Mode::Input, OutType::PushPull, PullType::NoPull)
+    }
+
+    pub fn into_pull_up_input(self) -> Pin<P, N, Input<PullUp>> {
+        self.into_mode(Mode::Input, OutType::PushPull, PullType::PullUp)
+    }
+
+    pub fn into_pull_down_input(self) -> Pin<P, N, Input<PullDown>> {
+        self.into_mode(Mode::Input, OutType::PushPull, PullType::PullDown)
+    }
+
+    pub fn into_push_pull_output(self) -> Pin<P, N, Output<PushPull>> {
+        self.into_mode(Mode::Output, OutType::PushPull, PullType::NoPull)
+    }
+
+    pub fn into_open_drain_output(self) -> Pin<P, N, Output<OpenDrain>> {
+        self.into_mode(Mode::Output, OutType::OpenDrain, PullType::NoPull)
+    }
+
+    // push-pull alternate function, the function is selected before the
+    // pin is handed to the peripheral
+    pub fn into_alternate<AF : AltFnId>(self) -> Pin<P, N, Alternate<AF>> {
+        set_alt_fn_bits(P::PORT, N::PIN, AF::AF).unwrap();
+        self.into_mode(Mode::AltFn, OutType::PushPull, PullType::NoPull)
+    }
+
+    pub fn into_alternate_open_drain<AF : AltFnId>(self) -> Pin<P, N, Alternate<AF>> {
+        set_alt_fn_bits(P::PORT, N::PIN, AF::AF).unwrap();
+        self.into_mode(Mode::AltFn, OutType::OpenDrain, PullType::NoPull)
+    }
+
+    pub fn into_analog(self) -> Pin<P, N, Analog> {
+        self.into_mode(Mode::Analog, OutType::PushPull, PullType::NoPull)
+    }
+}
+
+impl<P : PortId, N : PinId, PULL> Pin<P, N, Input<PULL>> {
+    pub fn is_high(&self) -> bool {
+        read(P::PORT, N::PIN).unwrap()
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+impl<P : PortId, N : PinId, OTYPE> Pin<P, N, Output<OTYPE>> {
+    pub fn set_high(&mut self) {
+        write(P::PORT, N::PIN, true).unwrap();
+    }
+
+    pub fn set_low(&mut self) {
+        write(P::PORT, N::PIN, false).unwrap();
+    }
+
+    pub fn toggle(&mut self) {
+        let state = self.is_set_high();
+        write(P::PORT, N::PIN, !state).unwrap();
+    }
+
+    pub fn is_set_high(&self) -> bool {
+        read_output(P::PORT, N::PIN).unwrap()
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
     }
 
-    pub fn write(gpio_port : &gpiok::RegisterBlock, pin : u8, state : bool) -> Result<(), ()> {
-        if pin > 15 {return Err(());}
-        let port = gpio_port;
-        port.bsrr.write(|w| unsafe {
-            if state {
-                w.bits(1 << pin)
-            } else {
-                w.bits(1 << (pin + 16))
+    // with an open-drain output this reads the line, not the latch
+    pub fn is_high(&self) -> bool {
+        read(P::PORT, N::PIN).unwrap()
+    }
+
+    pub fn set_speed(&mut self, os : OutSpeed) {
+        set_speed(P::PORT, N::PIN, os).unwrap();
+    }
+}
+
+impl<P : PortId, N : PinId, AF> Pin<P, N, Alternate<AF>> {
+    pub fn set_speed(&mut self, os : OutSpeed) {
+        set_speed(P::PORT, N::PIN, os).unwrap();
+    }
+
+    pub fn set_pull(&mut self, pt : PullType) {
+        set_pull(P::PORT, N::PIN, pt).unwrap();
+    }
+}
+
+// embedded-hal view of the typed pins, for drivers written against it
+
+impl<P : PortId, N : PinId, OTYPE> digital::OutputPin for Pin<P, N, Output<OTYPE>> {
+    fn set_high(&mut self) {
+        Pin::set_high(self)
+    }
+
+    fn set_low(&mut self) {
+        Pin::set_low(self)
+    }
+}
+
+impl<P : PortId, N : PinId, OTYPE> digital::StatefulOutputPin for Pin<P, N, Output<OTYPE>> {
+    fn is_set_high(&self) -> bool {
+        Pin::is_set_high(self)
+    }
+
+    fn is_set_low(&self) -> bool {
+        Pin::is_set_low(self)
+    }
+}
+
+impl<P : PortId, N : PinId, OTYPE> digital::ToggleableOutputPin for Pin<P, N, Output<OTYPE>> {
+    fn toggle(&mut self) {
+        Pin::toggle(self)
+    }
+}
+
+impl<P : PortId, N : PinId, PULL> digital::InputPin for Pin<P, N, Input<PULL>> {
+    fn is_high(&self) -> bool {
+        Pin::is_high(self)
+    }
+
+    fn is_low(&self) -> bool {
+        Pin::is_low(self)
+    }
+}
+
+// an open-drain output is also read back, e.g. for I2C clock stretching
+impl<P : PortId, N : PinId> digital::InputPin for Pin<P, N, Output<OpenDrain>> {
+    fn is_high(&self) -> bool {
+        Pin::is_high(self)
+    }
+
+    fn is_low(&self) -> bool {
+        !Pin::is_high(self)
+    }
+}
+
+// Typed pin of any port, for tables holding pins of several drivers. It can
+// only be made from a typed pin so the number is valid.
+pub struct ErasedPin<MODE> {
+    port : Port,
+    pin : u8,
+    _mode : PhantomData<MODE>,
+}
+
+impl<MODE> ErasedPin<MODE> {
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn pin_number(&self) -> u8 {
+        self.pin
+    }
+}
+
+impl<PULL> ErasedPin<Input<PULL>> {
+    pub fn is_high(&self) -> bool {
+        read(self.port, self.pin).unwrap()
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+impl<OTYPE> ErasedPin<Output<OTYPE>> {
+    pub fn set_high(&mut self) {
+        write(self.port, self.pin, true).unwrap();
+    }
+
+    pub fn set_low(&mut self) {
+        write(self.port, self.pin, false).unwrap();
+    }
+
+    pub fn toggle(&mut self) {
+        let state = self.is_set_high();
+        write(self.port, self.pin, !state).unwrap();
+    }
+
+    pub fn is_set_high(&self) -> bool {
+        read_output(self.port, self.pin).unwrap()
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+}
+
+impl<OTYPE> digital::OutputPin for ErasedPin<Output<OTYPE>> {
+    fn set_high(&mut self) {
+        ErasedPin::set_high(self)
+    }
+
+    fn set_low(&mut self) {
+        ErasedPin::set_low(self)
+    }
+}
+
+impl<OTYPE> digital::StatefulOutputPin for ErasedPin<Output<OTYPE>> {
+    fn is_set_high(&self) -> bool {
+        ErasedPin::is_set_high(self)
+    }
+
+    fn is_set_low(&self) -> bool {
+        ErasedPin::is_set_low(self)
+    }
+}
+
+impl<PULL> digital::InputPin for ErasedPin<Input<PULL>> {
+    fn is_high(&self) -> bool {
+        ErasedPin::is_high(self)
+    }
+
+    fn is_low(&self) -> bool {
+        ErasedPin::is_low(self)
+    }
+}
+
+pub trait GpioExt {
+    type Parts;
+
+    // enables the port clock and hands out every pin in its reset mode
+    fn split(self) -> Self::Parts;
+}
+
+macro_rules! markers {
+    ($trait_:ident, $c:ident, $ty:ty, [$($Name:ident : $val:expr,)+]) => {
+        $(
+            pub struct $Name;
+            impl $trait_ for $Name {
+                const $c : $ty = $val;
             }
-        });
-        Ok(())
+        )+
     }
 }
+
+markers!(PortId, PORT, Port, [
+    PortA : Port::A, PortB : Port::B, PortC : Port::C, PortD : Port::D,
+    PortE : Port::E, PortF : Port::F, PortG : Port::G, PortH : Port::H,
+    PortI : Port::I, PortJ : Port::J, PortK : Port::K,
+]);
+
+markers!(PinId, PIN, u8, [
+    N0 : 0, N1 : 1, N2 : 2, N3 : 3, N4 : 4, N5 : 5, N6 : 6, N7 : 7,
+    N8 : 8, N9 : 9, N10 : 10, N11 : 11, N12 : 12, N13 : 13, N14 : 14, N15 : 15,
+]);
+
+markers!(AltFnId, AF, u8, [
+    AF0 : 0, AF1 : 1, AF2 : 2, AF3 : 3, AF4 : 4, AF5 : 5, AF6 : 6, AF7 : 7,
+    AF8 : 8, AF9 : 9, AF10 : 10, AF11 : 11, AF12 : 12, AF13 : 13, AF14 : 14, AF15 : 15,
+]);
+
+macro_rules! gpio {
+    ($GPIOX:ident, $gpiox:ident, $PortX:ident, $port:expr, [
+        $($pxi:ident : ($Ni:ident, $MODE:ty),)+
+    ]) => {
+        pub mod $gpiox {
+            use super::*;
+
+            pub struct Parts {
+                $(pub $pxi : Pin<$PortX, $Ni, $MODE>,)+
+            }
+
+            impl GpioExt for $GPIOX {
+                type Parts = Parts;
+
+                // the pins can't be given back, so the port clock is kept
+                // outside the hold table and split can't fail
+                fn split(self) -> Parts {
+                    clk_gate::keep(Gate::Ahb1($port.clk()));
+                    Parts {
+                        $($pxi : Pin::new(),)+
+                    }
+                }
+            }
+        }
+    }
+}
+
+// every pin leaves reset as a floating input except the debug port pins,
+// which are on AF0 so that the debugger stays attached
+gpio!(GPIOA, gpioa, PortA, Port::A, [
+    pa0 : (N0, Input<Floating>), pa1 : (N1, Input<Floating>),
+    pa2 : (N2, Input<Floating>), pa3 : (N3, Input<Floating>),
+    pa4 : (N4, Input<Floating>), pa5 : (N5, Input<Floating>),
+    pa6 : (N6, Input<Floating>), pa7 : (N7, Input<Floating>),
+    pa8 : (N8, Input<Floating>), pa9 : (N9, Input<Floating>),
+    pa10 : (N10, Input<Floating>), pa11 : (N11, Input<Floating>),
+    pa12 : (N12, Input<Floating>), pa13 : (N13, Alternate<AF0>),
+    pa14 : (N14, Alternate<AF0>), pa15 : (N15, Alternate<AF0>),
+]);
+
+gpio!(GPIOB, gpiob, PortB, Port::B, [
+    pb0 : (N0, Input<Floating>), pb1 : (N1, Input<Floating>),
+    pb2 : (N2, Input<Floating>), pb3 : (N3, Alternate<AF0>),
+    pb4 : (N4, Alternate<AF0>), pb5 : (N5, Input<Floating>),
+    pb6 : (N6, Input<Floating>), pb7 : (N7, Input<Floating>),
+    pb8 : (N8, Input<Floating>), pb9 : (N9, Input<Floating>),
+    pb10 : (N10, Input<Floating>), pb11 : (N11, Input<Floating>),
+    pb12 : (N12, Input<Floating>), pb13 : (N13, Input<Floating>),
+    pb14 : (N14, Input<Floating>), pb15 : (N15, Input<Floating>),
+]);
+
+macro_rules! gpio_floating {
+    ($GPIOX:ident, $gpiox:ident, $PortX:ident, $port:expr, [
+        $p0:ident, $p1:ident, $p2:ident, $p3:ident, $p4:ident, $p5:ident,
+        $p6:ident, $p7:ident, $p8:ident, $p9:ident, $p10:ident, $p11:ident,
+        $p12:ident, $p13:ident, $p14:ident, $p15:ident
+    ]) => {
+        gpio!($GPIOX, $gpiox, $PortX, $port, [
+            $p0 : (N0, Input<Floating>), $p1 : (N1, Input<Floating>),
+            $p2 : (N2, Input<Floating>), $p3 : (N3, Input<Floating>),
+            $p4 : (N4, Input<Floating>), $p5 : (N5, Input<Floating>),
+            $p6 : (N6, Input<Floating>), $p7 : (N7, Input<Floating>),
+            $p8 : (N8, Input<Floating>), $p9 : (N9, Input<Floating>),
+            $p10 : (N10, Input<Floating>), $p11 : (N11, Input<Floating>),
+            $p12 : (N12, Input<Floating>), $p13 : (N13, Input<Floating>),
+            $p14 : (N14, Input<Floating>), $p15 : (N15, Input<Floating>),
+        ]);
+    }
+}
+
+gpio_floating!(GPIOC, gpioc, PortC, Port::C, [
+    pc0, pc1, pc2, pc3, pc4, pc5, pc6, pc7, pc8, pc9, pc10, pc11, pc12, pc13, pc14, pc15
+]);
+gpio_floating!(GPIOD, gpiod, PortD, Port::D, [
+    pd0, pd1, pd2, pd3, pd4, pd5, pd6, pd7, pd8, pd9, pd10, pd11, pd12, pd13, pd14, pd15
+]);
+gpio_floating!(GPIOE, gpioe, PortE, Port::E, [
+    pe0, pe1, pe2, pe3, pe4, pe5, pe6, pe7, pe8, pe9, pe10, pe11, pe12, pe13, pe14, pe15
+]);
+gpio_floating!(GPIOF, gpiof, PortF, Port::F, [
+    pf0, pf1, pf2, pf3, pf4, pf5, pf6, pf7, pf8, pf9, pf10, pf11, pf12, pf13, pf14, pf15
+]);
+gpio_floating!(GPIOG, gpiog, PortG, Port::G, [
+    pg0, pg1, pg2, pg3, pg4, pg5, pg6, pg7, pg8, pg9, pg10, pg11, pg12, pg13, pg14, pg15
+]);
+gpio_floating!(GPIOH, gpioh, PortH, Port::H, [
+    ph0, ph1, ph2, ph3, ph4, ph5, ph6, ph7, ph8, ph9, ph10, ph11, ph12, ph13, ph14, ph15
+]);
+gpio_floating!(GPIOI, gpioi, PortI, Port::I, [
+    pi0, pi1, pi2, pi3, pi4, pi5, pi6, pi7, pi8, pi9, pi10, pi11, pi12, pi13, pi14, pi15
+]);
+gpio_floating!(GPIOJ, gpioj, PortJ, Port::J, [
+    pj0, pj1, pj2, pj3, pj4, pj5, pj6, pj7, pj8, pj9, pj10, pj11, pj12, pj13, pj14, pj15
+]);
+gpio_floating!(GPIOK, gpiok, PortK, Port::K, [
+    pk0, pk1, pk2, pk3, pk4, pk5, pk6, pk7, pk8, pk9, pk10, pk11, pk12, pk13, pk14, pk15
+]);
@@ -0,0 +1,131 @@
+use spl_rs::{gpio, rcc};
+use spl_rs::gpio::{Pin, PortId, PinId, PortA, PortC, N8, N9, Alternate, AF0, Input, Floating};
+use spl_rs::rcc::{Mco1ClockSrc, Mco2ClockSrc, McoPre};
+use system::clk_config::HSI_FREQ;
+
+const OWNER     : &'static str = "mco";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mco {
+    Mco1(Mco1ClockSrc),
+    Mco2(Mco2ClockSrc),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum McoError {
+    // the selected source is not running, nothing would come out of the pin
+    SourceStopped,
+    PinConflict(gpio::PinConflict),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct McoOutput {
+    pub freq        : u32,
+    // max frequency the pin is rated for at the selected speed
+    pub pin_max     : u32,
+}
+
+impl McoOutput {
+    // the signal on the pin will be distorted, pick a higher speed or a
+    // bigger prescaler
+    pub fn over_speed(&self) -> bool {
+        self.freq > self.pin_max
+    }
+}
+
+// datasheet figures at 2.7V..3.6V with a 30pF load, a scope probe is
+// usually lighter than that
+fn pin_max_freq(os : &gpio::OutSpeed) -> u32 {
+    match *os {
+        gpio::OutSpeed::Low => 2_000_000,
+        gpio::OutSpeed::Medium => 25_000_000,
+        gpio::OutSpeed::High => 50_000_000,
+        gpio::OutSpeed::VeryHigh => 100_000_000,
+    }
+}
+
+fn pre_div(pre : McoPre) -> u32 {
+    match pre {
+        McoPre::DivBy1 => 1,
+        McoPre::DivBy2 => 2,
+        McoPre::DivBy3 => 3,
+        McoPre::DivBy4 => 4,
+        McoPre::DivBy5 => 5,
+    }
+}
+
+// frequency of the source before the prescaler, 0 if it is stopped
+pub fn source_freq(mco : Mco) -> u32 {
+    let freqs = rcc::get_clocks_freq();
+    let ready = |f| rcc::check_flag(f);
+    match mco {
+        Mco::Mco1(Mco1ClockSrc::Hsi) =>
+            if ready(rcc::ClkFlag::HSI_RDY) { HSI_FREQ } else { 0 },
+        Mco::Mco1(Mco1ClockSrc::Lse) =>
+            if rcc::get_lse_status() { rcc::LSE_VALUE } else { 0 },
+        Mco::Mco1(Mco1ClockSrc::Hse) | Mco::Mco2(Mco2ClockSrc::Hse) =>
+            if ready(rcc::ClkFlag::HSE_RDY) { rcc::HSE_VALUE } else { 0 },
+        Mco::Mco1(Mco1ClockSrc::Pll) | Mco::Mco2(Mco2ClockSrc::Pll) => freqs.pll_clk,
+        Mco::Mco2(Mco2ClockSrc::SysClk) => freqs.sysclk,
+        Mco::Mco2(Mco2ClockSrc::PllI2s) => freqs.plli2s_clk,
+    }
+}
+
+// Route MCO1 to PA8 through `pre`, the pin is switched to AF0 at speed `os`.
+// The output is configured even if it is faster than what the pin can drive
+// at that speed, check McoOutput::over_speed. The pin is handed back
+// untouched on error.
+pub fn enable_mco1<MODE>(pin : Pin<PortA, N8, MODE>, src : Mco1ClockSrc, pre : McoPre,
+                         os : gpio::OutSpeed)
+    -> Result<(Pin<PortA, N8, Alternate<AF0>>, McoOutput), (Pin<PortA, N8, MODE>, McoError)> {
+    enable(pin, Mco::Mco1(src), pre, os)
+}
+
+// Route MCO2 to PC9, see enable_mco1
+pub fn enable_mco2<MODE>(pin : Pin<PortC, N9, MODE>, src : Mco2ClockSrc, pre : McoPre,
+                         os : gpio::OutSpeed)
+    -> Result<(Pin<PortC, N9, Alternate<AF0>>, McoOutput), (Pin<PortC, N9, MODE>, McoError)> {
+    enable(pin, Mco::Mco2(src), pre, os)
+}
+
+// The pins are shared with I2C3 on the board, a claim keeps the touch screen
+// driver off them while the clock is out.
+fn enable<P, N, MODE>(pin : Pin<P, N, MODE>, mco : Mco, pre : McoPre, os : gpio::OutSpeed)
+    -> Result<(Pin<P, N, Alternate<AF0>>, McoOutput), (Pin<P, N, MODE>, McoError)>
+    where P : PortId, N : PinId {
+    let src_freq = source_freq(mco);
+    if src_freq == 0 {
+        return Err((pin, McoError::SourceStopped));
+    }
+
+    if let Err(e) = gpio::claim(P::PORT, N::PIN, OWNER) {
+        return Err((pin, McoError::PinConflict(e)));
+    }
+
+    match mco {
+        Mco::Mco1(src) => {
+            rcc::set_mco1_src(src);
+            rcc::set_mco1_pre(pre);
+        },
+        Mco::Mco2(src) => {
+            rcc::set_mco2_src(src);
+            rcc::set_mco2_pre(pre);
+        },
+    }
+    let mut pin = pin.into_alternate::<AF0>();
+    pin.set_speed(os);
+
+    Ok((pin, McoOutput {
+        freq        : src_freq / pre_div(pre),
+        pin_max     : pin_max_freq(&os),
+    }))
+}
+
+// Put the pin back in its reset state (floating input). The RCC keeps
+// feeding the MCO internally, which is harmless.
+pub fn disable<P, N>(pin : Pin<P, N, Alternate<AF0>>) -> Pin<P, N, Input<Floating>>
+    where P : PortId, N : PinId {
+    let pin = pin.into_floating_input();
+    gpio::unclaim(P::PORT, N::PIN, OWNER);
+    pin
+}
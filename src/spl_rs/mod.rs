@@ -1,2 +1,14 @@
 pub mod gpio;
 pub mod rcc;
+pub mod clk_gate;
+pub mod bitband;
+pub mod mco;
+pub mod exti;
+pub mod spi;
+pub mod dma;
+pub mod delay;
+pub mod soft_i2c;
+pub mod soft_spi;
+
+// hardware independent, see system/mod.rs
+pub use stm32f429i_disco::spl_rs::{pins, clk_decode};
@@ -0,0 +1,83 @@
+// Description of the pins, independent of the hardware access so that the
+// pin tables can be checked anywhere. spl_rs::gpio applies them.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    Input   = 0b00,
+    Output  = 0b01,
+    AltFn   = 0b10,
+    Analog  = 0b11,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OutType {
+    PushPull = 0,
+    OpenDrain = 1,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OutSpeed {
+    Low = 0b00,
+    Medium = 0b01,
+    High = 0b10,
+    VeryHigh = 0b11,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PullType {
+    NoPull = 0b00,
+    PullUp = 0b01,
+    PullDown = 0b10,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AltFn {
+    Sys               = 0,
+    Tim12             = 1,
+    Tim345            = 2,
+    Tim8910           = 3,
+    I2c123            = 4,
+    Spi123456         = 5,
+    Spi23Sai1         = 6,
+    Spi3Usart123      = 7,
+    Usart6Uart4578    = 8,
+    Can12Tim121314Lcd = 9,
+    Otg2HsOtg1Fs      = 10,
+    Eth               = 11,
+    FmcSdioOtg2Fs     = 12,
+    Dcmi              = 13,
+    Lcd               = 14,
+    SysEvent          = 15,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Port {
+    A, B, C, D, E, F, G, H, I, J, K,
+}
+
+// Complete setup of one pin, `af` is only meaningful in AltFn mode
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PinConfig {
+    pub port    : Port,
+    pub pin     : u8,
+    pub mode    : Mode,
+    pub af      : AltFn,
+    pub otype   : OutType,
+    pub speed   : OutSpeed,
+    pub pull    : PullType,
+}
+
+// first pin appearing twice across the groups, if any
+pub fn find_overlap(groups : &[&[PinConfig]]) -> Option<(Port, u8)> {
+    let mut used = [0u16; 11];
+    for g in groups.iter() {
+        for p in g.iter() {
+            let mask = 1 << p.pin;
+            if used[p.port as usize] & mask != 0 {
+                return Some((p.port, p.pin));
+            }
+            used[p.port as usize] |= mask;
+        }
+    }
+    None
+}
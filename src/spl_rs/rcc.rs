@@ -1,4 +1,39 @@
 use stm32f429::RCC;
+use misc;
+use spl_rs::bitband;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RccError {
+    PllMOutOfRange(u8),
+    PllNOutOfRange(u16),
+    PllPOutOfRange(u8),
+    PllQOutOfRange(u8),
+    PllI2sROutOfRange(u8),
+    PllI2sNOutOfRange(u16),
+    PllI2sQOutOfRange(u8),
+    PllSaiROutOfRange(u8),
+    PllSaiNOutOfRange(u16),
+    PllSaiQOutOfRange(u8),
+    PllSaiDivQOutOfRange(u8),
+    PllI2sDivQOutOfRange(u8),
+    RtcDivOutOfRange(u8),
+    SscgIncStepOutOfRange(u16),
+    SscgModPeriodOutOfRange(u16),
+    HseStartupTimeout,
+    HsiStartupTimeout,
+    PllLockTimeout,
+    PllI2sLockTimeout,
+    PllSaiLockTimeout,
+    OverDriveTimeout,
+    OverDriveSwitchTimeout,
+    SysClkSwitchTimeout,
+}
+
+// number of polling iterations before giving up on a ready flag, same
+// order of magnitude as HSE_STARTUP_TIMEOUT in the SPL
+pub const HSE_STARTUP_TIMEOUT   : u32 = 0x5000;
+pub const PLL_LOCK_TIMEOUT      : u32 = 0x5000;
+pub const CLK_SWITCH_TIMEOUT    : u32 = 0x5000;
 
 bitflags! {
     pub struct ClkFlag : u32 {
@@ -15,9 +50,14 @@ pub fn check_flag(f : ClkFlag) -> bool {
     ((rcc.cr.read().bits() & f.bits()) != 0)
 }
 
+// poll a ready flag at most `timeout` times, returns false if it never rose
+pub fn wait_flag(f : ClkFlag, timeout : u32) -> bool {
+    misc::wait_until(|| check_flag(f), timeout)
+}
+
 bitflags! {
     pub struct Clock : u32 {
-        const PLL_SAI_RDY   = 1 << 28;
+        const PLL_SAI_ON    = 1 << 28;
         const PLL_I2S_ON    = 1 << 26;
         const PLL_ON        = 1 << 24;
         const CSS_ON        = 1 << 19;
@@ -27,41 +67,36 @@ bitflags! {
     }
 }
 
+// one bit-band write per clock, cr is also touched by the css interrupt
 pub fn clock_ctrl(c : Clock, state : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.cr.modify(|r, w| unsafe {
-        let o = r.bits();
-        let n = if state {
-            o | c.bits()
-        } else {
-            o & !c.bits()
-        };
-        w.bits(n)
-    });
+    for bit in 0..32 {
+        if c.bits() & (1 << bit) != 0 {
+            bitband::write(&rcc.cr, bit, state);
+        }
+    }
 }
 
 pub fn select_pll_src(hse : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.pllcfgr.modify(|_, w| {
-        w.pllsrc().bit(hse)
-    });
+    bitband::write(&rcc.pllcfgr, 22, hse);
 }
 
-pub fn configure_pll(q : u8, n : u16, p : u8, m : u8) -> Result<(), ()> {
-    if q > 0b1111 {
-        return Err(())
+pub fn configure_pll(q : u8, n : u16, p : u8, m : u8) -> Result<(), RccError> {
+    if q < 2 || q > 0b1111 {
+        return Err(RccError::PllQOutOfRange(q))
     }
 
     if p > 0b11 {
-        return Err(())
+        return Err(RccError::PllPOutOfRange(p))
     }
 
-    if n > 0x1FF {
-        return Err(())
+    if n < 50 || n > 432 {
+        return Err(RccError::PllNOutOfRange(n))
     }
 
-    if m > 0x1F {
-        return Err(())
+    if m < 2 || m > 0x3F {
+        return Err(RccError::PllMOutOfRange(m))
     }
 
     let rcc = unsafe {&*RCC::ptr()};
@@ -74,6 +109,7 @@ pub fn configure_pll(q : u8, n : u16, p : u8, m : u8) -> Result<(), ()> {
     Ok(())
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Mco2ClockSrc {
     SysClk    = 0b00,
     PllI2s    = 0b01,
@@ -88,6 +124,7 @@ pub fn set_mco2_src(clk : Mco2ClockSrc) {
     });
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Mco1ClockSrc {
     Hsi = 0b00,
     Lse = 0b01,
@@ -102,7 +139,9 @@ pub fn set_mco1_src(clk : Mco1ClockSrc) {
     });
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum McoPre {
+    DivBy1 = 0b000,
     DivBy2 = 0b100,
     DivBy3 = 0b101,
     DivBy4 = 0b110,
@@ -138,9 +177,9 @@ pub fn set_plli2s_src(is : I2sSrc) {
     });
 }
 
-pub fn set_rtc_div(d : u8) -> Result<(), ()> {
+pub fn set_rtc_div(d : u8) -> Result<(), RccError> {
     if d > 31 {
-        return Err(())
+        return Err(RccError::RtcDivOutOfRange(d))
     }
 
     let rcc = unsafe {&*RCC::ptr()};
@@ -192,7 +231,7 @@ pub fn set_ahb_pre(ap : AhbPre) {
     });
 }
 
-#[derive(PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum SysClkSrc {
     Hsi = 0b00,
     Hse = 0b01,
@@ -284,12 +323,8 @@ pub fn set_interrupt(ie : InterruptEnable, en : bool) {
 
 pub fn check_interrupt_flag(intf : InterruptFlag) -> bool {
     let rcc = unsafe {&*RCC::ptr()};
-    let rf = InterruptFlag::from_bits(rcc.cir.read().bits());
-    let rf = if let Some(rf) = rf {
-        rf
-    } else {
-        InterruptFlag::empty()
-    };
+    // cir also holds the enable bits, from_bits would reject it
+    let rf = InterruptFlag::from_bits_truncate(rcc.cir.read().bits());
     if rf.contains(intf) {
         true
     } else {
@@ -589,6 +624,7 @@ bitflags! {
         const TIM10   = 1 << 17;
         const TIM9    = 1 << 16;
         const SYS_CFG = 1 << 14;
+        const SPI4    = 1 << 13;
         const SPI1    = 1 << 12;
         const SDIO    = 1 << 11;
         const ADC3    = 1 << 10;
@@ -683,16 +719,12 @@ pub fn set_lp_apb2_periph_clk(ae : Apb2Enable, en : bool) {
 ////////////////////////////////////////////////////////////////////////////////
 pub fn reset_backup_domain(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.bdcr.modify(|_, w| {
-        w.bdrst().bit(en)
-    });
+    bitband::write(&rcc.bdcr, 16, en);
 }
 
 pub fn set_rtc(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.bdcr.modify(|_, w| {
-        w.rtcen().bit(en)
-    });
+    bitband::write(&rcc.bdcr, 15, en);
 }
 
 #[derive(Copy, Clone)]
@@ -715,16 +747,12 @@ pub fn set_rtc_clk_src(rcs : RtcClkSrc) {
 
 pub fn enable_lse_bypass(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.bdcr.modify(|_, w| {
-        w.lsebyp().bit(en)
-    });
+    bitband::write(&rcc.bdcr, 2, en);
 }
 
 pub fn set_lse(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.bdcr.modify(|_, w| {
-        w.lseon().bit(en)
-    });
+    bitband::write(&rcc.bdcr, 0, en);
 }
 
 pub fn get_lse_status() -> bool {
@@ -747,7 +775,7 @@ bitflags! {
 
 pub fn get_reset_flag() -> ResetFlag {
     let rcc = unsafe {&*RCC::ptr()};
-    let ret = ResetFlag::from_bits(rcc.csr.read().bits() & 0xFF000000);
+    let ret = ResetFlag::from_bits(rcc.csr.read().bits() & 0xFE000000);
     if let Some(ret) = ret {
         ret
     } else {
@@ -757,7 +785,7 @@ pub fn get_reset_flag() -> ResetFlag {
 
 pub fn clear_reset_flag() {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.csr.modify(|_, w| w.rmvf().bit(true));
+    bitband::write(&rcc.csr, 24, true);
 }
 
 pub fn check_lsi_flag() -> bool {
@@ -767,29 +795,23 @@ pub fn check_lsi_flag() -> bool {
 
 pub fn set_lsi(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.csr.modify(|r, w| {
-        w.lsion().bit(en)
-    });
+    bitband::write(&rcc.csr, 0, en);
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 pub fn set_sspm(en : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.sscgr.modify(|_, w| {
-        w.sscgen().bit(en)
-    });
+    bitband::write(&rcc.sscgr, 31, en);
 }
 
 pub fn select_spread(down : bool) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.sscgr.modify(|_, w| {
-        w.spreadsel().bit(down)
-    });
+    bitband::write(&rcc.sscgr, 30, down);
 }
 
-pub fn set_sscg_inc_step(is : u16) -> Result<(), ()> {
+pub fn set_sscg_inc_step(is : u16) -> Result<(), RccError> {
     if is > 32767 {
-        return Err(())
+        return Err(RccError::SscgIncStepOutOfRange(is))
     }
     let rcc = unsafe {&*RCC::ptr()};
     rcc.sscgr.modify(|_, w| unsafe {
@@ -799,9 +821,29 @@ pub fn set_sscg_inc_step(is : u16) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn set_sscg_mod_period(mp : u16) -> Result<(), ()>{
-    if mp > 4095 {
-        return Err(())
+// SSCGR has to be written in one go, before the main PLL is enabled or
+// after it has been disabled
+pub fn conf_sscg(mp : u16, is : u16, down : bool) -> Result<(), RccError> {
+    if mp > 8191 {
+        return Err(RccError::SscgModPeriodOutOfRange(mp))
+    }
+    if is > 32767 {
+        return Err(RccError::SscgIncStepOutOfRange(is))
+    }
+    let rcc = unsafe {&*RCC::ptr()};
+    rcc.sscgr.write(|w| unsafe {
+        w.modper().bits(mp)
+         .incstep().bits(is)
+         .spreadsel().bit(down)
+         .sscgen().bit(true)
+    });
+
+    Ok(())
+}
+
+pub fn set_sscg_mod_period(mp : u16) -> Result<(), RccError> {
+    if mp > 8191 {
+        return Err(RccError::SscgModPeriodOutOfRange(mp))
     }
     let rcc = unsafe {&*RCC::ptr()};
     rcc.sscgr.modify(|_, w| unsafe {
@@ -813,26 +855,22 @@ pub fn set_sscg_mod_period(mp : u16) -> Result<(), ()>{
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub fn conf_i2s_pll(r : u8, q : u8, n : u16) -> Result<(), ()> {
+pub fn conf_i2s_pll(r : u8, q : u8, n : u16) -> Result<(), RccError> {
     match r {
-        0 | 1 => return Err(()),
-        x if x > 7 => return Err(()),
+        0 | 1 => return Err(RccError::PllI2sROutOfRange(r)),
+        x if x > 7 => return Err(RccError::PllI2sROutOfRange(r)),
         _ => (),
     };
 
-    if n > 511 {
-        return Err(())
-    }
-
     match n {
-        x if x < 50 => return Err(()),
-        x if x > 432 => return Err(()),
+        x if x < 50 => return Err(RccError::PllI2sNOutOfRange(n)),
+        x if x > 432 => return Err(RccError::PllI2sNOutOfRange(n)),
         _ => (),
     };
 
     match q {
-        0 | 1 => return Err(()),
-        x if x > 15 => return Err(()),
+        0 | 1 => return Err(RccError::PllI2sQOutOfRange(q)),
+        x if x > 15 => return Err(RccError::PllI2sQOutOfRange(q)),
         _ => (),
     };
 
@@ -848,21 +886,22 @@ pub fn conf_i2s_pll(r : u8, q : u8, n : u16) -> Result<(), ()> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub fn conf_sai_pll(r : u8, q : u8, n : u16) -> Result<(), ()> {
+pub fn conf_sai_pll(r : u8, q : u8, n : u16) -> Result<(), RccError> {
     match r {
-        x if x < 2 => return Err(()),
-        x if x > 7 => return Err(()),
+        x if x < 2 => return Err(RccError::PllSaiROutOfRange(r)),
+        x if x > 7 => return Err(RccError::PllSaiROutOfRange(r)),
         _ => (),
     };
 
     match q {
-        x if x < 2 => return Err(()),
+        x if x < 2 => return Err(RccError::PllSaiQOutOfRange(q)),
+        x if x > 15 => return Err(RccError::PllSaiQOutOfRange(q)),
         _ => (),
     };
 
     match n {
-        x if x < 50 => return Err(()),
-        x if x > 432 => return Err(()),
+        x if x < 50 => return Err(RccError::PllSaiNOutOfRange(n)),
+        x if x > 432 => return Err(RccError::PllSaiNOutOfRange(n)),
         _ => (),
     };
 
@@ -876,6 +915,29 @@ pub fn conf_sai_pll(r : u8, q : u8, n : u16) -> Result<(), ()> {
     Ok(())
 }
 
+// only touches the N and R factors, Q is left to the SAI configuration
+pub fn conf_lcd_pll(r : u8, n : u16) -> Result<(), RccError> {
+    match r {
+        x if x < 2 => return Err(RccError::PllSaiROutOfRange(r)),
+        x if x > 7 => return Err(RccError::PllSaiROutOfRange(r)),
+        _ => (),
+    };
+
+    match n {
+        x if x < 50 => return Err(RccError::PllSaiNOutOfRange(n)),
+        x if x > 432 => return Err(RccError::PllSaiNOutOfRange(n)),
+        _ => (),
+    };
+
+    let rcc = unsafe {&*RCC::ptr()};
+    rcc.pllsaicfgr.modify(|_, w| unsafe {
+        w.pllsair().bits(r)
+         .pllsain().bits(n)
+    });
+
+    Ok(())
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 pub enum TimMul {
     Time2,
@@ -884,11 +946,9 @@ pub enum TimMul {
 
 pub fn set_timers_freq(m : TimMul) {
     let rcc = unsafe {&*RCC::ptr()};
-    rcc.dckcfgr.modify(|_, w| {
-        match m {
-            TimMul::Time2 => w.timpre().bit(false),
-            TimMul::Time4 => w.timpre().bit(true),
-        }
+    bitband::write(&rcc.dckcfgr, 24, match m {
+        TimMul::Time2 => false,
+        TimMul::Time4 => true,
     });
 }
 
@@ -932,9 +992,9 @@ pub fn conf_lcd_pllsai_divr(d : LcdPllSaiDiv) {
     });
 }
 
-pub fn conf_sai1_pllsai_divq(d : u8) -> Result<(), ()>{
+pub fn conf_sai1_pllsai_divq(d : u8) -> Result<(), RccError> {
     if d > 32 || d < 1 {
-        return Err(())
+        return Err(RccError::PllSaiDivQOutOfRange(d))
     }
     let d = d - 1;
     let rcc = unsafe {&*RCC::ptr()};
@@ -944,9 +1004,9 @@ pub fn conf_sai1_pllsai_divq(d : u8) -> Result<(), ()>{
     Ok(())
 }
 
-pub fn conf_i2s_pllsai_divq(d : u8) -> Result<(), ()> {
+pub fn conf_i2s_pllsai_divq(d : u8) -> Result<(), RccError> {
     if d > 32 || d < 1 {
-        return Err(())
+        return Err(RccError::PllI2sDivQOutOfRange(d))
     }
     let d = d - 1;
     let rcc = unsafe {&*RCC::ptr()};
@@ -955,3 +1015,24 @@ pub fn conf_i2s_pllsai_divq(d : u8) -> Result<(), ()> {
     });
     Ok(())
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Clock frequencies decoding, the equivalent of RCC_GetClocksFreq in the SPL.
+
+pub use spl_rs::clk_decode::{HSE_VALUE, LSE_VALUE, RccSnapshot, ClockFreqs, decode_clocks};
+
+pub fn read_snapshot() -> RccSnapshot {
+    let rcc = unsafe {&*RCC::ptr()};
+    RccSnapshot {
+        cr          : rcc.cr.read().bits(),
+        pllcfgr     : rcc.pllcfgr.read().bits(),
+        cfgr        : rcc.cfgr.read().bits(),
+        dckcfgr     : rcc.dckcfgr.read().bits(),
+        pllsaicfgr  : rcc.pllsaicfgr.read().bits(),
+        plli2scfgr  : rcc.plli2scfgr.read().bits(),
+    }
+}
+
+pub fn get_clocks_freq() -> ClockFreqs {
+    decode_clocks(&read_snapshot(), HSE_VALUE)
+}
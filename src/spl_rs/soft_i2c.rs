@@ -0,0 +1,235 @@
+use hal::digital::{InputPin, OutputPin};
+use hal::blocking::i2c;
+
+use spl_rs::delay::{self, CycleDelay};
+
+// Bit-banged I2C master, for buses whose pins have no I2C peripheral. Both
+// lines must be open-drain outputs with a pull-up, driving a pin high only
+// releases the line. Addresses are 7 bit. Slaves may stretch the clock up to
+// STRETCH_TIMEOUT_US.
+
+const STRETCH_TIMEOUT_US : u32 = 25_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum I2cError {
+    // no slave acknowledged the address
+    AddrNack,
+    DataNack,
+    // sda read low while we released it, another master is talking
+    ArbitrationLost,
+    // scl held low longer than the stretch timeout
+    Timeout,
+    // a 0 Hz bus was asked for
+    BitRateUnreachable(u32),
+}
+
+pub struct SoftI2c<SCL, SDA> {
+    scl             : SCL,
+    sda             : SDA,
+    half_period     : u32,
+    stretch_cycles  : u32,
+}
+
+impl<SCL, SDA> SoftI2c<SCL, SDA>
+    where SCL : OutputPin + InputPin, SDA : OutputPin + InputPin {
+    pub fn new(mut scl : SCL, mut sda : SDA, freq : u32, delay : CycleDelay)
+        -> Result<SoftI2c<SCL, SDA>, I2cError> {
+        if freq == 0 {
+            return Err(I2cError::BitRateUnreachable(freq));
+        }
+        scl.set_high();
+        sda.set_high();
+        Ok(SoftI2c {
+            scl             : scl,
+            sda             : sda,
+            half_period     : delay.hclk() / freq.saturating_mul(2),
+            stretch_cycles  : delay.ns_to_cycles(STRETCH_TIMEOUT_US * 1000),
+        })
+    }
+
+    pub fn free(self) -> (SCL, SDA) {
+        (self.scl, self.sda)
+    }
+
+    fn wait(&self) {
+        delay::delay_cycles(self.half_period);
+    }
+
+    // release scl and give the slave a chance to stretch it
+    fn scl_high(&mut self) -> Result<(), I2cError> {
+        self.scl.set_high();
+        let start = delay::cycle_count();
+        while self.scl.is_low() {
+            if delay::cycle_count().wrapping_sub(start) > self.stretch_cycles {
+                return Err(I2cError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), I2cError> {
+        self.sda.set_high();
+        self.scl_high()?;
+        self.wait();
+        if self.sda.is_low() {
+            return Err(I2cError::ArbitrationLost);
+        }
+        self.sda.set_low();
+        self.wait();
+        self.scl.set_low();
+        Ok(())
+    }
+
+    // start condition without a stop, scl is low on entry
+    fn restart(&mut self) -> Result<(), I2cError> {
+        self.sda.set_high();
+        self.wait();
+        self.start()
+    }
+
+    fn stop(&mut self) -> Result<(), I2cError> {
+        self.sda.set_low();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.sda.set_high();
+        self.wait();
+        if self.sda.is_low() {
+            return Err(I2cError::ArbitrationLost);
+        }
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit : bool) -> Result<(), I2cError> {
+        if bit { self.sda.set_high(); } else { self.sda.set_low(); }
+        self.wait();
+        self.scl_high()?;
+        if bit && self.sda.is_low() {
+            return Err(I2cError::ArbitrationLost);
+        }
+        self.wait();
+        self.scl.set_low();
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, I2cError> {
+        self.sda.set_high();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        let bit = self.sda.is_high();
+        self.scl.set_low();
+        Ok(bit)
+    }
+
+    // true when the byte was acknowledged
+    fn write_byte(&mut self, byte : u8) -> Result<bool, I2cError> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+        Ok(!self.read_bit()?)
+    }
+
+    fn read_byte(&mut self, ack : bool) -> Result<u8, I2cError> {
+        let mut byte = 0u8;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.read_bit()? as u8;
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    fn address(&mut self, addr : u8, read : bool) -> Result<(), I2cError> {
+        if self.write_byte((addr << 1) | read as u8)? {
+            Ok(())
+        } else {
+            Err(I2cError::AddrNack)
+        }
+    }
+
+    fn write_bytes(&mut self, bytes : &[u8]) -> Result<(), I2cError> {
+        for b in bytes.iter() {
+            if !self.write_byte(*b)? {
+                return Err(I2cError::DataNack);
+            }
+        }
+        Ok(())
+    }
+
+    // the last byte is not acknowledged, that ends the slave transmission
+    fn read_bytes(&mut self, buffer : &mut [u8]) -> Result<(), I2cError> {
+        let len = buffer.len();
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = self.read_byte(i + 1 < len)?;
+        }
+        Ok(())
+    }
+
+    fn start_write(&mut self, addr : u8, bytes : &[u8]) -> Result<(), I2cError> {
+        self.start()?;
+        self.address(addr, false)?;
+        self.write_bytes(bytes)
+    }
+
+    fn start_read(&mut self, addr : u8, buffer : &mut [u8]) -> Result<(), I2cError> {
+        self.start()?;
+        self.address(addr, true)?;
+        self.read_bytes(buffer)
+    }
+
+    fn start_write_read(&mut self, addr : u8, bytes : &[u8], buffer : &mut [u8])
+        -> Result<(), I2cError> {
+        self.start_write(addr, bytes)?;
+        self.restart()?;
+        self.address(addr, true)?;
+        self.read_bytes(buffer)
+    }
+
+    // send a stop after a failed transfer, unless the bus belongs to
+    // another master now
+    fn finish(&mut self, res : Result<(), I2cError>) -> Result<(), I2cError> {
+        match res {
+            Err(I2cError::ArbitrationLost) => {
+                self.scl.set_high();
+                self.sda.set_high();
+                res
+            },
+            Err(e) => {
+                let _ = self.stop();
+                Err(e)
+            },
+            Ok(()) => self.stop(),
+        }
+    }
+}
+
+impl<SCL, SDA> i2c::Write for SoftI2c<SCL, SDA>
+    where SCL : OutputPin + InputPin, SDA : OutputPin + InputPin {
+    type Error = I2cError;
+
+    fn write(&mut self, addr : u8, bytes : &[u8]) -> Result<(), I2cError> {
+        let res = self.start_write(addr, bytes);
+        self.finish(res)
+    }
+}
+
+impl<SCL, SDA> i2c::Read for SoftI2c<SCL, SDA>
+    where SCL : OutputPin + InputPin, SDA : OutputPin + InputPin {
+    type Error = I2cError;
+
+    fn read(&mut self, addr : u8, buffer : &mut [u8]) -> Result<(), I2cError> {
+        let res = self.start_read(addr, buffer);
+        self.finish(res)
+    }
+}
+
+impl<SCL, SDA> i2c::WriteRead for SoftI2c<SCL, SDA>
+    where SCL : OutputPin + InputPin, SDA : OutputPin + InputPin {
+    type Error = I2cError;
+
+    // register style access, the read follows a repeated start
+    fn write_read(&mut self, addr : u8, bytes : &[u8], buffer : &mut [u8]) -> Result<(), I2cError> {
+        let res = self.start_write_read(addr, bytes, buffer);
+        self.finish(res)
+    }
+}
@@ -0,0 +1,140 @@
+use hal::digital::{InputPin, OutputPin};
+use hal::blocking::spi;
+use hal::spi::{Mode, Phase, Polarity};
+
+use spl_rs::delay::{self, CycleDelay};
+use spl_rs::spi::SpiError;
+
+// Bit-banged SPI master, MSB first, 8 bit words. The chip select is left to
+// the caller. With CPHA 0 the data is sampled on the leading clock edge and
+// shifted on the trailing one, CPHA 1 is the other way round. The transfers
+// mirror the ones of spi::Spi, they can't fail though.
+
+pub struct SoftSpi<SCK, MOSI, MISO> {
+    sck             : SCK,
+    mosi            : MOSI,
+    miso            : MISO,
+    mode            : Mode,
+    half_period     : u32,
+}
+
+impl<SCK, MOSI, MISO> SoftSpi<SCK, MOSI, MISO>
+    where SCK : OutputPin, MOSI : OutputPin, MISO : InputPin {
+    // `freq` is an upper bound, above hclk / 2 the clock runs as fast as the
+    // pins can be toggled
+    pub fn new(sck : SCK, mosi : MOSI, miso : MISO, mode : Mode, freq : u32, delay : CycleDelay)
+        -> Result<SoftSpi<SCK, MOSI, MISO>, SpiError> {
+        if freq == 0 {
+            return Err(SpiError::BitRateUnreachable(freq));
+        }
+        let mut spi = SoftSpi {
+            sck             : sck,
+            mosi            : mosi,
+            miso            : miso,
+            mode            : mode,
+            half_period     : delay.hclk() / freq.saturating_mul(2),
+        };
+        spi.sck_idle();
+        Ok(spi)
+    }
+
+    pub fn free(self) -> (SCK, MOSI, MISO) {
+        (self.sck, self.mosi, self.miso)
+    }
+
+    // takes effect on the next word, change it with the slave deselected
+    pub fn set_mode(&mut self, mode : Mode) {
+        self.mode = mode;
+        self.sck_idle();
+    }
+
+    fn wait(&self) {
+        delay::delay_cycles(self.half_period);
+    }
+
+    fn sck_idle(&mut self) {
+        match self.mode.polarity {
+            Polarity::IdleLow => self.sck.set_low(),
+            Polarity::IdleHigh => self.sck.set_high(),
+        }
+    }
+
+    fn sck_active(&mut self) {
+        match self.mode.polarity {
+            Polarity::IdleLow => self.sck.set_high(),
+            Polarity::IdleHigh => self.sck.set_low(),
+        }
+    }
+
+    fn mosi(&mut self, bit : bool) {
+        if bit { self.mosi.set_high(); } else { self.mosi.set_low(); }
+    }
+
+    pub fn transfer_byte(&mut self, out : u8) -> u8 {
+        let mut byte = 0u8;
+        for i in (0..8).rev() {
+            let bit = out & (1 << i) != 0;
+            match self.mode.phase {
+                Phase::CaptureOnFirstTransition => {
+                    self.mosi(bit);
+                    self.wait();
+                    self.sck_active();
+                    byte = (byte << 1) | self.miso.is_high() as u8;
+                    self.wait();
+                    self.sck_idle();
+                },
+                Phase::CaptureOnSecondTransition => {
+                    self.sck_active();
+                    self.mosi(bit);
+                    self.wait();
+                    self.sck_idle();
+                    byte = (byte << 1) | self.miso.is_high() as u8;
+                    self.wait();
+                },
+            }
+        }
+        byte
+    }
+
+    // full duplex, the received words replace the buffer content
+    pub fn transfer(&mut self, words : &mut [u8]) -> Result<(), SpiError> {
+        for w in words.iter_mut() {
+            *w = self.transfer_byte(*w);
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, words : &[u8]) -> Result<(), SpiError> {
+        for w in words.iter() {
+            let _ = self.transfer_byte(*w);
+        }
+        Ok(())
+    }
+
+    // clocks out `fill` for every word read
+    pub fn read(&mut self, words : &mut [u8], fill : u8) -> Result<(), SpiError> {
+        for w in words.iter_mut() {
+            *w = self.transfer_byte(fill);
+        }
+        Ok(())
+    }
+}
+
+impl<SCK, MOSI, MISO> spi::Transfer<u8> for SoftSpi<SCK, MOSI, MISO>
+    where SCK : OutputPin, MOSI : OutputPin, MISO : InputPin {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        SoftSpi::transfer(self, words)?;
+        Ok(words)
+    }
+}
+
+impl<SCK, MOSI, MISO> spi::Write<u8> for SoftSpi<SCK, MOSI, MISO>
+    where SCK : OutputPin, MOSI : OutputPin, MISO : InputPin {
+    type Error = SpiError;
+
+    fn write(&mut self, words : &[u8]) -> Result<(), SpiError> {
+        SoftSpi::write(self, words)
+    }
+}
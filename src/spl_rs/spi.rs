@@ -1,45 +1,166 @@
-use stm32f429::{SPI1, SPI2, SPI3, SPI4, SPI5};
+use stm32f429::{SPI1, SPI2, SPI3, SPI4, SPI5, SPI6};
+use stm32f429::spi1::RegisterBlock;
+use hal::blocking::spi;
 
+use spl_rs::{rcc, clk_gate, bitband, dma};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+
+// Polled SPI driver. The builder collects the settings, configure() enables
+// the clock and writes CR1/CR2. Transfers are full duplex, a byte is read
+// back for every byte written, so bidimode and rx_only are only useful to
+// code driving the registers itself.
+
+const CLK_OWNER : &'static str = "spi";
+
+// polls of SR before a transfer is declared stuck
+const SPI_TIMEOUT : u32 = 0x10000;
+
+// SR bits
+const RXNE      : u32 = 1 << 0;
+const TXE       : u32 = 1 << 1;
+const CRCERR    : u32 = 1 << 4;
+const MODF      : u32 = 1 << 5;
+const OVR       : u32 = 1 << 6;
+const BSY       : u32 = 1 << 7;
+
+// CR2 bits
+const CR2_RXDMAEN   : u8 = 0;
+const CR2_TXDMAEN   : u8 = 1;
+
+// CR1 bits
+const CR1_SPE       : u8 = 6;
+const CR1_CRCEN     : u8 = 13;
+const CR1_CRCNEXT   : u8 = 12;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpiPeriph {
+    Spi1,
+    Spi2,
+    Spi3,
+    Spi4,
+    Spi5,
+    Spi6,
+}
+
+impl SpiPeriph {
+    // all the instances share the SPI1 register layout
+    pub fn regs(&self) -> &'static RegisterBlock {
+        let ptr = match *self {
+            SpiPeriph::Spi1 => SPI1::ptr() as *const RegisterBlock,
+            SpiPeriph::Spi2 => SPI2::ptr() as *const RegisterBlock,
+            SpiPeriph::Spi3 => SPI3::ptr() as *const RegisterBlock,
+            SpiPeriph::Spi4 => SPI4::ptr() as *const RegisterBlock,
+            SpiPeriph::Spi5 => SPI5::ptr() as *const RegisterBlock,
+            SpiPeriph::Spi6 => SPI6::ptr() as *const RegisterBlock,
+        };
+        unsafe { &*ptr }
+    }
+
+    pub fn gate(&self) -> Gate {
+        match *self {
+            SpiPeriph::Spi1 => Gate::Apb2(rcc::Apb2Enable::SPI1),
+            SpiPeriph::Spi2 => Gate::Apb1(rcc::Apb1Enable::SPI2),
+            SpiPeriph::Spi3 => Gate::Apb1(rcc::Apb1Enable::SPI3),
+            SpiPeriph::Spi4 => Gate::Apb2(rcc::Apb2Enable::SPI4),
+            SpiPeriph::Spi5 => Gate::Apb2(rcc::Apb2Enable::SPI5),
+            SpiPeriph::Spi6 => Gate::Apb2(rcc::Apb2Enable::SPI6),
+        }
+    }
+
+    // DMA2 rx stream, tx stream and channel serving the instance
+    pub fn dma_streams(&self) -> Option<(u8, u8, u8)> {
+        match *self {
+            SpiPeriph::Spi5 => Some((3, 4, 2)),
+            _ => None,
+        }
+    }
+
+    // clock the baud rate divider is fed from
+    pub fn bus_clk(&self) -> u32 {
+        let freqs = rcc::get_clocks_freq();
+        match *self {
+            SpiPeriph::Spi2 | SpiPeriph::Spi3 => freqs.pclk1,
+            _ => freqs.pclk2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpiError {
+    NoPeriph,
+    // even the largest divider gives a faster clock than requested
+    BitRateUnreachable(u32),
+    // the frame size of the call doesn't match the configuration
+    FrameMismatch,
+    Overrun,
+    ModeFault,
+    Crc,
+    Timeout,
+    // no stream for the instance, crc enabled or more than 65535 frames
+    DmaUnsupported,
+    DmaTransferError,
+    Clock(ClkGateError),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum DataFrameFormat {
     Frame8Bits = 0,
     Frame16Bits = 1,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ClkPhaCfg {
     CphaFirst,
     CphaSecond,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ClkPolCfg {
     CpolLow,
     CpolHigh,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum BaudRateDivider {
     DivBy2 = 0,
-    DivBy2 = 1,
-    DivBy2 = 2,
-    DivBy2 = 3,
-    DivBy2 = 4,
-    DivBy2 = 5,
-    DivBy2 = 6,
-    DivBy2 = 7,
+    DivBy4 = 1,
+    DivBy8 = 2,
+    DivBy16 = 3,
+    DivBy32 = 4,
+    DivBy64 = 5,
+    DivBy128 = 6,
+    DivBy256 = 7,
+}
+
+// smallest divider keeping the spi clock at or below `bit_rate`
+pub fn baudrate_div(bus_clk : u32, bit_rate : u32) -> Result<u8, SpiError> {
+    let mut br = 0;
+    while (bus_clk >> (br + 1)) > bit_rate {
+        if br == 0b111 {
+            return Err(SpiError::BitRateUnreachable(bit_rate));
+        }
+        br += 1;
+    }
+    Ok(br)
 }
 
+#[derive(Copy, Clone)]
 pub struct SpiBuilder {
     bidimode_en         : bool,
     bidioe_en           : bool,
     crc_en              : bool,
-    crc_next            : bool,
+    crc_polynomial      : u16,
     data_frame          : DataFrameFormat,
     rx_only             : bool,
     sw_slave_mgmt       : bool,
     lsb_first           : bool,
     baudrate_freq_div   : u8,
+    // takes precedence over baudrate_freq_div
+    bit_rate            : Option<u32>,
     clock_polarity      : ClkPolCfg,
     clock_edge          : ClkPhaCfg,
     master              : bool,
-    spi_periph          : Option<stm32f429::spi1::RegisterBlock>,
+    spi_periph          : Option<SpiPeriph>,
 }
 
 impl SpiBuilder {
@@ -48,11 +169,13 @@ impl SpiBuilder {
             bidimode_en         : false,
             bidioe_en           : false,
             crc_en              : false,
+            crc_polynomial      : 7,
             data_frame          : DataFrameFormat::Frame8Bits,
             rx_only             : false,
             sw_slave_mgmt       : false,
             lsb_first           : false,
             baudrate_freq_div   : 2,
+            bit_rate            : None,
             clock_polarity      : ClkPolCfg::CpolHigh,
             clock_edge          : ClkPhaCfg::CphaFirst,
             master              : true,
@@ -60,89 +183,523 @@ impl SpiBuilder {
         }
     }
 
-    pub fn bidimode(self, en : bool) -> SpiBuilder {
+    pub fn bidimode(mut self, en : bool) -> SpiBuilder {
         self.bidimode_en = en;
         self
     }
 
-    pub fn bidioe(self, en : bool) -> SpiBuilder {
+    pub fn bidioe(mut self, en : bool) -> SpiBuilder {
         self.bidioe_en = en;
         self
     }
 
-    pub fn crc(self, en : bool) -> SpiBuilder {
+    pub fn crc(mut self, en : bool) -> SpiBuilder {
         self.crc_en = en;
         self
     }
 
-    pub fn data_frame_length(self, dff : DataFrameFormat) -> SpiBuilder {
+    pub fn crc_polynomial(mut self, p : u16) -> SpiBuilder {
+        self.crc_polynomial = p;
+        self
+    }
+
+    pub fn data_frame_length(mut self, dff : DataFrameFormat) -> SpiBuilder {
         self.data_frame = dff;
         self
     }
 
-    pub fn rx_only(self, en : bool) -> SpiBuilder {
+    pub fn rx_only(mut self, en : bool) -> SpiBuilder {
         self.rx_only = en;
         self
     }
 
-    pub fn sw_slave_mgmt(self, en : bool) -> SpiBuilder {
+    pub fn sw_slave_mgmt(mut self, en : bool) -> SpiBuilder {
         self.sw_slave_mgmt = en;
         self
     }
 
-    pub fn lsb_first(self, en : bool) -> SpiBuilder {
+    pub fn lsb_first(mut self, en : bool) -> SpiBuilder {
         self.lsb_first = en;
         self
     }
 
-    pub fn baudrate_freq_div(self, div : BaudRateDivider) -> SpiBuilder {
+    pub fn baudrate_freq_div(mut self, div : BaudRateDivider) -> SpiBuilder {
         self.baudrate_freq_div = div as u8;
+        self.bit_rate = None;
         self
     }
 
-    pub fn clock_polarity(self, cpol : ClkPolCfg) -> SpiBuilder {
+    // highest acceptable spi clock, the divider is picked from the bus
+    // clock when configuring
+    pub fn bit_rate(mut self, freq : u32) -> SpiBuilder {
+        self.bit_rate = Some(freq);
+        self
+    }
+
+    pub fn clock_polarity(mut self, cpol : ClkPolCfg) -> SpiBuilder {
         self.clock_polarity = cpol;
         self
     }
 
-    pub fn clock_edge(self, cpha : ClkPhaCfg) -> SpiBuilder {
+    pub fn clock_edge(mut self, cpha : ClkPhaCfg) -> SpiBuilder {
         self.clock_edge = cpha;
         self
     }
 
-    pub fn master(self, en : bool) -> SpiBuilder {
+    pub fn master(mut self, en : bool) -> SpiBuilder {
         self.master = en;
         self
     }
 
-    pub fn spi_periph(self, sp : stm32f429::spi1::RegisterBlock) -> SpiBuilder {
+    pub fn spi_periph(mut self, sp : SpiPeriph) -> SpiBuilder {
         self.spi_periph = Some(sp);
         self
     }
 
-    pub fn configure(self) -> Spi {
-        Spi {self}
+    fn cr1(&self, br : u8) -> u32 {
+        (self.bidimode_en as u32) << 15
+            | (self.bidioe_en as u32) << 14
+            | (self.crc_en as u32) << 13
+            | (self.data_frame as u32) << 11
+            | (self.rx_only as u32) << 10
+            | (self.sw_slave_mgmt as u32) << 9
+            // with software slave management a master must see NSS high
+            | ((self.sw_slave_mgmt && self.master) as u32) << 8
+            | (self.lsb_first as u32) << 7
+            | (br as u32) << 3
+            | (self.master as u32) << 2
+            | ((self.clock_polarity == ClkPolCfg::CpolHigh) as u32) << 1
+            | (self.clock_edge == ClkPhaCfg::CphaSecond) as u32
+    }
+
+    fn cr2(&self) -> u32 {
+        // a master drives NSS itself unless it is managed by software
+        ((self.master && !self.sw_slave_mgmt) as u32) << 2
+    }
+
+    pub fn configure(self) -> Result<Spi, SpiError> {
+        let periph = match self.spi_periph {
+            Some(p) => p,
+            None => return Err(SpiError::NoPeriph),
+        };
+        // nothing is held if the bit rate can't be reached
+        let br = match self.bit_rate {
+            Some(f) => baudrate_div(periph.bus_clk(), f)?,
+            None => self.baudrate_freq_div,
+        };
+        clk_gate::acquire(periph.gate(), CLK_OWNER).map_err(SpiError::Clock)?;
+
+        let spi = Spi {
+            periph  : periph,
+            cfg     : self,
+            cr1     : self.cr1(br),
+            cr2     : self.cr2(),
+        };
+        spi.reconfigure();
+        Ok(spi)
     }
 }
 
+// Frame sizes accepted by the transfers
+pub trait Frame : Copy {
+    const FORMAT : DataFrameFormat;
+    const WIDTH : dma::Width;
+    fn to_bits(self) -> u32;
+    fn from_bits(bits : u32) -> Self;
+}
+
+impl Frame for u8 {
+    const FORMAT : DataFrameFormat = DataFrameFormat::Frame8Bits;
+    const WIDTH : dma::Width = dma::Width::Byte;
+    fn to_bits(self) -> u32 { self as u32 }
+    fn from_bits(bits : u32) -> u8 { bits as u8 }
+}
+
+impl Frame for u16 {
+    const FORMAT : DataFrameFormat = DataFrameFormat::Frame16Bits;
+    const WIDTH : dma::Width = dma::Width::HalfWord;
+    fn to_bits(self) -> u32 { self as u32 }
+    fn from_bits(bits : u32) -> u16 { bits as u16 }
+}
+
 pub struct Spi {
-    spi_cfg : SpiBuilder,
+    periph  : SpiPeriph,
+    cfg     : SpiBuilder,
+    cr1     : u32,
+    cr2     : u32,
 }
 
 impl Spi {
-    pub fn read(&self, reg : u8, buf : &mut u16) -> Result<(), ()> {
-        Ok(())
+    pub fn periph(&self) -> SpiPeriph {
+        self.periph
     }
 
-    // write a complete buffer to the peripheral. Because some peripherals need a 16 bits wide
-    // frame, we directly use a 16 bits buffer.
-    pub fn write(&self, reg : u8, data : &[u16]) -> Result<(), ()>{
-        Ok(())
+    // actual spi clock with the current bus clock
+    pub fn bit_rate(&self) -> u32 {
+        self.periph.bus_clk() >> (((self.cr1 >> 3) & 0b111) + 1)
     }
 
     // If another peripheral needs another configuration for the same spi, a call to this
     // method reconfigures the corresponding spi to the last used parameters.
     pub fn reconfigure(&self) {
+        let spi = self.periph.regs();
+        // let a frame still on the wire finish before touching the config
+        let _ = self.wait_idle();
+        spi.cr1.write(|w| unsafe{w.bits(self.cr1)});
+        spi.cr2.write(|w| unsafe{w.bits(self.cr2)});
+        if self.cfg.crc_en {
+            spi.crcpr.write(|w| unsafe{w.bits(self.cfg.crc_polynomial as u32)});
+        }
+        bitband::write(&spi.cr1, CR1_SPE, true);
+    }
+
+    // pick a new divider after a bus clock change
+    pub fn set_bit_rate(&mut self, freq : u32) -> Result<(), SpiError> {
+        let br = baudrate_div(self.periph.bus_clk(), freq)?;
+        self.cr1 = (self.cr1 & !(0b111 << 3)) | ((br as u32) << 3);
+        self.reconfigure();
+        Ok(())
+    }
+
+    pub fn disable(&self) {
+        let _ = self.wait_idle();
+        bitband::write(&self.periph.regs().cr1, CR1_SPE, false);
+    }
+
+    // Disable the instance and drop the clock hold of configure(). The hold
+    // is shared by every Spi configured on the instance.
+    pub fn release(self) -> Result<(), ClkGateError> {
+        self.disable();
+        clk_gate::release(self.periph.gate(), CLK_OWNER)
+    }
+
+    // Read SR, clearing and reporting the error flags. A mode fault also
+    // disables the peripheral, it is restored with the saved config.
+    fn check(&self) -> Result<u32, SpiError> {
+        let spi = self.periph.regs();
+        let sr = spi.sr.read().bits();
+        if sr & MODF != 0 {
+            spi.cr1.write(|w| unsafe{w.bits(self.cr1)});
+            bitband::write(&spi.cr1, CR1_SPE, true);
+            return Err(SpiError::ModeFault);
+        }
+        if sr & OVR != 0 {
+            let _ = spi.dr.read().bits();
+            let _ = spi.sr.read().bits();
+            return Err(SpiError::Overrun);
+        }
+        if sr & CRCERR != 0 {
+            spi.sr.write(|w| unsafe{w.bits(!CRCERR & 0xFFFF)});
+            return Err(SpiError::Crc);
+        }
+        Ok(sr)
+    }
+
+    fn wait(&self, flag : u32) -> Result<(), SpiError> {
+        let mut t = SPI_TIMEOUT;
+        while self.check()? & flag == 0 {
+            if t == 0 {
+                return Err(SpiError::Timeout);
+            }
+            t -= 1;
+        }
+        Ok(())
+    }
+
+    fn wait_idle(&self) -> Result<(), SpiError> {
+        let mut t = SPI_TIMEOUT;
+        while self.periph.regs().sr.read().bits() & BSY != 0 {
+            if t == 0 {
+                return Err(SpiError::Timeout);
+            }
+            t -= 1;
+        }
+        Ok(())
+    }
+
+    fn reset_crc(&self) {
+        let spi = self.periph.regs();
+        bitband::write(&spi.cr1, CR1_SPE, false);
+        bitband::write(&spi.cr1, CR1_CRCEN, false);
+        bitband::write(&spi.cr1, CR1_CRCEN, true);
+        bitband::write(&spi.cr1, CR1_SPE, true);
+    }
+
+    fn exchange(&self, out : u32, last : bool) -> Result<u32, SpiError> {
+        let spi = self.periph.regs();
+        self.wait(TXE)?;
+        spi.dr.write(|w| unsafe{w.bits(out)});
+        // the crc goes out right after the last frame
+        if last && self.cfg.crc_en {
+            bitband::write(&spi.cr1, CR1_CRCNEXT, true);
+        }
+        self.wait(RXNE)?;
+        Ok(spi.dr.read().bits())
+    }
+
+    // receive the crc of the other side, CRCERR is set if it doesn't match
+    fn finish(&self) -> Result<(), SpiError> {
+        if self.cfg.crc_en {
+            self.wait(RXNE)?;
+            let _ = self.periph.regs().dr.read().bits();
+        }
+        self.wait_idle()?;
+        self.check().map(|_| ())
+    }
+
+    fn start<W : Frame>(&self, len : usize) -> Result<bool, SpiError> {
+        if W::FORMAT != self.cfg.data_frame {
+            return Err(SpiError::FrameMismatch);
+        }
+        if len == 0 {
+            return Ok(false);
+        }
+        if self.cfg.crc_en {
+            self.reset_crc();
+        }
+        // drop a frame left over by an earlier failure
+        let _ = self.check();
+        Ok(true)
+    }
+
+    // full duplex, every word is replaced with the one received
+    pub fn transfer<W : Frame>(&self, words : &mut [W]) -> Result<(), SpiError> {
+        if !self.start::<W>(words.len())? {
+            return Ok(());
+        }
+        let len = words.len();
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = W::from_bits(self.exchange(w.to_bits(), i + 1 == len)?);
+        }
+        self.finish()
+    }
+
+    pub fn write<W : Frame>(&self, words : &[W]) -> Result<(), SpiError> {
+        if !self.start::<W>(words.len())? {
+            return Ok(());
+        }
+        let len = words.len();
+        for (i, w) in words.iter().enumerate() {
+            let _ = self.exchange(w.to_bits(), i + 1 == len)?;
+        }
+        self.finish()
+    }
+
+    // clocks out `fill` for every word read
+    pub fn read<W : Frame>(&self, words : &mut [W], fill : W) -> Result<(), SpiError> {
+        if !self.start::<W>(words.len())? {
+            return Ok(());
+        }
+        let len = words.len();
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = W::from_bits(self.exchange(fill.to_bits(), i + 1 == len)?);
+        }
+        self.finish()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+// DMA transfers
+/////////////////////////////////////////////////////////////////////////////////
+
+// Background transfers on the DMA2 streams of the instance. The Spi and the
+// buffer are moved into the returned handle and given back by wait(), so
+// nothing touches them while the DMA does. abort() stops the streams and
+// gives them back at once, dropping the handle stops the streams too. Buffers must not be in the CCM
+// RAM, DMA2 can't reach it. The callback runs in interrupt context once the
+// last frame is received.
+
+// frame clocked out by read_dma and frame written to by write_dma, one pair
+// per instance
+static mut FILL : [u16; 6] = [0; 6];
+static mut SINK : [u16; 6] = [0; 6];
+
+#[must_use]
+pub struct DmaTransfer<B> {
+    // only taken by wait and abort, which consume the handle
+    inner   : Option<(Spi, B)>,
+    len     : usize,
+}
+
+impl<B> DmaTransfer<B> {
+    pub fn is_done(&self) -> bool {
+        match self.inner {
+            Some((ref spi, _)) => {
+                let (rx, _, _) = spi.periph.dma_streams().unwrap();
+                !dma::is_enabled(rx)
+            },
+            None => true,
+        }
+    }
+
+    // block until the transfer ends, the buffer content is only meaningful
+    // when the result is Ok
+    pub fn wait(mut self) -> (Spi, B, Result<(), SpiError>) {
+        let (spi, buf) = self.inner.take().unwrap();
+        let (rx, tx, _) = spi.periph.dma_streams().unwrap();
+        let mut t = SPI_TIMEOUT.saturating_mul(self.len as u32);
+        let mut res = Ok(());
+        while dma::is_enabled(rx) {
+            if t == 0 {
+                res = Err(SpiError::Timeout);
+                break;
+            }
+            t -= 1;
+        }
+        let flags = dma::status(rx) | dma::status(tx);
+        if spi.end_dma(rx, tx).is_err() && res.is_ok() {
+            res = Err(SpiError::Timeout);
+        }
+
+        if res.is_ok() && flags & (dma::TEIF | dma::DMEIF) != 0 {
+            res = Err(SpiError::DmaTransferError);
+        }
+        if res.is_ok() {
+            res = spi.wait_idle().and_then(|_| spi.check().map(|_| ()));
+        }
+        (spi, buf, res)
+    }
+
+    // stop the streams without waiting, part of the frames may have gone
+    // through
+    pub fn abort(mut self) -> (Spi, B) {
+        let (spi, buf) = self.inner.take().unwrap();
+        let (rx, tx, _) = spi.periph.dma_streams().unwrap();
+        let _ = spi.end_dma(rx, tx);
+        (spi, buf)
+    }
+}
+
+impl<B> Drop for DmaTransfer<B> {
+    fn drop(&mut self) {
+        if let Some((ref spi, _)) = self.inner {
+            let (rx, tx, _) = spi.periph.dma_streams().unwrap();
+            let _ = spi.end_dma(rx, tx);
+        }
+    }
+}
+
+impl Spi {
+    // Err if a stream did not stop, the DMA2 clock is kept for it
+    fn end_dma(&self, rx : u8, tx : u8) -> Result<(), dma::DmaError> {
+        let spi = self.periph.regs();
+        let tx_res = dma::stop(tx);
+        let rx_res = dma::stop(rx);
+        bitband::write(&spi.cr2, CR2_TXDMAEN, false);
+        bitband::write(&spi.cr2, CR2_RXDMAEN, false);
+        dma::release();
+        tx_res.and(rx_res)
+    }
+
+    fn start_dma<W : Frame>(&self, tx_addr : u32, tx_inc : bool, rx_addr : u32, rx_inc : bool,
+                            len : usize, done : Option<fn()>) -> Result<(), SpiError> {
+        let (rx, tx, channel) = match self.periph.dma_streams() {
+            Some(s) => s,
+            None => return Err(SpiError::DmaUnsupported),
+        };
+        if W::FORMAT != self.cfg.data_frame {
+            return Err(SpiError::FrameMismatch);
+        }
+        if self.cfg.crc_en || len == 0 || len > 0xFFFF {
+            return Err(SpiError::DmaUnsupported);
+        }
+
+        let spi = self.periph.regs();
+        self.wait_idle()?;
+        // no stale frame may be taken for the first one received
+        let _ = spi.dr.read().bits();
+        let _ = self.check();
+
+        let dr = &spi.dr as *const _ as u32;
+        let stream = |dir, addr, inc, handler| dma::StreamConfig {
+            channel     : channel,
+            dir         : dir,
+            periph_addr : dr,
+            mem_addr    : addr,
+            len         : len as u16,
+            mem_inc     : inc,
+            width       : W::WIDTH,
+            handler     : handler,
+        };
+
+        // rx first so that no received frame is missed
+        bitband::write(&spi.cr2, CR2_RXDMAEN, true);
+        let started = dma::start(rx, &stream(dma::Direction::PeriphToMem, rx_addr, rx_inc, done))
+            .and_then(|_| dma::start(tx, &stream(dma::Direction::MemToPeriph, tx_addr, tx_inc, None)));
+        if started.is_err() {
+            let _ = self.end_dma(rx, tx);
+            return Err(SpiError::DmaUnsupported);
+        }
+        bitband::write(&spi.cr2, CR2_TXDMAEN, true);
+        Ok(())
+    }
+
+    // full duplex, the received frames replace the buffer content
+    pub fn transfer_dma<W : Frame>(self, buf : &'static mut [W], done : Option<fn()>)
+        -> Result<DmaTransfer<&'static mut [W]>, (Spi, &'static mut [W], SpiError)> {
+        let addr = buf.as_ptr() as u32;
+        let len = buf.len();
+        match self.start_dma::<W>(addr, true, addr, true, len, done) {
+            Ok(()) => Ok(DmaTransfer { inner : Some((self, buf)), len : len }),
+            Err(e) => Err((self, buf, e)),
+        }
+    }
+
+    pub fn write_dma<W : Frame>(self, buf : &'static [W], done : Option<fn()>)
+        -> Result<DmaTransfer<&'static [W]>, (Spi, &'static [W], SpiError)> {
+        let sink = unsafe { &SINK[self.periph as usize] as *const u16 as u32 };
+        let len = buf.len();
+        match self.start_dma::<W>(buf.as_ptr() as u32, true, sink, false, len, done) {
+            Ok(()) => Ok(DmaTransfer { inner : Some((self, buf)), len : len }),
+            Err(e) => Err((self, buf, e)),
+        }
+    }
+
+    // clocks out `fill` for every frame read
+    pub fn read_dma<W : Frame>(self, buf : &'static mut [W], fill : W, done : Option<fn()>)
+        -> Result<DmaTransfer<&'static mut [W]>, (Spi, &'static mut [W], SpiError)> {
+        let fill_addr = unsafe {
+            FILL[self.periph as usize] = fill.to_bits() as u16;
+            &FILL[self.periph as usize] as *const u16 as u32
+        };
+        let len = buf.len();
+        match self.start_dma::<W>(fill_addr, false, buf.as_ptr() as u32, true, len, done) {
+            Ok(()) => Ok(DmaTransfer { inner : Some((self, buf)), len : len }),
+            Err(e) => Err((self, buf, e)),
+        }
+    }
+}
+
+impl spi::Transfer<u8> for Spi {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        Spi::transfer(self, words)?;
+        Ok(words)
+    }
+}
+
+impl spi::Transfer<u16> for Spi {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u16]) -> Result<&'w [u16], SpiError> {
+        Spi::transfer(self, words)?;
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for Spi {
+    type Error = SpiError;
+
+    fn write(&mut self, words : &[u8]) -> Result<(), SpiError> {
+        Spi::write(self, words)
+    }
+}
+
+impl spi::Write<u16> for Spi {
+    type Error = SpiError;
 
+    fn write(&mut self, words : &[u16]) -> Result<(), SpiError> {
+        Spi::write(self, words)
     }
 }
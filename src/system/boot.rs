@@ -0,0 +1,192 @@
+use core::fmt;
+use core::fmt::Write;
+
+use stm32f429::{PWR, RTC};
+use cortex_m_semihosting::hio;
+
+use spl_rs::{rcc, clk_gate};
+use spl_rs::clk_gate::{Gate, ClkGateError};
+use spl_rs::rcc::ResetFlag;
+use bsp::lcd::lcd::Lcd;
+
+// Boot diagnostics. The reset flags of RCC_CSR are sticky until cleared, so
+// they are captured first thing after reset, reported, then cleared. A
+// reset counter is kept in the RTC backup registers, it survives every reset
+// as long as the backup domain stays powered.
+
+// BKP0R holds the magic telling the counter in BKP1R is valid
+const BKP_MAGIC : u32 = 0xB007_C0DE;
+const CLK_OWNER : &'static str = "boot";
+const LCD_LINE_LEN : usize = 32;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResetCause {
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    Software,
+    PowerOn,
+    BrownOut,
+    Pin,
+    Unknown,
+}
+
+impl ResetCause {
+    // Several flags are raised for a single reset: every internal reset
+    // also pulls NRST low so PIN_RST comes with all of them, and a power-on
+    // raises both POR_RST and BOR_RST. The most specific flag wins.
+    pub fn from_flags(f : ResetFlag) -> ResetCause {
+        if f.contains(ResetFlag::IWDG_RST) {
+            ResetCause::IndependentWatchdog
+        } else if f.contains(ResetFlag::WWDG_RST) {
+            ResetCause::WindowWatchdog
+        } else if f.contains(ResetFlag::LPWR_RST) {
+            ResetCause::LowPower
+        } else if f.contains(ResetFlag::SOFT_RST) {
+            ResetCause::Software
+        } else if f.contains(ResetFlag::POR_RST) {
+            ResetCause::PowerOn
+        } else if f.contains(ResetFlag::BOR_RST) {
+            ResetCause::BrownOut
+        } else if f.contains(ResetFlag::PIN_RST) {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ResetCause::IndependentWatchdog => "independent watchdog",
+            ResetCause::WindowWatchdog => "window watchdog",
+            ResetCause::LowPower => "low-power",
+            ResetCause::Software => "software",
+            ResetCause::PowerOn => "power-on",
+            ResetCause::BrownOut => "brown-out",
+            ResetCause::Pin => "pin",
+            ResetCause::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BootInfo {
+    pub flags       : ResetFlag,
+    pub cause       : ResetCause,
+    // number of resets since the backup domain was last powered up,
+    // including this one
+    pub reset_count : u32,
+}
+
+static mut INFO : Option<BootInfo> = None;
+
+// To be called once at startup, before anything clears the reset flags.
+// Bumps the reset counter, subsequent calls return the same info. Nothing is
+// captured if the backup domain can't be reached.
+pub fn capture() -> Result<BootInfo, ClkGateError> {
+    if let Some(info) = unsafe { INFO } {
+        return Ok(info);
+    }
+
+    let flags = rcc::get_reset_flag();
+    let info = BootInfo {
+        flags       : flags,
+        cause       : ResetCause::from_flags(flags),
+        reset_count : bump_reset_count()?,
+    };
+    unsafe {
+        INFO = Some(info);
+    }
+    Ok(info)
+}
+
+pub fn boot_info() -> Option<BootInfo> {
+    unsafe { INFO }
+}
+
+// clear the hardware flags so the next reset is reported on its own, the
+// captured info stays available
+pub fn clear_flags() {
+    rcc::clear_reset_flag();
+}
+
+fn bump_reset_count() -> Result<u32, ClkGateError> {
+    let pwr = unsafe {&*PWR::ptr()};
+    let rtc = unsafe {&*RTC::ptr()};
+
+    // backup registers are write protected until DBP is set
+    clk_gate::acquire(Gate::Apb1(rcc::Apb1Enable::PWR), CLK_OWNER)?;
+    pwr.cr.modify(|_, w| w.dbp().bit(true));
+
+    let count = if rtc.bkp0r.read().bits() == BKP_MAGIC {
+        rtc.bkp1r.read().bits().wrapping_add(1)
+    } else {
+        rtc.bkp0r.write(|w| unsafe {w.bits(BKP_MAGIC)});
+        1
+    };
+    rtc.bkp1r.write(|w| unsafe {w.bits(count)});
+
+    pwr.cr.modify(|_, w| w.dbp().bit(false));
+    let _ = clk_gate::release(Gate::Apb1(rcc::Apb1Enable::PWR), CLK_OWNER);
+    Ok(count)
+}
+
+pub fn write_report<W : fmt::Write>(w : &mut W, info : &BootInfo) -> fmt::Result {
+    writeln!(w, "reset cause : {}", info.cause.name())?;
+    writeln!(w, "reset flags : {:#010x}", info.flags.bits())?;
+    writeln!(w, "reset count : {}", info.reset_count)?;
+    let clocks = rcc::get_clocks_freq();
+    writeln!(w, "sysclk      : {} Hz", clocks.sysclk)
+}
+
+// only works with a debugger attached, the core halts otherwise
+pub fn report_semihosting(info : &BootInfo) -> Result<(), ()> {
+    let mut stdout = hio::hstdout()?;
+    write_report(&mut stdout, info).map_err(|_| ())
+}
+
+// print the report on the LCD, one line of text per report line starting at
+// text line `first_line`
+pub fn report_lcd(lcd : &Lcd, first_line : u16, info : &BootInfo) -> fmt::Result {
+    let mut out = LcdWriter {
+        lcd     : lcd,
+        line    : first_line,
+        buf     : [0; LCD_LINE_LEN],
+        len     : 0,
+    };
+    write_report(&mut out, info)
+}
+
+struct LcdWriter<'a> {
+    lcd     : &'a Lcd,
+    line    : u16,
+    buf     : [u8; LCD_LINE_LEN],
+    len     : usize,
+}
+
+impl<'a> LcdWriter<'a> {
+    fn flush_line(&mut self) {
+        let y = self.line * self.lcd.get_font().height;
+        // only ascii is ever written to the buffer
+        let s = unsafe { ::core::str::from_utf8_unchecked(&self.buf[..self.len]) };
+        self.lcd.clear_line(y);
+        self.lcd.display_string_line(y, s);
+        self.line += 1;
+        self.len = 0;
+    }
+}
+
+impl<'a> fmt::Write for LcdWriter<'a> {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.flush_line();
+            } else if self.len < LCD_LINE_LEN && c >= 0x20 && c < 0x7F {
+                // characters past the screen width are dropped
+                self.buf[self.len] = c;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,991 @@
+// Declarative description of the clock tree. Nothing in here touches a
+// register: a ClockConfig is solved into a frozen Clocks value which is
+// then applied by system::clks.
+
+pub const HSI_FREQ          : u32 = 16_000_000;
+
+const HSE_MIN               : u32 = 4_000_000;
+const HSE_MAX               : u32 = 26_000_000;
+
+const VCO_IN_MIN            : u32 = 1_000_000;
+const VCO_IN_MAX            : u32 = 2_000_000;
+const VCO_OUT_MIN           : u32 = 100_000_000;
+const VCO_OUT_MAX           : u32 = 432_000_000;
+
+const PLL_M_MIN             : u32 = 2;
+const PLL_M_MAX             : u32 = 63;
+const PLL_N_MIN             : u32 = 50;
+const PLL_N_MAX             : u32 = 432;
+const PLL_Q_MIN             : u32 = 2;
+const PLL_Q_MAX             : u32 = 15;
+const PLL_P_VALUES          : [u32; 4] = [2, 4, 6, 8];
+
+const PLL48_FREQ            : u32 = 48_000_000;
+
+const PLLSAI_R_MIN          : u32 = 2;
+const PLLSAI_R_MAX          : u32 = 7;
+const PLLSAI_DIVR_VALUES    : [u32; 4] = [2, 4, 8, 16];
+const PLLI2S_R_MIN          : u32 = 2;
+const PLLI2S_R_MAX          : u32 = 7;
+// maximum I2S kernel clock out of PLLI2S R
+const I2S_CLK_MAX           : u32 = 192_000_000;
+// I2S linear prescaler, 2 * I2SDIV + ODD with I2SDIV in 2..255
+const I2S_DIV_MIN           : u32 = 4;
+const I2S_DIV_MAX           : u32 = 511;
+const PLLSAI_Q_MIN          : u32 = 2;
+const PLLSAI_Q_MAX          : u32 = 15;
+const PLLSAI_DIVQ_MAX       : u32 = 32;
+// SAI master clock divider, 2 * MCKDIV with MCKDIV in 1..15, MCKDIV = 0
+// divides by 1
+const SAI_MCKDIV_MAX        : u32 = 15;
+const AUDIO_FS_MIN          : u32 = 8_000;
+const AUDIO_FS_MAX          : u32 = 192_000;
+// maximum LTDC pixel clock
+const LCD_CLK_MAX           : u32 = 42_000_000;
+
+// spread spectrum limits, RM0090 section 6.3.18. The depth is the peak
+// spread in hundredths of a percent.
+const SSCG_MOD_FREQ_MAX     : u32 = 10_000;
+const SSCG_DEPTH_MAX        : u16 = 200;
+const SSCG_MODPER_MAX       : u32 = 8191;
+const SSCG_INCSTEP_MAX      : u32 = 32767;
+
+const SYSCLK_MAX            : u32 = 180_000_000;
+// above this frequency the regulator has to run in over-drive mode
+const SYSCLK_MAX_NO_OD      : u32 = 168_000_000;
+
+// highest sysclk allowed by the regulator voltage scales 3 and 2
+const SYSCLK_MAX_SCALE3     : u32 = 120_000_000;
+const SYSCLK_MAX_SCALE2     : u32 = 144_000_000;
+
+const PCLK1_MAX             : u32 = 45_000_000;
+const PCLK2_MAX             : u32 = 90_000_000;
+const PCLK1_MAX_NO_OD       : u32 = 42_000_000;
+const PCLK2_MAX_NO_OD       : u32 = 84_000_000;
+
+const AHB_DIVIDERS          : [u32; 9] = [1, 2, 4, 8, 16, 64, 128, 256, 512];
+const APB_DIVIDERS          : [u32; 5] = [1, 2, 4, 8, 16];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClkSrc {
+    Hsi,
+    Hse(u32),
+}
+
+impl ClkSrc {
+    pub fn freq(&self) -> u32 {
+        match *self {
+            ClkSrc::Hsi => HSI_FREQ,
+            ClkSrc::Hse(f) => f,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ConfigError {
+    HseOutOfRange(u32),     // contains the requested hse frequency
+    SysClkOutOfRange(u32),  // contains the requested sysclk
+    HclkOutOfRange(u32),
+    Pclk1OutOfRange(u32),
+    Pclk2OutOfRange(u32),
+    PllUnreachable(u32),    // no M/N/P combination gives this sysclk
+    Pll48Unreachable,       // no Q divider gives exactly 48MHz
+    LcdClkUnreachable(u32), // contains the requested pixel clock
+    SscgModFreqOutOfRange(u32),
+    SscgDepthOutOfRange(u16),
+    SscgUnreachable,        // needs the PLL, or MODPER x INCSTEP overflows
+    AudioFsOutOfRange(u32), // contains the requested sample rate
+    AudioRatioUnsupported(u32),
+    AudioClkUnreachable(u32),
+}
+
+// supply voltage of the device, sets the flash access time
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SupplyRange {
+    V1_8To2_1,
+    V2_1To2_4,
+    V2_4To2_7,
+    V2_7To3_6,
+}
+
+// value of the VOS bits in PWR_CR
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VoltageScale {
+    Scale3 = 0b01,
+    Scale2 = 0b10,
+    Scale1 = 0b11,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PllConfig {
+    pub m : u8,
+    pub n : u16,
+    pub p : u8,     // real division factor, 2, 4, 6 or 8
+    pub q : u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Spread {
+    Center,
+    Down,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SscgConfig {
+    pub modper  : u16,
+    pub incstep : u16,
+    pub spread  : Spread,
+}
+
+pub struct ClockConfig {
+    src             : ClkSrc,
+    sysclk          : Option<u32>,
+    hclk            : Option<u32>,
+    pclk1           : Option<u32>,
+    pclk2           : Option<u32>,
+    pll48clk        : bool,
+    supply          : SupplyRange,
+    sscg            : Option<(u32, u16, Spread)>,
+}
+
+impl ClockConfig {
+    pub fn new() -> ClockConfig {
+        ClockConfig {
+            src             : ClkSrc::Hsi,
+            sysclk          : None,
+            hclk            : None,
+            pclk1           : None,
+            pclk2           : None,
+            pll48clk        : false,
+            supply          : SupplyRange::V2_7To3_6,
+            sscg            : None,
+        }
+    }
+
+    pub fn use_hsi(mut self) -> ClockConfig {
+        self.src = ClkSrc::Hsi;
+        self
+    }
+
+    pub fn use_hse(mut self, freq : u32) -> ClockConfig {
+        self.src = ClkSrc::Hse(freq);
+        self
+    }
+
+    pub fn sysclk(mut self, freq : u32) -> ClockConfig {
+        self.sysclk = Some(freq);
+        self
+    }
+
+    // hclk and pclks are upper bounds, the fastest frequency reachable
+    // with the available prescalers below the request is selected.
+    pub fn hclk(mut self, freq : u32) -> ClockConfig {
+        self.hclk = Some(freq);
+        self
+    }
+
+    pub fn pclk1(mut self, freq : u32) -> ClockConfig {
+        self.pclk1 = Some(freq);
+        self
+    }
+
+    pub fn pclk2(mut self, freq : u32) -> ClockConfig {
+        self.pclk2 = Some(freq);
+        self
+    }
+
+    // request an exact 48MHz output on the PLL Q divider for USB OTG FS,
+    // SDIO and RNG
+    pub fn require_pll48clk(mut self, en : bool) -> ClockConfig {
+        self.pll48clk = en;
+        self
+    }
+
+    // the stm32f429i-disco runs at 3V, the default
+    pub fn supply(mut self, range : SupplyRange) -> ClockConfig {
+        self.supply = range;
+        self
+    }
+
+    // modulate the main PLL at mod_freq Hz with a peak deviation of depth
+    // hundredths of a percent, lowers the EMI peaks of sysclk
+    pub fn spread_spectrum(mut self, mod_freq : u32, depth : u16, spread : Spread) -> ClockConfig {
+        self.sscg = Some((mod_freq, depth, spread));
+        self
+    }
+
+    pub fn freeze(self) -> Result<Clocks, ConfigError> {
+        let src_freq = self.src.freq();
+        if let ClkSrc::Hse(f) = self.src {
+            if f < HSE_MIN || f > HSE_MAX {
+                return Err(ConfigError::HseOutOfRange(f));
+            }
+        }
+
+        let sysclk = self.sysclk.unwrap_or(src_freq);
+        if sysclk == 0 || sysclk > SYSCLK_MAX {
+            return Err(ConfigError::SysClkOutOfRange(sysclk));
+        }
+
+        let pll = if sysclk == src_freq && !self.pll48clk {
+            None
+        } else {
+            Some(solve_pll(src_freq, sysclk, self.pll48clk)?)
+        };
+
+        let sscg = match (self.sscg, pll) {
+            (None, _) => None,
+            (Some(_), None) => return Err(ConfigError::SscgUnreachable),
+            (Some((mod_freq, depth, spread)), Some(p)) => {
+                // center spread pushes sysclk above its nominal value
+                if spread == Spread::Center &&
+                        sysclk as u64 * (10_000 + depth as u64) > SYSCLK_MAX as u64 * 10_000 {
+                    return Err(ConfigError::SscgDepthOutOfRange(depth));
+                }
+                Some(solve_sscg(src_freq / p.m as u32, p.n, mod_freq, depth, spread)?)
+            },
+        };
+
+        let overdrive = sysclk > SYSCLK_MAX_NO_OD;
+        let (pclk1_max, pclk2_max) = if overdrive {
+            (PCLK1_MAX, PCLK2_MAX)
+        } else {
+            (PCLK1_MAX_NO_OD, PCLK2_MAX_NO_OD)
+        };
+
+        let hclk_req = self.hclk.unwrap_or(sysclk);
+        let hpre = match select_divider(sysclk, hclk_req, &AHB_DIVIDERS) {
+            Some(d) => d,
+            None => return Err(ConfigError::HclkOutOfRange(hclk_req)),
+        };
+        let hclk = sysclk / hpre;
+        let latency = flash_latency(hclk, self.supply)?;
+
+        let pclk1_req = self.pclk1.unwrap_or(if hclk < pclk1_max { hclk } else { pclk1_max });
+        if pclk1_req > pclk1_max {
+            return Err(ConfigError::Pclk1OutOfRange(pclk1_req));
+        }
+        let ppre1 = match select_divider(hclk, pclk1_req, &APB_DIVIDERS) {
+            Some(d) => d,
+            None => return Err(ConfigError::Pclk1OutOfRange(pclk1_req)),
+        };
+
+        let pclk2_req = self.pclk2.unwrap_or(if hclk < pclk2_max { hclk } else { pclk2_max });
+        if pclk2_req > pclk2_max {
+            return Err(ConfigError::Pclk2OutOfRange(pclk2_req));
+        }
+        let ppre2 = match select_divider(hclk, pclk2_req, &APB_DIVIDERS) {
+            Some(d) => d,
+            None => return Err(ConfigError::Pclk2OutOfRange(pclk2_req)),
+        };
+
+        let pclk1 = hclk / ppre1;
+        let pclk2 = hclk / ppre2;
+
+        Ok(Clocks {
+            src         : self.src,
+            pll         : pll,
+            hpre        : hpre as u16,
+            ppre1       : ppre1 as u8,
+            ppre2       : ppre2 as u8,
+            sysclk      : sysclk,
+            hclk        : hclk,
+            pclk1       : pclk1,
+            pclk2       : pclk2,
+            timclk1     : if ppre1 == 1 { pclk1 } else { pclk1 * 2 },
+            timclk2     : if ppre2 == 1 { pclk2 } else { pclk2 * 2 },
+            pll48clk    : pll.map(|p| pll_out(src_freq, p.m, p.n, p.q as u32)),
+            overdrive   : overdrive,
+            latency     : latency,
+            supply      : self.supply,
+            sscg        : sscg,
+        })
+    }
+}
+
+// Flash wait states for a given hclk, RM0090 table 12 (STM32F42xxx). Each
+// supply range allows a fixed frequency step per wait state.
+pub fn flash_latency(hclk : u32, range : SupplyRange) -> Result<u8, ConfigError> {
+    let (step, max) = match range {
+        SupplyRange::V1_8To2_1 => (20_000_000, 168_000_000),
+        SupplyRange::V2_1To2_4 => (22_000_000, 180_000_000),
+        SupplyRange::V2_4To2_7 => (24_000_000, 180_000_000),
+        SupplyRange::V2_7To3_6 => (30_000_000, 180_000_000),
+    };
+
+    if hclk == 0 || hclk > max {
+        return Err(ConfigError::HclkOutOfRange(hclk));
+    }
+
+    Ok(((hclk - 1) / step) as u8)
+}
+
+pub fn pll_out(src_freq : u32, m : u8, n : u16, div : u32) -> u32 {
+    (src_freq as u64 * n as u64 / (m as u64 * div as u64)) as u32
+}
+
+// smallest divider giving a frequency lower or equal to the target
+fn select_divider(input : u32, target : u32, dividers : &[u32]) -> Option<u32> {
+    for d in dividers.iter() {
+        if input / *d <= target {
+            return Some(*d);
+        }
+    }
+    None
+}
+
+// Look for M/N/P(/Q) giving exactly sysclk. The highest VCO input frequency
+// is preferred since it lowers the PLL jitter.
+fn solve_pll(src_freq : u32, sysclk : u32, pll48clk : bool) -> Result<PllConfig, ConfigError> {
+    let mut pll48_missed = false;
+
+    let mut m = (src_freq + VCO_IN_MAX - 1) / VCO_IN_MAX;
+    if m < PLL_M_MIN {
+        m = PLL_M_MIN;
+    }
+
+    while m <= PLL_M_MAX && src_freq / m >= VCO_IN_MIN {
+        for p in PLL_P_VALUES.iter() {
+            let vco_out = sysclk as u64 * *p as u64;
+            if vco_out < VCO_OUT_MIN as u64 || vco_out > VCO_OUT_MAX as u64 {
+                continue;
+            }
+            // N = vco_out / (src_freq / M) has to be an integer
+            if (vco_out * m as u64) % src_freq as u64 != 0 {
+                continue;
+            }
+            let n = ((vco_out * m as u64) / src_freq as u64) as u32;
+            if n < PLL_N_MIN || n > PLL_N_MAX {
+                continue;
+            }
+            let vco_out = vco_out as u32;
+
+            let q = if pll48clk {
+                if vco_out % PLL48_FREQ != 0 ||
+                        vco_out / PLL48_FREQ < PLL_Q_MIN ||
+                        vco_out / PLL48_FREQ > PLL_Q_MAX {
+                    pll48_missed = true;
+                    continue;
+                }
+                vco_out / PLL48_FREQ
+            } else {
+                // keep the 48MHz domain within its limit even if unused
+                let q = (vco_out + PLL48_FREQ - 1) / PLL48_FREQ;
+                if q < PLL_Q_MIN { PLL_Q_MIN } else if q > PLL_Q_MAX { PLL_Q_MAX } else { q }
+            };
+
+            return Ok(PllConfig {
+                m : m as u8,
+                n : n as u16,
+                p : *p as u8,
+                q : q as u8,
+            });
+        }
+        m += 1;
+    }
+
+    if pll48_missed {
+        Err(ConfigError::Pll48Unreachable)
+    } else {
+        Err(ConfigError::PllUnreachable(sysclk))
+    }
+}
+
+// MODPER and INCSTEP for a modulation frequency and a peak depth (in
+// hundredths of a percent), from the PLL input frequency after M and the
+// main PLL N. RM0090 equations:
+//   MODPER  = round(vco_in / (4 * mod_freq))
+//   INCSTEP = round((2^15 - 1) * depth% * N / (100 * 5 * MODPER))
+pub fn solve_sscg(vco_in : u32, n : u16, mod_freq : u32, depth : u16, spread : Spread)
+    -> Result<SscgConfig, ConfigError> {
+    if mod_freq == 0 || mod_freq > SSCG_MOD_FREQ_MAX {
+        return Err(ConfigError::SscgModFreqOutOfRange(mod_freq));
+    }
+    if depth == 0 || depth > SSCG_DEPTH_MAX {
+        return Err(ConfigError::SscgDepthOutOfRange(depth));
+    }
+
+    let modper = (vco_in + 2 * mod_freq) / (4 * mod_freq);
+    if modper == 0 || modper > SSCG_MODPER_MAX {
+        return Err(ConfigError::SscgModFreqOutOfRange(mod_freq));
+    }
+
+    // depth is in hundredths of a percent, hence 500 * 100
+    let num = SSCG_INCSTEP_MAX as u64 * depth as u64 * n as u64;
+    let den = 50_000 * modper as u64;
+    let incstep = ((num + den / 2) / den) as u32;
+    if incstep == 0 || incstep > SSCG_INCSTEP_MAX || modper * incstep > SSCG_INCSTEP_MAX {
+        return Err(ConfigError::SscgUnreachable);
+    }
+
+    // the modulated VCO has to stay within its range, the peak to peak
+    // deviation is twice the depth in both modes
+    let vco_out = vco_in as u64 * n as u64;
+    let dev = vco_out * depth as u64 / 10_000;
+    let (low, high) = match spread {
+        Spread::Center => (vco_out - dev, vco_out + dev),
+        Spread::Down => (vco_out - 2 * dev, vco_out),
+    };
+    if low < VCO_OUT_MIN as u64 || high > VCO_OUT_MAX as u64 {
+        return Err(ConfigError::SscgDepthOutOfRange(depth));
+    }
+
+    Ok(SscgConfig {
+        modper  : modper as u16,
+        incstep : incstep as u16,
+        spread  : spread,
+    })
+}
+
+// PLLSAI settings feeding the LTDC, freq is the pixel clock really obtained
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LcdClock {
+    pub n       : u16,
+    pub r       : u8,
+    pub divr    : u8,   // real division factor, 2, 4, 8 or 16
+    pub freq    : u32,
+}
+
+// Closest pixel clock reachable with PLLSAI N/R and PLLSAIDIVR from the
+// PLL input frequency (after M), vco_in.
+pub fn solve_lcd_clock(vco_in : u32, pixel_clk : u32) -> Result<LcdClock, ConfigError> {
+    if vco_in == 0 || pixel_clk == 0 || pixel_clk > LCD_CLK_MAX {
+        return Err(ConfigError::LcdClkUnreachable(pixel_clk));
+    }
+
+    let mut best : Option<LcdClock> = None;
+    let mut best_err = u32::max_value();
+
+    for divr in PLLSAI_DIVR_VALUES.iter() {
+        for r in PLLSAI_R_MIN..(PLLSAI_R_MAX + 1) {
+            let div = (r * *divr) as u64;
+            // rounded N for this post divider
+            let n = ((pixel_clk as u64 * div + vco_in as u64 / 2) / vco_in as u64) as u32;
+            if n < PLL_N_MIN || n > PLL_N_MAX {
+                continue;
+            }
+            let vco_out = vco_in as u64 * n as u64;
+            if vco_out < VCO_OUT_MIN as u64 || vco_out > VCO_OUT_MAX as u64 {
+                continue;
+            }
+            let freq = (vco_out / div) as u32;
+            let err = if freq > pixel_clk { freq - pixel_clk } else { pixel_clk - freq };
+            if err < best_err {
+                best_err = err;
+                best = Some(LcdClock {
+                    n       : n as u16,
+                    r       : r as u8,
+                    divr    : *divr as u8,
+                    freq    : freq,
+                });
+            }
+        }
+    }
+
+    match best {
+        Some(c) => Ok(c),
+        None => Err(ConfigError::LcdClkUnreachable(pixel_clk)),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AudioPll {
+    // PLLI2S R output feeding the I2S peripherals
+    I2s,
+    // PLLSAI Q output divided by PLLSAIDIVQ feeding the SAI
+    Sai,
+}
+
+// PLL and divider settings for an audio sample rate. For I2S `div` is
+// PLLI2S R and `periph_div` is 2 * I2SDIV + ODD. For SAI `div` is PLLSAI Q,
+// `divq` PLLSAIDIVQ and `periph_div` is 2 * MCKDIV (1 if MCKDIV = 0).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AudioClock {
+    pub pll         : AudioPll,
+    pub n           : u16,
+    pub div         : u8,
+    pub divq        : u8,
+    pub periph_div  : u16,
+    // sample rate really obtained, rounded to the Hz
+    pub fs          : u32,
+    // deviation from the requested sample rate in parts per million
+    pub error_ppm   : i32,
+}
+
+// error in ppm of (num / den) against target, in 64 bits to keep the
+// fraction of the obtained rate
+fn ppm(num : u64, den : u64, target : u32) -> i64 {
+    let want = target as u64 * den;
+    (num as i64 - want as i64) * 1_000_000 / want as i64
+}
+
+// Settings of PLLI2S and the I2S prescaler closest to the sample rate `fs`.
+// `ratio` is the I2S clock to fs ratio after the prescaler: 256 with the
+// master clock output enabled, else 32 or 64 for 16 or 32 bits frames.
+pub fn solve_i2s_clock(vco_in : u32, fs : u32, ratio : u32) -> Result<AudioClock, ConfigError> {
+    if fs < AUDIO_FS_MIN || fs > AUDIO_FS_MAX {
+        return Err(ConfigError::AudioFsOutOfRange(fs));
+    }
+    if ratio != 32 && ratio != 64 && ratio != 256 {
+        return Err(ConfigError::AudioRatioUnsupported(ratio));
+    }
+
+    let mut best : Option<AudioClock> = None;
+    let mut best_err = i64::max_value();
+
+    for n in PLL_N_MIN..(PLL_N_MAX + 1) {
+        let vco_out = vco_in as u64 * n as u64;
+        if vco_out < VCO_OUT_MIN as u64 || vco_out > VCO_OUT_MAX as u64 {
+            continue;
+        }
+        for r in PLLI2S_R_MIN..(PLLI2S_R_MAX + 1) {
+            if vco_out / r as u64 > I2S_CLK_MAX as u64 {
+                continue;
+            }
+            // rounded prescaler for this I2S clock
+            let unit = r as u64 * fs as u64 * ratio as u64;
+            let d = ((vco_out + unit / 2) / unit) as u32;
+            if d < I2S_DIV_MIN || d > I2S_DIV_MAX {
+                continue;
+            }
+            let den = r as u64 * d as u64 * ratio as u64;
+            let err = ppm(vco_out, den, fs);
+            if err.abs() < best_err {
+                best_err = err.abs();
+                best = Some(AudioClock {
+                    pll         : AudioPll::I2s,
+                    n           : n as u16,
+                    div         : r as u8,
+                    divq        : 1,
+                    periph_div  : d as u16,
+                    fs          : ((vco_out + den / 2) / den) as u32,
+                    error_ppm   : err as i32,
+                });
+            }
+        }
+    }
+
+    match best {
+        Some(c) => Ok(c),
+        None => Err(ConfigError::AudioClkUnreachable(fs)),
+    }
+}
+
+// Settings of PLLSAI, PLLSAIDIVQ and the SAI master clock divider closest
+// to the sample rate `fs` with a master clock of `ratio` * fs. PLLSAI N is
+// shared with the LTDC pixel clock: when the LCD is running pass its N in
+// `n`, only Q and the dividers are searched then.
+pub fn solve_sai_clock(vco_in : u32, fs : u32, ratio : u32, n : Option<u16>)
+    -> Result<AudioClock, ConfigError> {
+    if fs < AUDIO_FS_MIN || fs > AUDIO_FS_MAX {
+        return Err(ConfigError::AudioFsOutOfRange(fs));
+    }
+    if ratio == 0 {
+        return Err(ConfigError::AudioRatioUnsupported(ratio));
+    }
+
+    let (n_min, n_max) = match n {
+        Some(n) => (n as u32, n as u32),
+        None => (PLL_N_MIN, PLL_N_MAX),
+    };
+
+    let mut best : Option<AudioClock> = None;
+    let mut best_err = i64::max_value();
+
+    for n in n_min..(n_max + 1) {
+        let vco_out = vco_in as u64 * n as u64;
+        if vco_out < VCO_OUT_MIN as u64 || vco_out > VCO_OUT_MAX as u64 {
+            continue;
+        }
+        for q in PLLSAI_Q_MIN..(PLLSAI_Q_MAX + 1) {
+            for mckdiv in 0..(SAI_MCKDIV_MAX + 1) {
+                let pd = if mckdiv == 0 { 1 } else { 2 * mckdiv };
+                let unit = q as u64 * pd as u64 * fs as u64 * ratio as u64;
+                let divq = ((vco_out + unit / 2) / unit) as u32;
+                if divq < 1 || divq > PLLSAI_DIVQ_MAX {
+                    continue;
+                }
+                let den = q as u64 * divq as u64 * pd as u64 * ratio as u64;
+                let err = ppm(vco_out, den, fs);
+                if err.abs() < best_err {
+                    best_err = err.abs();
+                    best = Some(AudioClock {
+                        pll         : AudioPll::Sai,
+                        n           : n as u16,
+                        div         : q as u8,
+                        divq        : divq as u8,
+                        periph_div  : pd as u16,
+                        fs          : ((vco_out + den / 2) / den) as u32,
+                        error_ppm   : err as i32,
+                    });
+                }
+            }
+        }
+    }
+
+    match best {
+        Some(c) => Ok(c),
+        None => Err(ConfigError::AudioClkUnreachable(fs)),
+    }
+}
+
+// Frozen result of a ClockConfig, every frequency is in Hz.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Clocks {
+    src         : ClkSrc,
+    pll         : Option<PllConfig>,
+    hpre        : u16,
+    ppre1       : u8,
+    ppre2       : u8,
+    sysclk      : u32,
+    hclk        : u32,
+    pclk1       : u32,
+    pclk2       : u32,
+    timclk1     : u32,
+    timclk2     : u32,
+    pll48clk    : Option<u32>,
+    overdrive   : bool,
+    latency     : u8,
+    supply      : SupplyRange,
+    sscg        : Option<SscgConfig>,
+}
+
+impl Clocks {
+    pub fn src(&self) -> ClkSrc {
+        self.src
+    }
+
+    pub fn pll(&self) -> Option<PllConfig> {
+        self.pll
+    }
+
+    pub fn hpre(&self) -> u16 {
+        self.hpre
+    }
+
+    pub fn ppre1(&self) -> u8 {
+        self.ppre1
+    }
+
+    pub fn ppre2(&self) -> u8 {
+        self.ppre2
+    }
+
+    pub fn sysclk(&self) -> u32 {
+        self.sysclk
+    }
+
+    pub fn hclk(&self) -> u32 {
+        self.hclk
+    }
+
+    pub fn pclk1(&self) -> u32 {
+        self.pclk1
+    }
+
+    pub fn pclk2(&self) -> u32 {
+        self.pclk2
+    }
+
+    pub fn timclk1(&self) -> u32 {
+        self.timclk1
+    }
+
+    pub fn timclk2(&self) -> u32 {
+        self.timclk2
+    }
+
+    pub fn pll48clk(&self) -> Option<u32> {
+        self.pll48clk
+    }
+
+    pub fn overdrive(&self) -> bool {
+        self.overdrive
+    }
+
+    // flash wait states required by hclk
+    pub fn latency(&self) -> u8 {
+        self.latency
+    }
+
+    pub fn supply(&self) -> SupplyRange {
+        self.supply
+    }
+
+    // the prefetch buffer can't be used below 2.1V
+    pub fn prefetch(&self) -> bool {
+        self.supply != SupplyRange::V1_8To2_1
+    }
+
+    pub fn sscg(&self) -> Option<SscgConfig> {
+        self.sscg
+    }
+
+    // lowest regulator output voltage able to sustain sysclk
+    pub fn voltage_scale(&self) -> VoltageScale {
+        if self.sysclk <= SYSCLK_MAX_SCALE3 {
+            VoltageScale::Scale3
+        } else if self.sysclk <= SYSCLK_MAX_SCALE2 {
+            VoltageScale::Scale2
+        } else {
+            VoltageScale::Scale1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vco_in(src_freq : u32, pll : PllConfig) -> u32 {
+        src_freq / pll.m as u32
+    }
+
+    fn vco_out(src_freq : u32, pll : PllConfig) -> u32 {
+        vco_in(src_freq, pll) * pll.n as u32
+    }
+
+    #[test]
+    fn hse_8mhz_to_180mhz() {
+        let clocks = ClockConfig::new()
+            .use_hse(8_000_000)
+            .sysclk(180_000_000)
+            .freeze()
+            .unwrap();
+
+        assert_eq!(clocks.pll(), Some(PllConfig { m : 4, n : 180, p : 2, q : 8 }));
+        assert_eq!(clocks.sysclk(), 180_000_000);
+        assert_eq!(clocks.hclk(), 180_000_000);
+        assert_eq!(clocks.pclk1(), 45_000_000);
+        assert_eq!(clocks.pclk2(), 90_000_000);
+        assert_eq!(clocks.timclk1(), 90_000_000);
+        assert_eq!(clocks.timclk2(), 180_000_000);
+        assert!(clocks.overdrive());
+        assert_eq!(clocks.latency(), 5);
+        assert_eq!(clocks.voltage_scale(), VoltageScale::Scale1);
+    }
+
+    #[test]
+    fn hsi_without_pll() {
+        let clocks = ClockConfig::new().freeze().unwrap();
+
+        assert_eq!(clocks.src(), ClkSrc::Hsi);
+        assert_eq!(clocks.pll(), None);
+        assert_eq!(clocks.sysclk(), HSI_FREQ);
+        assert_eq!(clocks.pll48clk(), None);
+        assert_eq!(clocks.latency(), 0);
+    }
+
+    #[test]
+    fn hsi_with_usb_clock() {
+        let clocks = ClockConfig::new()
+            .use_hsi()
+            .sysclk(168_000_000)
+            .require_pll48clk(true)
+            .freeze()
+            .unwrap();
+
+        assert_eq!(clocks.pll(), Some(PllConfig { m : 8, n : 168, p : 2, q : 7 }));
+        assert_eq!(clocks.pll48clk(), Some(48_000_000));
+        assert!(!clocks.overdrive());
+
+        // 360MHz out of the VCO is no multiple of 48MHz, 720MHz is too high
+        assert_eq!(ClockConfig::new()
+                       .use_hsi()
+                       .sysclk(180_000_000)
+                       .require_pll48clk(true)
+                       .freeze(),
+                   Err(ConfigError::Pll48Unreachable));
+    }
+
+    #[test]
+    fn vco_in_range() {
+        for hse in [4_000_000, 8_000_000, 12_000_000, 25_000_000, 26_000_000].iter() {
+            let pll = solve_pll(*hse, 168_000_000, false).unwrap();
+            let f = vco_in(*hse, pll);
+            assert!(f >= VCO_IN_MIN && f <= VCO_IN_MAX, "hse {} vco_in {}", hse, f);
+            assert!(pll.m as u32 >= PLL_M_MIN && pll.m as u32 <= PLL_M_MAX);
+        }
+
+        assert_eq!(ClockConfig::new().use_hse(3_999_999).freeze(),
+                   Err(ConfigError::HseOutOfRange(3_999_999)));
+        assert_eq!(ClockConfig::new().use_hse(26_000_001).freeze(),
+                   Err(ConfigError::HseOutOfRange(26_000_001)));
+    }
+
+    #[test]
+    fn vco_out_range() {
+        let mut sysclk = 25_000_000;
+        while sysclk <= SYSCLK_MAX {
+            let pll = solve_pll(8_000_000, sysclk, false).unwrap();
+            let f = vco_out(8_000_000, pll);
+            assert!(f >= VCO_OUT_MIN && f <= VCO_OUT_MAX, "sysclk {} vco_out {}", sysclk, f);
+            assert_eq!(f / pll.p as u32, sysclk);
+            // the unused 48MHz domain stays within its limit
+            assert!(f / pll.q as u32 <= PLL48_FREQ);
+            sysclk += 1_000_000;
+        }
+
+        // 12MHz needs a VCO below 100MHz even with P = 8
+        assert_eq!(solve_pll(8_000_000, 12_000_000, false),
+                   Err(ConfigError::PllUnreachable(12_000_000)));
+    }
+
+    #[test]
+    fn pclk1_above_45mhz() {
+        assert_eq!(ClockConfig::new()
+                       .use_hse(8_000_000)
+                       .sysclk(180_000_000)
+                       .pclk1(46_000_000)
+                       .freeze(),
+                   Err(ConfigError::Pclk1OutOfRange(46_000_000)));
+
+        // without over-drive the limit is 42MHz
+        assert_eq!(ClockConfig::new()
+                       .use_hse(8_000_000)
+                       .sysclk(168_000_000)
+                       .pclk1(45_000_000)
+                       .freeze(),
+                   Err(ConfigError::Pclk1OutOfRange(45_000_000)));
+
+        let clocks = ClockConfig::new()
+            .use_hse(8_000_000)
+            .sysclk(168_000_000)
+            .freeze()
+            .unwrap();
+        assert_eq!(clocks.pclk1(), 42_000_000);
+        assert_eq!(clocks.pclk2(), 84_000_000);
+    }
+
+    #[test]
+    fn unreachable_sysclk() {
+        assert_eq!(ClockConfig::new().use_hse(8_000_000).sysclk(180_000_001).freeze(),
+                   Err(ConfigError::SysClkOutOfRange(180_000_001)));
+        assert_eq!(ClockConfig::new().sysclk(0).freeze(),
+                   Err(ConfigError::SysClkOutOfRange(0)));
+        // N would not be an integer for any M and P
+        assert_eq!(ClockConfig::new().use_hse(25_000_000).sysclk(179_999_999).freeze(),
+                   Err(ConfigError::PllUnreachable(179_999_999)));
+    }
+
+    // RM0090 table 12, highest hclk of each wait state for every supply range
+    const LATENCY_EDGES : [(SupplyRange, &'static [u32]); 4] = [
+        (SupplyRange::V1_8To2_1, &[20, 40, 60, 80, 100, 120, 140, 160, 168]),
+        (SupplyRange::V2_1To2_4, &[22, 44, 66, 88, 110, 132, 154, 176, 180]),
+        (SupplyRange::V2_4To2_7, &[24, 48, 72, 96, 120, 144, 168, 180]),
+        (SupplyRange::V2_7To3_6, &[30, 60, 90, 120, 150, 180]),
+    ];
+
+    #[test]
+    fn flash_latency_edges() {
+        for &(range, edges) in LATENCY_EDGES.iter() {
+            assert_eq!(flash_latency(1, range), Ok(0));
+            for (ws, mhz) in edges.iter().enumerate() {
+                let edge = mhz * 1_000_000;
+                assert_eq!(flash_latency(edge, range), Ok(ws as u8), "{:?} {}Hz", range, edge);
+                if ws + 1 < edges.len() {
+                    assert_eq!(flash_latency(edge + 1, range), Ok(ws as u8 + 1),
+                               "{:?} {}Hz", range, edge + 1);
+                } else {
+                    assert_eq!(flash_latency(edge + 1, range),
+                               Err(ConfigError::HclkOutOfRange(edge + 1)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flash_latency_zero() {
+        assert_eq!(flash_latency(0, SupplyRange::V2_7To3_6),
+                   Err(ConfigError::HclkOutOfRange(0)));
+    }
+
+    #[test]
+    fn prefetch_by_supply() {
+        let clocks = |range| ClockConfig::new().supply(range).freeze().unwrap();
+        assert!(!clocks(SupplyRange::V1_8To2_1).prefetch());
+        assert!(clocks(SupplyRange::V2_1To2_4).prefetch());
+        assert!(clocks(SupplyRange::V2_4To2_7).prefetch());
+        assert!(clocks(SupplyRange::V2_7To3_6).prefetch());
+    }
+
+    // 8MHz HSE divided by M = 4, as set up by default_config
+    const AUDIO_VCO_IN : u32 = 2_000_000;
+    const AUDIO_PPM_MAX : i32 = 500;
+
+    // fs, then n, div, periph_div and the error of the I2S solution, then
+    // n, div, divq, periph_div and the error of the SAI one
+    const AUDIO_RATES : [(u32, (u16, u8, u16, i32), (u16, u8, u8, u16, i32)); 9] = [
+        (8_000,   (128, 5, 25, 0),    (128, 5, 25, 1, 0)),
+        (11_025,  (127, 2, 45, -62),  (127, 3, 30, 1, -62)),
+        (16_000,  (213, 4, 26, 37),   (213, 2, 26, 2, 37)),
+        (22_050,  (127, 3, 15, -62),  (127, 3, 15, 1, -62)),
+        (32_000,  (213, 4, 13, 37),   (213, 2, 26, 1, 37)),
+        (44_100,  (79, 2, 7, -344),   (79, 2, 7, 1, -344)),
+        (48_000,  (86, 2, 7, -186),   (86, 2, 7, 1, -186)),
+        (96_000,  (172, 2, 7, -186),  (86, 7, 1, 1, -186)),
+        (192_000, (192, 2, 4, -23437), (172, 7, 1, 1, -186)),
+    ];
+
+    // the settings are in range and give back the reported rate and error
+    fn check_audio(clk : &AudioClock, fs : u32, ratio : u32) {
+        let vco_out = AUDIO_VCO_IN as u64 * clk.n as u64;
+        assert!(vco_out >= VCO_OUT_MIN as u64 && vco_out <= VCO_OUT_MAX as u64);
+        let den = clk.div as u64 * clk.divq as u64 * clk.periph_div as u64 * ratio as u64;
+        assert_eq!(((vco_out + den / 2) / den) as u32, clk.fs);
+        assert_eq!(ppm(vco_out, den, fs) as i32, clk.error_ppm);
+    }
+
+    #[test]
+    fn i2s_rates() {
+        for &(fs, (n, div, periph_div, err), _) in AUDIO_RATES.iter() {
+            let clk = solve_i2s_clock(AUDIO_VCO_IN, fs, 256).unwrap();
+            assert_eq!((clk.pll, clk.n, clk.div, clk.divq, clk.periph_div, clk.error_ppm),
+                       (AudioPll::I2s, n, div, 1, periph_div, err), "{}Hz", fs);
+            check_audio(&clk, fs, 256);
+            assert!(clk.div as u32 >= PLLI2S_R_MIN && clk.div as u32 <= PLLI2S_R_MAX);
+            assert!(clk.periph_div as u32 >= I2S_DIV_MIN && clk.periph_div as u32 <= I2S_DIV_MAX);
+            assert!(AUDIO_VCO_IN * clk.n as u32 / clk.div as u32 <= I2S_CLK_MAX);
+            // 192kHz x 256 needs an I2S clock above 192MHz with the
+            // smallest prescaler, the closest rate is 187.5kHz
+            if fs != 192_000 {
+                assert!(clk.error_ppm.abs() <= AUDIO_PPM_MAX, "{}Hz {}ppm", fs, clk.error_ppm);
+            }
+        }
+    }
+
+    #[test]
+    fn sai_rates() {
+        for &(fs, _, (n, div, divq, periph_div, err)) in AUDIO_RATES.iter() {
+            let clk = solve_sai_clock(AUDIO_VCO_IN, fs, 256, None).unwrap();
+            assert_eq!((clk.pll, clk.n, clk.div, clk.divq, clk.periph_div, clk.error_ppm),
+                       (AudioPll::Sai, n, div, divq, periph_div, err), "{}Hz", fs);
+            check_audio(&clk, fs, 256);
+            assert!(clk.div as u32 >= PLLSAI_Q_MIN && clk.div as u32 <= PLLSAI_Q_MAX);
+            assert!(clk.divq >= 1 && clk.divq as u32 <= PLLSAI_DIVQ_MAX);
+            assert!(clk.periph_div == 1 ||
+                    (clk.periph_div % 2 == 0 && clk.periph_div as u32 <= 2 * SAI_MCKDIV_MAX));
+            assert!(clk.error_ppm.abs() <= AUDIO_PPM_MAX, "{}Hz {}ppm", fs, clk.error_ppm);
+        }
+    }
+
+    #[test]
+    fn sai_with_lcd_n() {
+        // N is kept when the LTDC already runs from PLLSAI
+        let clk = solve_sai_clock(AUDIO_VCO_IN, 48_000, 256, Some(192)).unwrap();
+        assert_eq!(clk.n, 192);
+        check_audio(&clk, 48_000, 256);
+    }
+
+    #[test]
+    fn audio_out_of_range() {
+        assert_eq!(solve_i2s_clock(AUDIO_VCO_IN, 7_999, 256),
+                   Err(ConfigError::AudioFsOutOfRange(7_999)));
+        assert_eq!(solve_sai_clock(AUDIO_VCO_IN, 192_001, 256, None),
+                   Err(ConfigError::AudioFsOutOfRange(192_001)));
+        assert_eq!(solve_i2s_clock(AUDIO_VCO_IN, 48_000, 128),
+                   Err(ConfigError::AudioRatioUnsupported(128)));
+        assert_eq!(solve_sai_clock(AUDIO_VCO_IN, 48_000, 0, None),
+                   Err(ConfigError::AudioRatioUnsupported(0)));
+    }
+}
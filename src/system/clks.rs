@@ -1,49 +1,246 @@
-use stm32f429::{RCC, PWR, FLASH};
+use stm32f429::{PWR, FLASH};
 
-use spl_rs::rcc;
+use spl_rs::{rcc, clk_gate};
+use spl_rs::rcc::RccError;
+use spl_rs::clk_gate::{Gate, ClkGateError};
+use system::clk_config::{ClockConfig, Clocks, ClkSrc, ConfigError, Spread};
+use system::clk_config::{AudioClock, AudioPll};
+use misc;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClkError {
+    Config(ConfigError),
+    Rcc(RccError),
+    Gate(ClkGateError),
+}
+
+impl From<ConfigError> for ClkError {
+    fn from(e : ConfigError) -> ClkError {
+        ClkError::Config(e)
+    }
+}
+
+impl From<RccError> for ClkError {
+    fn from(e : RccError) -> ClkError {
+        ClkError::Rcc(e)
+    }
+}
+
+impl From<ClkGateError> for ClkError {
+    fn from(e : ClkGateError) -> ClkError {
+        ClkError::Gate(e)
+    }
+}
+
+const CLK_OWNER : &'static str = "clks";
 
 /*
 This configuration follows the one present in system_stm32f4xx.c for
 the stm32f429i-disco board template project in sw4stm32 using SPL.
 */
+pub fn default_config() -> ClockConfig {
+    ClockConfig::new()
+        .use_hse(rcc::HSE_VALUE)
+        .sysclk(180_000_000)
+        .hclk(180_000_000)
+        .pclk1(45_000_000)
+        .pclk2(90_000_000)
+}
 
-pub fn init() -> Result<(), ()>{
+pub fn init() -> Result<Clocks, ClkError> {
+    let clocks = default_config().freeze()?;
+    configure(&clocks)?;
+    Ok(clocks)
+}
+
+// The PWR clock is held from the first call on, the regulator settings
+// follow every clock change.
+pub fn configure(clocks : &Clocks) -> Result<(), ClkError> {
+    clk_gate::acquire(Gate::Apb1(rcc::Apb1Enable::PWR), CLK_OWNER)?;
+    apply(clocks)?;
+    Ok(())
+}
+
+fn apply(clocks : &Clocks) -> Result<(), RccError> {
     let pwr = unsafe{ &*PWR::ptr() };
     let flash = unsafe{ &*FLASH::ptr() };
 
-    rcc::clock_ctrl(rcc::Clock::HSE_ON, true);
-    while rcc::check_flag(rcc::ClkFlag::HSE_RDY) != true {}
+    // flash wait states have to be raised before the frequency goes up and
+    // can only be lowered once it went down
+    let latency = flash.acr.read().latency().bits();
+    if clocks.latency() > latency {
+        set_flash_latency(clocks.latency(), clocks.prefetch());
+    }
 
-    match rcc::configure_pll(0b0111, 0b101101000, 0b11, 0b001111) {
-        Ok(()) => (),
-        Err(()) => return Err(()),
-    };
+    // the main PLL can't be reprogrammed while running, run from HSI meanwhile
+    if rcc::check_flag(rcc::ClkFlag::PLL_RDY) {
+        leave_pll()?;
+    }
+
+    // over-drive can only be left while sysclk is not fed by the PLL
+    if !clocks.overdrive() && pwr.csr.read().odswrdy().bit() {
+        pwr.cr.modify(|_, w| w.odswen().bit(false).oden().bit(false));
+    }
+
+    // VOS is only taken into account at the next PLL start
+    pwr.cr.modify(|_, w| unsafe{w.vos().bits(clocks.voltage_scale() as u8)});
+
+    if let ClkSrc::Hse(_) = clocks.src() {
+        rcc::clock_ctrl(rcc::Clock::HSE_ON, true);
+        if !rcc::wait_flag(rcc::ClkFlag::HSE_RDY, rcc::HSE_STARTUP_TIMEOUT) {
+            rcc::clock_ctrl(rcc::Clock::HSE_ON, false);
+            return Err(RccError::HseStartupTimeout);
+        }
+    }
+
+    // PLLSRC and PLLM are shared with PLLI2S and PLLSAI, they are stopped
+    // while the main PLL is reprogrammed and restarted afterwards
+    let plli2s_on = rcc::check_flag(rcc::ClkFlag::PLL_I2S_RDY);
+    let pllsai_on = rcc::check_flag(rcc::ClkFlag::PLL_SAI_RDY);
 
-    rcc::set_apb1_periph_clk(rcc::Apb1Enable::PWR, true);
+    if let Some(pll) = clocks.pll() {
+        rcc::clock_ctrl(rcc::Clock::PLL_I2S_ON | rcc::Clock::PLL_SAI_ON, false);
+        rcc::select_pll_src(clocks.src() != ClkSrc::Hsi);
+        rcc::configure_pll(pll.q, pll.n, pll.p / 2 - 1, pll.m)?;
 
-    pwr.cr.modify(|_, w| unsafe{w.vos().bits(0b11)});
+        // the PLL is stopped, the only time SSCGR may be written
+        match clocks.sscg() {
+            Some(sscg) => rcc::conf_sscg(sscg.modper, sscg.incstep,
+                                         sscg.spread == Spread::Down)?,
+            None => rcc::set_sspm(false),
+        }
+    }
 
-    rcc::set_ahb_pre(rcc::AhbPre::Div1);
-    rcc::set_apb2_pre(rcc::ApbPre::Div2);
-    rcc::set_apb1_pre(rcc::ApbPre::Div4);
+    rcc::set_ahb_pre(ahb_pre(clocks.hpre()));
+    rcc::set_apb2_pre(apb_pre(clocks.ppre2()));
+    rcc::set_apb1_pre(apb_pre(clocks.ppre1()));
 
-    rcc::clock_ctrl(rcc::Clock::PLL_ON, true);
-    while rcc::check_flag(rcc::ClkFlag::PLL_RDY) != true {}
+    if clocks.pll().is_some() {
+        rcc::clock_ctrl(rcc::Clock::PLL_ON, true);
+        if !rcc::wait_flag(rcc::ClkFlag::PLL_RDY, rcc::PLL_LOCK_TIMEOUT) {
+            return Err(RccError::PllLockTimeout);
+        }
 
-    pwr.cr.modify(|_, w| w.oden().bit(true));
-    while pwr.csr.read().odrdy().bit() == false {}
+        if plli2s_on {
+            rcc::clock_ctrl(rcc::Clock::PLL_I2S_ON, true);
+            if !rcc::wait_flag(rcc::ClkFlag::PLL_I2S_RDY, rcc::PLL_LOCK_TIMEOUT) {
+                return Err(RccError::PllI2sLockTimeout);
+            }
+        }
 
-    pwr.cr.modify(|_, w| w.odswen().bit(true));
-    while pwr.csr.read().odswrdy().bit() == false {}
+        if pllsai_on {
+            rcc::clock_ctrl(rcc::Clock::PLL_SAI_ON, true);
+            if !rcc::wait_flag(rcc::ClkFlag::PLL_SAI_RDY, rcc::PLL_LOCK_TIMEOUT) {
+                return Err(RccError::PllSaiLockTimeout);
+            }
+        }
+    }
 
+    if clocks.overdrive() {
+        pwr.cr.modify(|_, w| w.oden().bit(true));
+        if !misc::wait_until(|| pwr.csr.read().odrdy().bit(), rcc::CLK_SWITCH_TIMEOUT) {
+            return Err(RccError::OverDriveTimeout);
+        }
+
+        pwr.cr.modify(|_, w| w.odswen().bit(true));
+        if !misc::wait_until(|| pwr.csr.read().odswrdy().bit(), rcc::CLK_SWITCH_TIMEOUT) {
+            return Err(RccError::OverDriveSwitchTimeout);
+        }
+    }
+
+    let sw = match (clocks.pll(), clocks.src()) {
+        (Some(_), _) => rcc::SysClkSrc::Pll,
+        (None, ClkSrc::Hse(_)) => rcc::SysClkSrc::Hse,
+        (None, ClkSrc::Hsi) => rcc::SysClkSrc::Hsi,
+    };
+    rcc::set_sysclk_src(sw);
+    if !misc::wait_until(|| rcc::get_sysclk_src() == sw, rcc::CLK_SWITCH_TIMEOUT) {
+        return Err(RccError::SysClkSwitchTimeout);
+    }
+
+    if clocks.latency() <= latency {
+        set_flash_latency(clocks.latency(), clocks.prefetch());
+    }
+
+    return Ok(())
+}
+
+// Program PLLI2S or PLLSAI for a solved audio clock. The divider of the
+// peripheral itself (I2SPR or SAI MCKDIV) is left to its driver. The other
+// output of the PLL keeps its current divider, PLLSAI N is shared with the
+// LTDC pixel clock.
+pub fn configure_audio(clk : &AudioClock) -> Result<(), RccError> {
+    let regs = rcc::read_snapshot();
+    match clk.pll {
+        AudioPll::I2s => {
+            let q = ((regs.plli2scfgr >> 24) & 0xF) as u8;
+            rcc::clock_ctrl(rcc::Clock::PLL_I2S_ON, false);
+            rcc::conf_i2s_pll(clk.div, if q < 2 { 2 } else { q }, clk.n)?;
+            rcc::clock_ctrl(rcc::Clock::PLL_I2S_ON, true);
+            if !rcc::wait_flag(rcc::ClkFlag::PLL_I2S_RDY, rcc::PLL_LOCK_TIMEOUT) {
+                return Err(RccError::PllI2sLockTimeout);
+            }
+        },
+        AudioPll::Sai => {
+            let r = ((regs.pllsaicfgr >> 28) & 0b111) as u8;
+            rcc::clock_ctrl(rcc::Clock::PLL_SAI_ON, false);
+            rcc::conf_sai_pll(if r < 2 { 2 } else { r }, clk.div, clk.n)?;
+            rcc::conf_sai1_pllsai_divq(clk.divq)?;
+            rcc::clock_ctrl(rcc::Clock::PLL_SAI_ON, true);
+            if !rcc::wait_flag(rcc::ClkFlag::PLL_SAI_RDY, rcc::PLL_LOCK_TIMEOUT) {
+                return Err(RccError::PllSaiLockTimeout);
+            }
+        },
+    }
+    Ok(())
+}
+
+// prefetch has to stay off when the supply is below 2.1V
+fn set_flash_latency(ws : u8, prefetch : bool) {
+    let flash = unsafe{ &*FLASH::ptr() };
     flash.acr.modify(|_, w| unsafe {
-        w.prften().bit(true)
+        w.prften().bit(prefetch)
          .icen().bit(true)
-         .latency().bits(5)
+         .latency().bits(ws)
     });
+}
 
-    rcc::set_sysclk_src(rcc::SysClkSrc::Pll);
-    while rcc::get_sysclk_src() != rcc::SysClkSrc::Pll {}
+fn ahb_pre(div : u16) -> rcc::AhbPre {
+    match div {
+        1 => rcc::AhbPre::Div1,
+        2 => rcc::AhbPre::Div2,
+        4 => rcc::AhbPre::Div4,
+        8 => rcc::AhbPre::Div8,
+        16 => rcc::AhbPre::Div16,
+        64 => rcc::AhbPre::Div64,
+        128 => rcc::AhbPre::Div128,
+        256 => rcc::AhbPre::Div256,
+        _ => rcc::AhbPre::Div512,
+    }
+}
 
-    return Ok(())
+fn apb_pre(div : u8) -> rcc::ApbPre {
+    match div {
+        1 => rcc::ApbPre::Div1,
+        2 => rcc::ApbPre::Div2,
+        4 => rcc::ApbPre::Div4,
+        8 => rcc::ApbPre::Div8,
+        _ => rcc::ApbPre::Div16,
+    }
+}
+
+fn leave_pll() -> Result<(), RccError> {
+    rcc::clock_ctrl(rcc::Clock::HSI_ON, true);
+    if !rcc::wait_flag(rcc::ClkFlag::HSI_RDY, rcc::HSE_STARTUP_TIMEOUT) {
+        return Err(RccError::HsiStartupTimeout);
+    }
+
+    rcc::set_sysclk_src(rcc::SysClkSrc::Hsi);
+    if !misc::wait_until(|| rcc::get_sysclk_src() == rcc::SysClkSrc::Hsi,
+                         rcc::CLK_SWITCH_TIMEOUT) {
+        return Err(RccError::SysClkSwitchTimeout);
+    }
+
+    rcc::clock_ctrl(rcc::Clock::PLL_ON, false);
+    Ok(())
 }
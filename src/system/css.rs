@@ -0,0 +1,101 @@
+use spl_rs::rcc;
+use system::profiles;
+use system::profiles::Profile;
+use system::clks::ClkError;
+use system::clk_config::{ClockConfig, Clocks, ConfigError};
+
+// Clock security system. When the HSE fails the hardware switches SYSCLK
+// back to HSI, stops the PLL and raises a NMI. The handler only makes sure
+// SYSCLK is on HSI and flags the failure, poll() then restarts the PLL from
+// HSI in thread mode so the application keeps running, with the accuracy of
+// the internal oscillator. The fallback is applied as Profile::Fallback.
+
+pub const FALLBACK_SYSCLK : u32 = 168_000_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CssStatus {
+    Disabled,
+    Monitoring,
+    // HSE failed, running on bare HSI until the next poll()
+    HseLost,
+    // HSE failed and the system now runs from the fallback configuration
+    HseFailed(Clocks),
+    // HSE failed and the fallback could not be applied, running on bare HSI
+    FallbackFailed(ClkError),
+}
+
+static mut STATUS : CssStatus = CssStatus::Disabled;
+static mut CALLBACK : Option<fn(CssStatus)> = None;
+
+pub fn fallback_config() -> ClockConfig {
+    ClockConfig::new()
+        .use_hsi()
+        .sysclk(FALLBACK_SYSCLK)
+}
+
+// to be called once clks::init succeeded on HSE, fails if the fallback
+// can't be solved
+pub fn enable() -> Result<(), ConfigError> {
+    fallback_config().freeze()?;
+    unsafe {
+        STATUS = CssStatus::Monitoring;
+    }
+    rcc::clock_ctrl(rcc::Clock::CSS_ON, true);
+    Ok(())
+}
+
+pub fn disable() {
+    rcc::clock_ctrl(rcc::Clock::CSS_ON, false);
+    unsafe {
+        STATUS = CssStatus::Disabled;
+    }
+}
+
+pub fn status() -> CssStatus {
+    unsafe { STATUS }
+}
+
+// the callback is called from poll() once the fallback has been tried
+pub fn set_callback(cb : fn(CssStatus)) {
+    unsafe {
+        CALLBACK = Some(cb);
+    }
+}
+
+// To be called from the main loop. Applies the fallback configuration after
+// a HSE failure, clks::configure and the clock listeners can't run from the
+// NMI which may have preempted the very code they reconfigure.
+pub fn poll() -> CssStatus {
+    if status() != CssStatus::HseLost {
+        return status();
+    }
+
+    // set_profile tells the listeners, whether it succeeds or not
+    let status = match profiles::set_profile(Profile::Fallback) {
+        Ok(clocks) => CssStatus::HseFailed(clocks),
+        Err(e) => CssStatus::FallbackFailed(e),
+    };
+
+    unsafe {
+        STATUS = status;
+        if let Some(cb) = CALLBACK {
+            cb(status);
+        }
+    }
+    status
+}
+
+fn nmi_handler() {
+    if rcc::check_interrupt_flag(rcc::InterruptFlag::CSSF) {
+        rcc::clear_interrupt_flag(rcc::InterruptClear::CSSC);
+        // the hardware already stopped HSE, make sure it's not restarted by
+        // the fallback and that CSS stays off until HSE is back
+        rcc::clock_ctrl(rcc::Clock::CSS_ON | rcc::Clock::HSE_ON, false);
+        rcc::set_sysclk_src(rcc::SysClkSrc::Hsi);
+        unsafe {
+            STATUS = CssStatus::HseLost;
+        }
+    }
+}
+
+exception!(NMI, nmi_handler);
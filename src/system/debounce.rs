@@ -0,0 +1,150 @@
+use cortex_m::interrupt;
+
+use spl_rs::gpio;
+use system::debouncer::Debouncer;
+pub use system::debouncer::{Events, DebounceConfig};
+
+// Debounced inputs. tick() samples every registered pin, it is meant to be
+// called from a periodic interrupt and all the durations are counted in
+// ticks. The filtering itself lives in system::debouncer.
+
+const MAX_INPUTS    : usize = 8;
+const QUEUE_LEN     : usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebounceError {
+    TooManyInputs,
+    UnknownInput,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct InputId(usize);
+
+// A registered input, it holds the pin until unregister gives it back
+pub struct DebouncedInput<P, N, PULL> {
+    id          : InputId,
+    pin         : gpio::Pin<P, N, gpio::Input<PULL>>,
+}
+
+impl<P, N, PULL> DebouncedInput<P, N, PULL> {
+    // to match the events of the input
+    pub fn id(&self) -> InputId {
+        self.id
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct InputEvent {
+    pub input   : InputId,
+    pub events  : Events,
+}
+
+#[derive(Copy, Clone)]
+struct Input {
+    port        : gpio::Port,
+    pin         : u8,
+    filter      : Debouncer,
+}
+
+struct Queue {
+    events      : [Option<InputEvent>; QUEUE_LEN],
+    head        : usize,
+    len         : usize,
+    dropped     : u32,
+}
+
+static mut INPUTS : [Option<Input>; MAX_INPUTS] = [None; MAX_INPUTS];
+static mut QUEUE : Queue = Queue {
+    events      : [None; QUEUE_LEN],
+    head        : 0,
+    len         : 0,
+    dropped     : 0,
+};
+
+fn inputs() -> &'static mut [Option<Input>; MAX_INPUTS] {
+    unsafe { &mut INPUTS }
+}
+
+fn queue() -> &'static mut Queue {
+    unsafe { &mut QUEUE }
+}
+
+// the pin is handed back if the table is full
+pub fn register<P, N, PULL>(pin : gpio::Pin<P, N, gpio::Input<PULL>>, cfg : DebounceConfig)
+    -> Result<DebouncedInput<P, N, PULL>, (gpio::Pin<P, N, gpio::Input<PULL>>, DebounceError)>
+    where P : gpio::PortId, N : gpio::PinId {
+    let slot = interrupt::free(|_| {
+        let i = inputs().iter().position(|slot| slot.is_none())?;
+        inputs()[i] = Some(Input {
+            port        : pin.port(),
+            pin         : pin.pin_number(),
+            filter      : Debouncer::new(cfg),
+        });
+        Some(i)
+    });
+    match slot {
+        Some(i) => Ok(DebouncedInput { id : InputId(i), pin : pin }),
+        None => Err((pin, DebounceError::TooManyInputs)),
+    }
+}
+
+// events of the input already queued are still returned by poll
+pub fn unregister<P, N, PULL>(input : DebouncedInput<P, N, PULL>)
+    -> gpio::Pin<P, N, gpio::Input<PULL>> {
+    interrupt::free(|_| {
+        inputs()[input.id.0] = None;
+    });
+    input.pin
+}
+
+pub fn is_pressed(id : InputId) -> Result<bool, DebounceError> {
+    match inputs().get(id.0) {
+        Some(&Some(ref i)) => Ok(i.filter.is_pressed()),
+        _ => Err(DebounceError::UnknownInput),
+    }
+}
+
+// sample all the inputs once, the oldest event is dropped when the queue
+// is full
+pub fn tick() {
+    interrupt::free(|_| {
+        for (i, slot) in inputs().iter_mut().enumerate() {
+            if let Some(ref mut input) = *slot {
+                let level = gpio::read(input.port, input.pin).unwrap();
+                let ev = input.filter.update(level);
+                if !ev.is_empty() {
+                    push(InputEvent { input : InputId(i), events : ev });
+                }
+            }
+        }
+    });
+}
+
+fn push(ev : InputEvent) {
+    let q = queue();
+    if q.len == QUEUE_LEN {
+        q.head = (q.head + 1) % QUEUE_LEN;
+        q.len -= 1;
+        q.dropped += 1;
+    }
+    q.events[(q.head + q.len) % QUEUE_LEN] = Some(ev);
+    q.len += 1;
+}
+
+pub fn poll() -> Option<InputEvent> {
+    interrupt::free(|_| {
+        let q = queue();
+        if q.len == 0 {
+            return None;
+        }
+        let ev = q.events[q.head].take();
+        q.head = (q.head + 1) % QUEUE_LEN;
+        q.len -= 1;
+        ev
+    })
+}
+
+// events lost because poll was not called often enough
+pub fn dropped() -> u32 {
+    queue().dropped
+}
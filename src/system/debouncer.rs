@@ -0,0 +1,242 @@
+// Debounce filter of a single input, fed one sample of the pin level per
+// tick. It knows nothing about the hardware, system::debounce samples the
+// pins.
+
+bitflags! {
+    pub struct Events : u8 {
+        const PRESS         = 1 << 0;
+        const RELEASE       = 1 << 1;
+        const LONG_PRESS    = 1 << 2;
+        // second press shortly after a click, comes with PRESS
+        const DOUBLE_CLICK  = 1 << 3;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DebounceConfig {
+    stable_ticks        : u16,
+    long_press_ticks    : u16,
+    double_click_ticks  : u16,
+    active_high         : bool,
+}
+
+impl DebounceConfig {
+    // timings for a 1ms tick
+    pub fn new() -> DebounceConfig {
+        DebounceConfig {
+            stable_ticks        : 20,
+            long_press_ticks    : 1000,
+            double_click_ticks  : 300,
+            active_high         : true,
+        }
+    }
+
+    // a level change is accepted once seen for this many ticks in a row
+    pub fn stable_ticks(mut self, n : u16) -> DebounceConfig {
+        self.stable_ticks = n;
+        self
+    }
+
+    pub fn long_press_ticks(mut self, n : u16) -> DebounceConfig {
+        self.long_press_ticks = n;
+        self
+    }
+
+    // longest gap between a release and the next press of a double click
+    pub fn double_click_ticks(mut self, n : u16) -> DebounceConfig {
+        self.double_click_ticks = n;
+        self
+    }
+
+    pub fn active_low(mut self) -> DebounceConfig {
+        self.active_high = false;
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Debouncer {
+    cfg         : DebounceConfig,
+    pressed     : bool,
+    // ticks the raw level disagreed with `pressed`
+    count       : u16,
+    held        : u16,
+    long_sent   : bool,
+    // the current press completed a double click
+    double      : bool,
+    // ticks since a click that may start a double click
+    gap         : Option<u16>,
+}
+
+impl Debouncer {
+    // starts released, a pin already pressed gives a PRESS once stable
+    pub fn new(cfg : DebounceConfig) -> Debouncer {
+        Debouncer {
+            cfg         : cfg,
+            pressed     : false,
+            count       : 0,
+            held        : 0,
+            long_sent   : false,
+            double      : false,
+            gap         : None,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    // feed one sample of the pin level, returns what happened on this tick
+    pub fn update(&mut self, level : bool) -> Events {
+        let mut ev = Events::empty();
+        let raw = level == self.cfg.active_high;
+
+        if raw != self.pressed {
+            self.count += 1;
+            if self.count >= self.cfg.stable_ticks {
+                self.count = 0;
+                self.pressed = raw;
+                ev |= if raw { self.on_press() } else { self.on_release() };
+                return ev;
+            }
+        } else {
+            self.count = 0;
+        }
+
+        if self.pressed {
+            if !self.long_sent && self.held < ::core::u16::MAX {
+                self.held += 1;
+                if self.held >= self.cfg.long_press_ticks {
+                    self.long_sent = true;
+                    ev |= Events::LONG_PRESS;
+                }
+            }
+        } else if let Some(g) = self.gap {
+            let g = g.saturating_add(1);
+            self.gap = if g > self.cfg.double_click_ticks { None } else { Some(g) };
+        }
+        ev
+    }
+
+    fn on_press(&mut self) -> Events {
+        self.held = 0;
+        self.long_sent = false;
+        self.double = self.gap.take().is_some();
+        if self.double {
+            Events::PRESS | Events::DOUBLE_CLICK
+        } else {
+            Events::PRESS
+        }
+    }
+
+    fn on_release(&mut self) -> Events {
+        // a long press or the end of a double click is not a click
+        self.gap = if self.long_sent || self.double { None } else { Some(0) };
+        Events::RELEASE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DebounceConfig {
+        DebounceConfig::new()
+            .stable_ticks(3)
+            .long_press_ticks(10)
+            .double_click_ticks(8)
+    }
+
+    // n samples of the same level, all the events they raised
+    fn feed(d : &mut Debouncer, level : bool, n : u16) -> Events {
+        let mut ev = Events::empty();
+        for _ in 0..n {
+            ev |= d.update(level);
+        }
+        ev
+    }
+
+    fn click(d : &mut Debouncer) -> Events {
+        feed(d, true, 3) | feed(d, false, 3)
+    }
+
+    #[test]
+    fn short_bounce_ignored() {
+        let mut d = Debouncer::new(config());
+        for _ in 0..5 {
+            assert_eq!(feed(&mut d, true, 2), Events::empty());
+            assert_eq!(feed(&mut d, false, 1), Events::empty());
+        }
+        assert!(!d.is_pressed());
+
+        // a bounce while pressed does not release either
+        feed(&mut d, true, 3);
+        assert_eq!(feed(&mut d, false, 2) | feed(&mut d, true, 1), Events::empty());
+        assert!(d.is_pressed());
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut d = Debouncer::new(config());
+        assert_eq!(feed(&mut d, true, 2), Events::empty());
+        assert_eq!(d.update(true), Events::PRESS);
+        assert!(d.is_pressed());
+        assert_eq!(feed(&mut d, true, 5), Events::empty());
+
+        assert_eq!(feed(&mut d, false, 2), Events::empty());
+        assert_eq!(d.update(false), Events::RELEASE);
+        assert!(!d.is_pressed());
+    }
+
+    #[test]
+    fn active_low() {
+        let mut d = Debouncer::new(config().active_low());
+        assert_eq!(feed(&mut d, true, 5), Events::empty());
+        assert_eq!(feed(&mut d, false, 3), Events::PRESS);
+        assert_eq!(feed(&mut d, true, 3), Events::RELEASE);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut d = Debouncer::new(config());
+        assert_eq!(feed(&mut d, true, 3), Events::PRESS);
+        assert_eq!(feed(&mut d, true, 9), Events::empty());
+        assert_eq!(d.update(true), Events::LONG_PRESS);
+        // sent once however long the button is held
+        assert_eq!(feed(&mut d, true, 100), Events::empty());
+        assert_eq!(feed(&mut d, false, 3), Events::RELEASE);
+
+        // a long press does not start a double click
+        assert_eq!(feed(&mut d, false, 2) | feed(&mut d, true, 3), Events::PRESS);
+    }
+
+    #[test]
+    fn double_click_inside_window() {
+        let mut d = Debouncer::new(config());
+        assert_eq!(click(&mut d), Events::PRESS | Events::RELEASE);
+        assert_eq!(feed(&mut d, false, 2), Events::empty());
+        assert_eq!(feed(&mut d, true, 3), Events::PRESS | Events::DOUBLE_CLICK);
+        assert_eq!(feed(&mut d, false, 3), Events::RELEASE);
+
+        // the second click can't start another double click
+        assert_eq!(feed(&mut d, true, 3), Events::PRESS);
+    }
+
+    #[test]
+    fn widest_double_click_window() {
+        let mut d = Debouncer::new(config().double_click_ticks(::core::u16::MAX));
+        assert_eq!(click(&mut d), Events::PRESS | Events::RELEASE);
+        // the gap count saturates instead of wrapping
+        assert_eq!(feed(&mut d, false, ::core::u16::MAX), Events::empty());
+        assert_eq!(feed(&mut d, false, 10), Events::empty());
+        assert_eq!(feed(&mut d, true, 3), Events::PRESS | Events::DOUBLE_CLICK);
+    }
+
+    #[test]
+    fn double_click_outside_window() {
+        let mut d = Debouncer::new(config());
+        assert_eq!(click(&mut d), Events::PRESS | Events::RELEASE);
+        assert_eq!(feed(&mut d, false, 10), Events::empty());
+        assert_eq!(feed(&mut d, true, 3), Events::PRESS);
+    }
+}
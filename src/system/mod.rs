@@ -1 +1,9 @@
 pub mod clks;
+pub mod css;
+pub mod profiles;
+pub mod boot;
+pub mod debounce;
+
+// hardware independent, they live in the library so that their tests run on
+// the host
+pub use stm32f429i_disco::system::{clk_config, debouncer};
@@ -0,0 +1,131 @@
+use spl_rs::rcc;
+use system::{clks, css};
+use system::clks::ClkError;
+use system::clk_config::{ClockConfig, Clocks};
+
+// Clock profiles that can be switched at runtime. Drivers whose timings
+// depend on the bus frequencies register a listener which is called with
+// the new clocks once the switch is done. Those which can't run out of
+// spec in between, like the SDRAM refresh, also register a pre listener
+// called with the new clocks before the switch.
+
+const MAX_LISTENERS : usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Profile {
+    Performance,    // 180MHz from HSE, over-drive enabled
+    Balanced,       // 84MHz from HSE, voltage scale 3
+    LowPower,       // 16MHz straight from HSI, PLL off
+    Fallback,       // 168MHz from HSI, applied by css after a HSE failure
+}
+
+impl Profile {
+    pub fn config(&self) -> ClockConfig {
+        match *self {
+            Profile::Performance => clks::default_config(),
+            Profile::Balanced => {
+                ClockConfig::new()
+                    .use_hse(rcc::HSE_VALUE)
+                    .sysclk(84_000_000)
+            },
+            Profile::LowPower => {
+                ClockConfig::new()
+                    .use_hsi()
+            },
+            Profile::Fallback => css::fallback_config(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ProfileError {
+    TooManyListeners,
+}
+
+static mut CURRENT : Profile = Profile::Performance;
+static mut LISTENERS : [Option<fn(&Clocks)>; MAX_LISTENERS] = [None; MAX_LISTENERS];
+static mut PRE_LISTENERS : [Option<fn(&Clocks)>; MAX_LISTENERS] = [None; MAX_LISTENERS];
+
+pub fn current() -> Profile {
+    unsafe { CURRENT }
+}
+
+pub fn set_profile(p : Profile) -> Result<Clocks, ClkError> {
+    let clocks = p.config().freeze()?;
+    // if the switch fails the pre listeners are left with settings fit for
+    // both clocks
+    notify_pre(&clocks);
+    if let Err(e) = clks::configure(&clocks) {
+        recover(current());
+        return Err(e);
+    }
+    unsafe {
+        CURRENT = p;
+    }
+    notify(&clocks);
+    Ok(clocks)
+}
+
+// A failed configure may leave the chip on bare HSI. Go back to `previous`,
+// or to LowPower which only needs HSI, so that CURRENT and the listeners
+// agree with the clocks actually running.
+fn recover(previous : Profile) {
+    for p in [previous, Profile::LowPower].iter() {
+        let clocks = match p.config().freeze() {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        notify_pre(&clocks);
+        if clks::configure(&clocks).is_ok() {
+            unsafe {
+                CURRENT = *p;
+            }
+            notify(&clocks);
+            return;
+        }
+    }
+}
+
+// registering the same listener twice is a no-op
+pub fn register(listener : fn(&Clocks)) -> Result<(), ProfileError> {
+    add(unsafe { &mut LISTENERS }, listener)
+}
+
+pub fn register_pre(listener : fn(&Clocks)) -> Result<(), ProfileError> {
+    add(unsafe { &mut PRE_LISTENERS }, listener)
+}
+
+fn add(listeners : &mut [Option<fn(&Clocks)>; MAX_LISTENERS], listener : fn(&Clocks))
+    -> Result<(), ProfileError> {
+    for l in listeners.iter() {
+        if let Some(f) = *l {
+            if f as usize == listener as usize {
+                return Ok(());
+            }
+        }
+    }
+    for l in listeners.iter_mut() {
+        if l.is_none() {
+            *l = Some(listener);
+            return Ok(());
+        }
+    }
+    Err(ProfileError::TooManyListeners)
+}
+
+pub fn notify(clocks : &Clocks) {
+    call(unsafe { &LISTENERS }, clocks);
+}
+
+// the current clocks are still running, see rcc::get_clocks_freq
+pub fn notify_pre(clocks : &Clocks) {
+    call(unsafe { &PRE_LISTENERS }, clocks);
+}
+
+fn call(listeners : &[Option<fn(&Clocks)>; MAX_LISTENERS], clocks : &Clocks) {
+    for l in listeners.iter() {
+        if let Some(f) = *l {
+            f(clocks);
+        }
+    }
+}